@@ -0,0 +1,256 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinHandle;
+
+use super::log::{Log, LogError};
+use super::offset::AbsOffset;
+use crate::proto::record::Record;
+
+// most appends the writer makes under one lock
+const MAX_DRAIN: usize = 256;
+
+struct Append {
+    record: Record,
+    reply: oneshot::Sender<Result<AbsOffset, LogError>>,
+}
+
+/// Appends to a log from async code through a bounded queue drained by a single writer.
+///
+/// `append` waits while the queue is full, so callers can't get further ahead of the disk
+/// than the queue's capacity. The writer runs on a blocking thread, appending whatever has
+/// queued up under one lock of the log, in the order it was queued. Each append is synced
+/// as the log's `SyncPolicy` says. `read` can be limited to a number of reads at once with
+/// `ConfigBuilder::with_max_concurrent_reads`, appends never are.
+pub struct AsyncLog {
+    sender: mpsc::Sender<Append>,
+    log: Arc<Mutex<Log>>,
+    writer: JoinHandle<()>,
+    reads: Option<Semaphore>, // a permit per read allowed at once
+}
+
+impl AsyncLog {
+    /// Starts the writer, which has to happen within a tokio runtime.
+    /// At most `capacity` appends are queued at once
+    pub fn new(log: Log, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let reads = log
+            .config()
+            .get_max_concurrent_reads()
+            .map(|max| Semaphore::new(max.max(1)));
+        let log = Arc::new(Mutex::new(log));
+        let writer = tokio::task::spawn_blocking({
+            let log = log.clone();
+            move || write_appends(log, receiver)
+        });
+        Self {
+            sender,
+            log,
+            writer,
+            reads,
+        }
+    }
+
+    /// Queues record to be appended, waiting for room in the queue,
+    /// and returns the offset it was given
+    pub async fn append(&self, record: Record) -> Result<AbsOffset, LogError> {
+        let (reply, offset) = oneshot::channel();
+        self.sender
+            .send(Append { record, reply })
+            .await
+            .map_err(|_| LogError::WriterStopped)?;
+        offset.await.map_err(|_| LogError::WriterStopped)?
+    }
+
+    /// Reads the record at offset on a blocking thread, first waiting for a permit when reads
+    /// are limited, so a burst of reads can't tie up every blocking thread
+    pub async fn read(&self, offset: AbsOffset) -> Result<Record, LogError> {
+        // held until the read is done
+        let _permit = match &self.reads {
+            Some(reads) => Some(reads.acquire().await.expect("the semaphore is never closed")),
+            None => None,
+        };
+        let log = self.log.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            log.lock().unwrap_or_else(|e| e.into_inner()).read(offset)
+        });
+        match read.await {
+            Ok(record) => record,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// The log being appended to, e.g. to read from it. Holding the lock holds up the writer
+    pub fn log(&self) -> &Arc<Mutex<Log>> {
+        &self.log
+    }
+
+    /// `Log::verify`, locking the log one segment at a time and yielding in between so queued
+    /// appends are written while it runs. Returns the number of records read
+    pub async fn verify(&self) -> Result<u64, LogError> {
+        let mut records = 0;
+        for base_offset in self.base_offsets() {
+            match self.lock().verify_segment(base_offset) {
+                Ok(read) => records += read,
+                // removed since, e.g. by max_segments
+                Err(LogError::SegmentNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(records)
+    }
+
+    /// `Log::reindex_all`, locking the log one segment at a time and yielding in between so
+    /// queued appends are written while it runs
+    pub async fn reindex_all(&self) -> Result<Vec<(AbsOffset, u64)>, LogError> {
+        let mut entries = vec![];
+        for base_offset in self.base_offsets() {
+            match self.lock().reindex_segment(base_offset) {
+                Ok(rebuilt) => entries.push((base_offset, rebuilt)),
+                Err(LogError::SegmentNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(entries)
+    }
+
+    // base offsets of the log's segments as of now
+    fn base_offsets(&self) -> Vec<AbsOffset> {
+        self.lock()
+            .segment_paths()
+            .into_iter()
+            .map(|(base_offset, _, _)| base_offset)
+            .collect()
+    }
+
+    // the writer only poisons the lock by panicking, which leaves the log as it was
+    fn lock(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stops the writer once everything queued has been appended, and gives the log back
+    pub async fn into_inner(self) -> Log {
+        drop(self.sender);
+        // the writer only stops early if it panicked, which poisons the lock anyway
+        let _ = self.writer.await;
+        match Arc::try_unwrap(self.log) {
+            Ok(log) => log.into_inner().unwrap_or_else(|e| e.into_inner()),
+            Err(_) => unreachable!("the writer has stopped and the log isn't shared"),
+        }
+    }
+}
+
+// appends everything queued until every sender is dropped
+fn write_appends(log: Arc<Mutex<Log>>, mut receiver: mpsc::Receiver<Append>) {
+    let mut drained = Vec::with_capacity(MAX_DRAIN);
+    while let Some(first) = receiver.blocking_recv() {
+        drained.push(first);
+        while drained.len() < MAX_DRAIN {
+            match receiver.try_recv() {
+                Ok(append) => drained.push(append),
+                Err(_) => break,
+            }
+        }
+
+        let mut log = log.lock().unwrap_or_else(|e| e.into_inner());
+        for Append { record, reply } in drained.drain(..) {
+            // the caller may have stopped waiting, the record is appended anyway
+            let _ = reply.send(log.append(record));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log::log::Config;
+
+    #[tokio::test]
+    async fn async_log_appends_in_order() {
+        let log = Log::in_memory(Config::default()).expect("cannot create log");
+        // a small queue, so most appends wait for room
+        let log = Arc::new(AsyncLog::new(log, 4));
+
+        let appends: Vec<_> = (0..200)
+            .map(|i| {
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let value = format!("hello world{}", i);
+                    let offset = log
+                        .append(Record::builder().value(value.clone()).build())
+                        .await
+                        .unwrap();
+                    (offset, value)
+                })
+            })
+            .collect();
+        let mut appended = vec![];
+        for append in appends {
+            appended.push(append.await.unwrap());
+        }
+
+        // every append got its own offset, with none skipped
+        let mut offsets: Vec<u64> = appended.iter().map(|(offset, _)| offset.0).collect();
+        offsets.sort();
+        assert_eq!(offsets, (0..200).collect::<Vec<u64>>());
+
+        let log = Arc::try_unwrap(log).ok().expect("appends are done").into_inner().await;
+        assert_eq!(log.len(), 200);
+        for (offset, value) in appended {
+            assert_eq!(log.read_str(offset).unwrap(), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn async_log_verify_and_reindex() {
+        let log = Log::in_memory(Config::small()).expect("cannot create log");
+        let log = AsyncLog::new(log, 4);
+        for i in 0..20 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .await
+                .unwrap();
+        }
+
+        // appends queued while the scans run are still written
+        let (verified, reindexed, appended) = tokio::join!(
+            log.verify(),
+            log.reindex_all(),
+            log.append(Record::builder().value("during").build())
+        );
+        assert!(verified.unwrap() >= 20);
+        assert!(reindexed.unwrap().len() > 1);
+        assert_eq!(appended.unwrap(), AbsOffset(20));
+
+        let log = log.into_inner().await;
+        assert_eq!(log.verify().unwrap(), 21);
+        assert_eq!(log.read_str(AbsOffset(20)).unwrap(), "during");
+    }
+
+    #[tokio::test]
+    async fn async_log_limits_reads() {
+        use crate::log::log::ConfigBuilder;
+        let config = ConfigBuilder::new(1024, 1024, 0).with_max_concurrent_reads(2).build();
+        let log = Log::in_memory(config).expect("cannot create log");
+        let log = Arc::new(AsyncLog::new(log, 4));
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .await
+                .unwrap();
+        }
+
+        // many more readers than permits, each waits its turn
+        let reads: Vec<_> = (0..50u64)
+            .map(|i| {
+                let log = log.clone();
+                tokio::spawn(async move { (i % 10, log.read(AbsOffset(i % 10)).await) })
+            })
+            .collect();
+        for read in reads {
+            let (offset, record) = read.await.unwrap();
+            assert_eq!(record.unwrap().value, format!("hello world{}", offset).as_bytes());
+        }
+        assert_eq!(log.reads.as_ref().unwrap().available_permits(), 2);
+    }
+}
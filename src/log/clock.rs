@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the timestamps given to appended records
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+/// Wall clock time, the default
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the unix epoch")
+            .as_millis() as u64
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now_millis: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now_millis),
+        }
+    }
+
+    pub fn set(&self, now_millis: u64) {
+        self.now.store(now_millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// A counter that ticks once per reading, so every record gets a distinct increasing timestamp
+/// regardless of wall clock time, e.g. on a follower replaying a leader's records
+pub struct LogicalClock {
+    next: AtomicU64,
+}
+
+impl LogicalClock {
+    pub fn new(start: u64) -> Self {
+        Self {
+            next: AtomicU64::new(start),
+        }
+    }
+}
+
+impl Clock for LogicalClock {
+    fn now_millis(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+}
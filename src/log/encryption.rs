@@ -0,0 +1,44 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+
+pub const NONCE_LENGTH: usize = 12;
+pub const TAG_LENGTH: usize = 16;
+
+/// Key used to encrypt record payloads at rest with AES-256-GCM.
+/// The key is only held in memory, it's never written to the log
+#[derive(Clone)]
+pub struct EncryptionConfig {
+    key: [u8; 32],
+}
+
+impl EncryptionConfig {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    pub fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+}
+
+// encrypts payload with a fresh nonce, returning the nonce followed by the ciphertext (and its tag)
+pub fn encrypt(cipher: &Aes256Gcm, payload: &[u8]) -> Option<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, payload).ok()?;
+
+    let mut sealed = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Some(sealed)
+}
+
+// reverses `encrypt`, fails if the key is wrong or the bytes were tampered with
+pub fn decrypt(cipher: &Aes256Gcm, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LENGTH + TAG_LENGTH {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
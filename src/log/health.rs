@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Counters describing a log, see `Log::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogStats {
+    pub segments: usize,
+    pub records: u64,
+    // bytes taken up by the stores of every segment
+    pub store_bytes: u64,
+    pub lowest_offset: Option<u64>,
+    pub highest_offset: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthStatus {
+    Ok,
+    // the log can still be used, but some of it can't be read
+    Degraded(Vec<String>),
+}
+
+/// Result of `Log::health_check`, cheap enough to produce for liveness and readiness probes
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub stats: LogStats,
+    // bytes that can still be appended before the active segment rolls
+    pub active_segment_remaining_bytes: u64,
+}
+
+impl HealthReport {
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+/// Sizes of the records in a log, as stored without the length (and checksum) in front of
+/// each one, see `Log::size_histogram`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SizeHistogram {
+    // records by the power of two their size is at most, and more than half of
+    pub buckets: BTreeMap<u64, u64>,
+    pub count: u64,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    // bytes taken up by every record
+    pub total: u64,
+}
+
+impl SizeHistogram {
+    /// Counts a record of `size` bytes
+    pub fn add(&mut self, size: u64) {
+        *self.buckets.entry(size.next_power_of_two()).or_default() += 1;
+        self.count += 1;
+        self.min = Some(self.min.map_or(size, |min| min.min(size)));
+        self.max = Some(self.max.map_or(size, |max| max.max(size)));
+        self.total += size;
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total as f64 / self.count as f64)
+    }
+}
+
+/// How long recent appends took to write, see `Log::append_latencies`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    // appends the stats were taken over
+    pub count: usize,
+}
+
+impl LatencyStats {
+    /// Stats of `latencies`, which can be in any order
+    pub fn from_latencies(latencies: impl IntoIterator<Item = Duration>) -> Self {
+        let mut sorted: Vec<Duration> = latencies.into_iter().collect();
+        sorted.sort();
+        // the smallest latency at least p percent of them are at most
+        let percentile = |p: usize| {
+            let rank = (sorted.len() * p).div_ceil(100).max(1);
+            sorted.get(rank - 1).copied().unwrap_or_default()
+        };
+        Self {
+            p50: percentile(50),
+            p99: percentile(99),
+            max: sorted.last().copied().unwrap_or_default(),
+            count: sorted.len(),
+        }
+    }
+}
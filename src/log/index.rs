@@ -12,7 +12,8 @@ use std::{
 };
 use thiserror::Error;
 
-use crate::log::log::Config;
+use crate::log::log::{Config, SyncPolicy};
+use crate::log::offset::{RelOffset, StorePos};
 use crate::log::log::{
     INDEX_ENTRY_LENGTH, INDEX_RECORD_OFFSET_LENGTH, POSITION_IN_STORE_FILE_LENGTH,
 };
@@ -22,57 +23,139 @@ use std::sync::Arc;
 
 #[derive(Debug, Default)]
 pub struct IndexEntry {
-    pub record_offset: u32,
-    pub position: u64,
+    pub record_offset: RelOffset,
+    pub position: StorePos,
 }
 
 #[derive(Error, Debug)]
 pub enum IndexError {
     #[error("Index is full")]
     IndexFullError,
-    #[error("Index entry {0} not found")]
-    IndexEntryNotFound(u32),
+    #[error("Cannot truncate index to {0} entries, it has fewer")]
+    InvalidTruncation(u64),
 
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
+// bytes backing an index, either a memory mapped file or an in-memory buffer
+#[derive(Debug)]
+pub enum IndexBackend {
+    File { file: File, mmap: MmapMut },
+    Memory(Vec<u8>),
+}
+
 #[derive(Debug)]
 pub struct Index {
-    pub file: File,
+    pub backend: IndexBackend,
     pub size: u64,
-    mmap: MmapMut,
     pub path: PathBuf,
+    growable: bool,
+    sync_policy: SyncPolicy,
+    writes: u64, // writes since the index was opened, to apply the sync policy
+    flush_on_drop: bool,
 }
 
 impl Index {
-    pub fn new(file_path: PathBuf, config: Arc<Config>) -> Self {
+    pub fn new(file_path: PathBuf, config: Arc<Config>) -> io::Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .create(true)
             .append(true)
-            .open(&file_path)
-            .expect("Unable to create or open file");
+            .open(&file_path)?;
 
-        let index_size = file.metadata().unwrap().len();
+        let index_size = file.metadata()?.len();
 
-        file.set_len(config.get_max_index_bytes())
-            .expect("Unable to truncate file");
+        // a grown index can be larger than the max, don't cut off its entries
+        file.set_len(config.get_max_index_bytes().max(index_size))?;
 
-        let mmap = unsafe { MmapMut::map_mut(&file).expect("Cannot create mmap file") };
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        // a closed index is shrunk to its entries, one that's still at least its preallocated
+        // size may not have been
+        let index_size = if index_size < config.get_max_index_bytes() {
+            index_size
+        } else {
+            written_len(&mmap[..index_size as usize])
+        };
 
-        Self {
-            file,
+        Ok(Self {
+            backend: IndexBackend::File { file, mmap },
             size: index_size,
-            mmap,
             path: file_path,
+            growable: config.is_index_growable(),
+            sync_policy: index_sync_policy(&config),
+            writes: 0,
+            flush_on_drop: config.is_flush_on_drop(),
+        })
+    }
+
+    pub fn in_memory(config: Arc<Config>) -> Self {
+        Self {
+            backend: IndexBackend::Memory(vec![0; config.get_max_index_bytes() as usize]),
+            size: 0,
+            path: PathBuf::new(),
+            growable: config.is_index_growable(),
+            sync_policy: index_sync_policy(&config),
+            writes: 0,
+            flush_on_drop: config.is_flush_on_drop(),
         }
     }
 
-    pub fn close(&mut self) {
+    /// Flushes the entries and shrinks the file to them, from its preallocated size
+    pub fn close(&mut self) -> Result<(), IndexError> {
         let size = self.size;
-        self.file.set_len(size).expect("Cannot truncate index file");
-        self.mmap.flush().expect("Cannot flush mem map")
+        if let IndexBackend::File { file, mmap } = &mut self.backend {
+            file.set_len(size)?;
+            mmap.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the entries out to the index file. Entries are only written to the memory map,
+    /// so until this (or `close`) runs they can be lost in a crash while their records are in
+    /// the store. Called after writes as often as the sync policy says
+    pub fn flush(&mut self) -> Result<(), IndexError> {
+        if let IndexBackend::File { mmap, .. } = &mut self.backend {
+            mmap.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the index's entries to a new file at `path`, or into memory when there's no path
+    pub fn copy_to(&self, path: Option<PathBuf>, config: Arc<Config>) -> io::Result<Index> {
+        let entries = &self.bytes()[..self.size as usize];
+        match path {
+            Some(path) => {
+                std::fs::write(&path, entries)?;
+                Index::new(path, config)
+            }
+            None => {
+                let mut index = Index::in_memory(config);
+                if let IndexBackend::Memory(bytes) = &mut index.backend {
+                    // a grown index can be larger than the max
+                    if bytes.len() < entries.len() {
+                        bytes.resize(entries.len(), 0);
+                    }
+                    bytes[..entries.len()].copy_from_slice(entries);
+                }
+                index.size = self.size;
+                Ok(index)
+            }
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.backend {
+            IndexBackend::File { mmap, .. } => &mmap[..],
+            IndexBackend::Memory(bytes) => &bytes[..],
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        match &mut self.backend {
+            IndexBackend::File { mmap, .. } => &mut mmap[..],
+            IndexBackend::Memory(bytes) => &mut bytes[..],
+        }
     }
 
     pub fn read_last_entry(&self) -> Option<IndexEntry> {
@@ -95,7 +178,7 @@ impl Index {
             return None;
         }
 
-        let record_offset = &self.mmap[position_in_index_file as usize
+        let record_offset = &self.bytes()[position_in_index_file as usize
             ..(position_in_index_file + INDEX_RECORD_OFFSET_LENGTH as u64) as usize];
 
         let record_offset = byteorder::BigEndian::read_u32(record_offset);
@@ -103,19 +186,117 @@ impl Index {
         let start = (position_in_index_file + INDEX_RECORD_OFFSET_LENGTH as u64) as usize;
         let end = start + POSITION_IN_STORE_FILE_LENGTH as usize;
 
-        let position_in_store_file = &self.mmap[start..end];
+        let position_in_store_file = &self.bytes()[start..end];
         let position_in_store_file = byteorder::BigEndian::read_u64(position_in_store_file);
 
         Some(IndexEntry {
-            record_offset,
-            position: position_in_store_file,
+            record_offset: RelOffset(record_offset),
+            position: StorePos(position_in_store_file),
         })
     }
 
-    pub fn write(&mut self, record_offset: u32, position: u64) -> Result<(), IndexError> {
-        if self.mmap.len() < (self.size as usize + INDEX_ENTRY_LENGTH as usize) {
-            // index file is full
-            return Err(IndexError::IndexFullError);
+    /// Finds the entry for `record_offset` (relative to the base offset), along with its
+    /// position in the index. Offsets can be sparse, so this doesn't assume entry n is offset n
+    pub fn find(&self, record_offset: RelOffset) -> Option<(u64, IndexEntry)> {
+        self.seek(record_offset)
+            .filter(|(_, entry)| entry.record_offset == record_offset)
+    }
+
+    /// Finds the first entry whose offset is `record_offset` or after it
+    pub fn seek(&self, record_offset: RelOffset) -> Option<(u64, IndexEntry)> {
+        // offsets are usually consecutive, so entry n is very likely offset n
+        let n = record_offset.0 as u64;
+        if let Some(entry) = self.read(n) {
+            if entry.record_offset == record_offset {
+                return Some((n, entry));
+            }
+        }
+
+        // entries are sorted by offset, so binary search for the first one that isn't before it
+        let (mut low, mut high) = (0, self.size / INDEX_ENTRY_LENGTH as u64);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.read(mid)?.record_offset < record_offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        self.read(low).map(|entry| (low, entry))
+    }
+
+    /// Finds the last entry whose offset is `record_offset` or before it
+    pub fn floor(&self, record_offset: RelOffset) -> Option<(u64, IndexEntry)> {
+        let entries = self.size / INDEX_ENTRY_LENGTH as u64;
+        let n = match self.seek(record_offset) {
+            Some((n, entry)) if entry.record_offset == record_offset => n,
+            Some((n, _)) => n.checked_sub(1)?,
+            None => entries.checked_sub(1)?,
+        };
+        self.read(n).map(|entry| (n, entry))
+    }
+
+    /// Finds the offset of the entry pointing at `position` in the store, e.g. to tell which
+    /// record a position belongs to. Positions increase with offsets, so this is a binary search.
+    /// The records of a record set share its position, so this is the first of them
+    pub fn offset_at_position(&self, position: StorePos) -> Option<RelOffset> {
+        let (mut low, mut high) = (0, self.size / INDEX_ENTRY_LENGTH as u64);
+        let mut found = None;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let entry = self.read(mid)?;
+            match entry.position.cmp(&position) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => {
+                    found = Some(entry.record_offset);
+                    high = mid;
+                }
+            }
+        }
+        found
+    }
+
+    // whether there's no room left for another entry, a growable index is never full
+    pub fn is_full(&self) -> bool {
+        !self.growable && !self.has_room()
+    }
+
+    // whether `entries` more entries fit, a growable index always has room
+    pub fn has_room_for(&self, entries: u64) -> bool {
+        self.growable
+            || self.bytes().len() as u64 >= self.size + entries * INDEX_ENTRY_LENGTH as u64
+    }
+
+    fn has_room(&self) -> bool {
+        self.bytes().len() >= (self.size as usize + INDEX_ENTRY_LENGTH as usize)
+    }
+
+    // doubles the index until there's room for another entry, remapping the file
+    fn grow(&mut self) -> Result<(), IndexError> {
+        let mut new_len = self.bytes().len().max(INDEX_ENTRY_LENGTH as usize);
+        while new_len < self.size as usize + INDEX_ENTRY_LENGTH as usize {
+            new_len *= 2;
+        }
+
+        match &mut self.backend {
+            IndexBackend::File { file, mmap } => {
+                mmap.flush()?;
+                file.set_len(new_len as u64)?;
+                *mmap = unsafe { MmapMut::map_mut(&*file)? };
+            }
+            IndexBackend::Memory(bytes) => bytes.resize(new_len, 0),
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, record_offset: RelOffset, position: StorePos) -> Result<(), IndexError> {
+        if !self.has_room() {
+            if !self.growable {
+                // index file is full
+                return Err(IndexError::IndexFullError);
+            }
+            self.grow()?;
         }
 
         let start = self.size;
@@ -123,17 +304,32 @@ impl Index {
 
         self.size += INDEX_ENTRY_LENGTH as u64; // new size should be the size of the index entry 4 + 8;
 
-        let mut r = &mut self.mmap[start as usize..end as usize];
+        let mut r = &mut self.bytes_mut()[start as usize..end as usize];
 
-        byteorder::BigEndian::write_u32(&mut r, record_offset);
+        byteorder::BigEndian::write_u32(&mut r, record_offset.0);
 
         // now let's write the position in store file
         let start = end;
         let end = start + 8 as u64;
 
-        let mut r = &mut self.mmap[start as usize..end as usize];
+        let mut r = &mut self.bytes_mut()[start as usize..end as usize];
+
+        byteorder::BigEndian::write_u64(&mut r, position.0);
+
+        self.writes += 1;
+        if self.sync_policy.should_sync(self.writes) {
+            self.flush()?;
+        }
+        Ok(())
+    }
 
-        byteorder::BigEndian::write_u64(&mut r, position);
+    /// Keeps only the first `entries` entries, the index can only shrink
+    pub fn truncate_to(&mut self, entries: u64) -> Result<(), IndexError> {
+        let size = entries * INDEX_ENTRY_LENGTH as u64;
+        if size > self.size {
+            return Err(IndexError::InvalidTruncation(entries));
+        }
+        self.size = size;
         Ok(())
     }
 
@@ -142,10 +338,52 @@ impl Index {
 
 impl Drop for Index {
     fn drop(&mut self) {
-        self.close()
+        if !self.flush_on_drop {
+            return;
+        }
+        // can't return an error from here, and panicking while unwinding aborts
+        if let Err(e) = self.close() {
+            eprintln!("Cannot close index {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// a batching log syncs a batch's entries once it's written, rather than as each is written
+fn index_sync_policy(config: &Config) -> SyncPolicy {
+    if config.is_batching() {
+        SyncPolicy::Never
+    } else {
+        config.get_sync_policy()
     }
 }
 
+// bytes taken up by entries in an index file. One that wasn't closed (e.g. after a crash) is
+// still at its preallocated size with zeroed entries at the end. Only the first entry can have
+// position 0, in a store written before stores had a version, so it's taken as written when
+// the one after it is. A lone first entry at position 0 can't be told from a zeroed one, it's
+// left out and `Segment::new` indexes its record again
+fn written_len(bytes: &[u8]) -> u64 {
+    let position_at = |n: usize| {
+        let start = n * INDEX_ENTRY_LENGTH as usize + INDEX_RECORD_OFFSET_LENGTH as usize;
+        BigEndian::read_u64(&bytes[start..start + POSITION_IN_STORE_FILE_LENGTH as usize])
+    };
+    let entries = bytes.len() / INDEX_ENTRY_LENGTH as usize;
+    if entries == 0 || (position_at(0) == 0 && (entries == 1 || position_at(1) == 0)) {
+        return 0;
+    }
+    // written entries all come before the zeroed ones, find the first zeroed one
+    let (mut low, mut high) = (1, entries);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if position_at(mid) != 0 {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    (low * INDEX_ENTRY_LENGTH as usize) as u64
+}
+
 #[cfg(test)]
 mod test {
     use crate::log::log::ConfigBuilder;
@@ -161,19 +399,19 @@ mod test {
 
         let config = Arc::new(config);
 
-        let mut index = Index::new(path, config);
+        let mut index = Index::new(path, config).unwrap();
 
-        index.write(0, 10);
-        index.write(1, 20);
-        index.write(2, 30);
-        index.write(3, 40);
+        index.write(RelOffset(0), StorePos(10));
+        index.write(RelOffset(1), StorePos(20));
+        index.write(RelOffset(2), StorePos(30));
+        index.write(RelOffset(3), StorePos(40));
 
         let result = index.read(1).unwrap();
 
-        assert_eq!(result.record_offset, 1);
-        assert_eq!(result.position, 20);
+        assert_eq!(result.record_offset, RelOffset(1));
+        assert_eq!(result.position, StorePos(20));
 
-        index.close();
+        index.close().unwrap();
 
         let config = ConfigBuilder::new(1024, 1024, 0).build();
 
@@ -183,17 +421,17 @@ mod test {
         let mut path = PathBuf::new();
         path.push(&index_file);
 
-        let mut index = Index::new(path, config);
+        let mut index = Index::new(path, config).unwrap();
 
-        index.write(4, 50);
-        index.write(5, 60);
-        index.write(6, 70);
-        index.write(7, 80);
+        index.write(RelOffset(4), StorePos(50));
+        index.write(RelOffset(5), StorePos(60));
+        index.write(RelOffset(6), StorePos(70));
+        index.write(RelOffset(7), StorePos(80));
 
         let result = index.read(7).unwrap();
 
-        assert_eq!(result.record_offset, 7);
-        assert_eq!(result.position, 80);
+        assert_eq!(result.record_offset, RelOffset(7));
+        assert_eq!(result.position, StorePos(80));
 
         // test that if we ask for an index that doesn't exist, we return none
 
@@ -202,4 +440,179 @@ mod test {
 
         std::fs::remove_file(index_file).unwrap();
     }
+
+    #[test]
+    fn index_test_growable() {
+        // room for 2 entries before growing
+        let config = ConfigBuilder::new(INDEX_ENTRY_LENGTH as u64 * 2, 1024, 0)
+            .with_index_growable(true)
+            .build();
+        let config = Arc::new(config);
+        let index_file = "index_growable";
+
+        let mut index = Index::new(PathBuf::from(index_file), config.clone()).unwrap();
+        for i in 0..10 {
+            assert!(!index.is_full());
+            index.write(RelOffset(i), StorePos(i as u64 * 10)).unwrap();
+        }
+        assert_eq!(index.size, INDEX_ENTRY_LENGTH as u64 * 10);
+        for i in 0..10 {
+            let entry = index.read(i as u64).unwrap();
+            assert_eq!(entry.record_offset, RelOffset(i));
+            assert_eq!(entry.position, StorePos(i as u64 * 10));
+        }
+        index.close().unwrap();
+
+        // reopening keeps every entry even though the index is larger than the max
+        let mut index = Index::new(PathBuf::from(index_file), config).unwrap();
+        assert_eq!(index.size, INDEX_ENTRY_LENGTH as u64 * 10);
+        assert_eq!(index.read_last_entry().unwrap().record_offset, RelOffset(9));
+        index.write(RelOffset(10), StorePos(100)).unwrap();
+        assert_eq!(index.read(10).unwrap().position, StorePos(100));
+        index.close().unwrap();
+
+        std::fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn index_truncate_to() {
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut index = Index::in_memory(Arc::new(config));
+        for i in 0..3 {
+            index.write(RelOffset(i), StorePos(i as u64 * 10)).unwrap();
+        }
+
+        assert!(matches!(
+            index.truncate_to(4),
+            Err(IndexError::InvalidTruncation(4))
+        ));
+        index.truncate_to(2).unwrap();
+        assert_eq!(index.read_last_entry().unwrap().record_offset, RelOffset(1));
+        assert!(index.read(2).is_none());
+
+        // the next entry goes where the dropped one was
+        index.write(RelOffset(2), StorePos(50)).unwrap();
+        assert_eq!(index.read(2).unwrap().position, StorePos(50));
+    }
+
+    #[test]
+    fn index_find_sparse() {
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut index = Index::in_memory(Arc::new(config));
+        for i in 0..5 {
+            index.write(RelOffset(i * 10), StorePos(i as u64 * 100)).unwrap();
+        }
+
+        let (n, entry) = index.find(RelOffset(30)).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(entry.position, StorePos(300));
+        assert!(index.find(RelOffset(0)).is_some());
+        assert!(index.find(RelOffset(25)).is_none());
+
+        assert_eq!(index.seek(RelOffset(25)).unwrap().1.record_offset, RelOffset(30));
+        assert_eq!(index.seek(RelOffset(40)).unwrap().1.record_offset, RelOffset(40));
+        assert!(index.seek(RelOffset(41)).is_none());
+
+        assert_eq!(index.floor(RelOffset(25)).unwrap().1.record_offset, RelOffset(20));
+        assert_eq!(index.floor(RelOffset(30)).unwrap().1.record_offset, RelOffset(30));
+        assert_eq!(index.floor(RelOffset(99)).unwrap().1.record_offset, RelOffset(40));
+        assert_eq!(index.floor(RelOffset(0)).unwrap().0, 0);
+    }
+
+    #[test]
+    fn index_flush() {
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let index_file = "index_flush";
+
+        let mut index = Index::new(PathBuf::from(index_file), config.clone()).unwrap();
+        for i in 0..3 {
+            index.write(RelOffset(i), StorePos(i as u64 * 10)).unwrap();
+        }
+        index.flush().unwrap();
+
+        // a second handle sees the entries without the first being closed
+        let reopened = Index::new(PathBuf::from(index_file), config).unwrap();
+        for i in 0..3 {
+            let entry = reopened.read(i as u64).unwrap();
+            assert_eq!(entry.record_offset, RelOffset(i));
+            assert_eq!(entry.position, StorePos(i as u64 * 10));
+        }
+        drop(reopened);
+        index.close().unwrap();
+
+        std::fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn index_reopens_entry_at_position_zero() {
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let index_file = "index_reopens_entry_at_position_zero";
+
+        // the first record of a store without a version is at position 0
+        let mut index = Index::new(PathBuf::from(index_file), config.clone()).unwrap();
+        index.write(RelOffset(0), StorePos(0)).unwrap();
+        index.close().unwrap();
+        drop(index);
+        let mut index = Index::new(PathBuf::from(index_file), config.clone()).unwrap();
+        assert_eq!(index.size, INDEX_ENTRY_LENGTH as u64);
+        assert_eq!(index.read(0).unwrap().position, StorePos(0));
+
+        // and it's kept when the index wasn't closed, as long as there's an entry after it
+        index.write(RelOffset(1), StorePos(20)).unwrap();
+        index.flush().unwrap();
+        std::mem::forget(index);
+        let index = Index::new(PathBuf::from(index_file), config).unwrap();
+        assert_eq!(index.size, INDEX_ENTRY_LENGTH as u64 * 2);
+        assert_eq!(index.read(0).unwrap().position, StorePos(0));
+
+        std::fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn index_offset_at_position() {
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut index = Index::in_memory(Arc::new(config));
+        assert_eq!(index.offset_at_position(StorePos(1)), None);
+
+        // sparse offsets, with positions growing along with them
+        for i in 0..5 {
+            index.write(RelOffset(i * 3), StorePos(1 + i as u64 * 40)).unwrap();
+        }
+
+        assert_eq!(index.offset_at_position(StorePos(1)), Some(RelOffset(0)));
+        assert_eq!(index.offset_at_position(StorePos(81)), Some(RelOffset(6)));
+        assert_eq!(index.offset_at_position(StorePos(161)), Some(RelOffset(12)));
+        // positions in the middle of a record, or past the last one, aren't entries
+        assert_eq!(index.offset_at_position(StorePos(50)), None);
+        assert_eq!(index.offset_at_position(StorePos(500)), None);
+    }
+
+    #[test]
+    fn index_recovers_size_after_unclean_close() {
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_flush_on_drop(false)
+            .build();
+        let config = Arc::new(config);
+        let index_file = "index_unclean_close";
+
+        let mut index = Index::new(PathBuf::from(index_file), config.clone()).unwrap();
+        for i in 0..3 {
+            index.write(RelOffset(i), StorePos(1 + i as u64 * 10)).unwrap();
+        }
+        index.flush().unwrap();
+        // dropped without closing, so the file keeps its zeroed preallocation
+        drop(index);
+        assert_eq!(std::fs::metadata(index_file).unwrap().len(), 1024);
+
+        // the zeroed entries aren't mistaken for entries at offset 0
+        let index = Index::new(PathBuf::from(index_file), config).unwrap();
+        assert_eq!(index.size, INDEX_ENTRY_LENGTH as u64 * 3);
+        let last = index.read_last_entry().unwrap();
+        assert_eq!(last.record_offset, RelOffset(2));
+        assert_eq!(last.position, StorePos(21));
+        assert!(index.read(3).is_none());
+        drop(index);
+
+        std::fs::remove_file(index_file).unwrap();
+    }
 }
@@ -1,8 +1,9 @@
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use memmap2::MmapMut;
 use prost::{DecodeError, EncodeError, Message};
+use serde::{Deserialize, Serialize};
 use std::{
-    borrow::BorrowMut,
+    borrow::{BorrowMut, Cow},
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Read, Write},
     num::ParseIntError,
@@ -11,18 +12,86 @@ use std::{
     vec,
 };
 use thiserror::Error;
+use tokio::sync::broadcast;
 
+use super::clock::{Clock, SystemClock};
+use super::encryption::EncryptionConfig;
+use super::health::{HealthReport, HealthStatus, LatencyStats, LogStats, SizeHistogram};
 use super::index::{Index, IndexError};
-use super::segment::{Segment, SegmentError};
+use super::manifest::{Manifest, Migration, MANIFEST_FILE, MIGRATION_FILE};
+use super::offset::{AbsOffset, OffsetAllocator, SequentialAllocator, StorePos};
+use super::offset_store::OffsetStore;
+use super::segment::{
+    CompactedSegment, LazySegment, Segment, SegmentError, SegmentMeta, SegmentRef,
+};
 use super::store::{Store, StoreError};
-use crate::proto::{self, record::Record};
+use super::subscription::Subscription;
+use crate::proto::{record::Record, AppendedRecord};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 pub const LEN_WIDTH: u8 = 8; // number of bytes used to store the position of a record
+pub const CHECKSUM_WIDTH: u8 = 4; // crc32 following the length, when checksums are enabled
 pub const INDEX_RECORD_OFFSET_LENGTH: u8 = 4; // should u32
 pub const POSITION_IN_STORE_FILE_LENGTH: u8 = 8; // u64
 pub const INDEX_ENTRY_LENGTH: u8 = INDEX_RECORD_OFFSET_LENGTH + POSITION_IN_STORE_FILE_LENGTH;
+pub const DEFAULT_SUBSCRIPTION_BUFFER: usize = 1024;
+// appends `Log::append_dedup_by_content` remembers the content of
+const CONTENT_DEDUP_WINDOW: usize = 1024;
+pub const DEFAULT_PROGRESS_INTERVAL: u64 = 1000;
+// directory the next segment is created in ahead of a roll, see `with_precreate_next_segment`.
+// It isn't a base offset, so it's never opened as a segment
+const PRECREATED_DIR: &str = "next";
+
+/// When appended data is fsync'd to disk. Anything but `Never` also fsyncs the log directory
+/// whenever a segment is created, so a crash can't lose the new segment's directory entry
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum SyncPolicy {
+    // leave it to the OS to write data back, once the store's buffer fills or the log is flushed
+    #[default]
+    Never,
+    EveryAppend,
+    // after every n appends to a store
+    EveryN(u64),
+}
+
+impl SyncPolicy {
+    // whether a store should sync after its `appends`th append
+    pub fn should_sync(&self, appends: u64) -> bool {
+        match self {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryAppend => true,
+            SyncPolicy::EveryN(n) => *n > 0 && appends.is_multiple_of(*n),
+        }
+    }
+}
+
+/// What iterating over a log does when it reaches a corrupt record
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum SkipOrFail {
+    // yield a `LogError::Corrupt` for the record and carry on with the next one
+    Skip,
+    // yield the error and stop
+    #[default]
+    Fail,
+}
+
+/// What a log is mostly used for, picking a config tuned for it, see `Config::preset`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Workload {
+    // large segments, batched appends and occasional syncs
+    HighThroughput,
+    // small segments, and each append synced before it returns
+    LowLatency,
+    // large segments with a sparse index
+    SpaceEfficient,
+}
 
 #[derive(Clone)]
 struct SegmentConfig {
@@ -32,18 +101,268 @@ struct SegmentConfig {
     max_record_size_kb: u16,
 }
 
+/// What appending to a log holding its maximum number of records does,
+/// see `ConfigBuilder::with_max_total_records`
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum OnFull {
+    // fail the append with `LogError::LogFull`
+    #[default]
+    Reject,
+    // remove the oldest segment, along with its records, to make room
+    EvictOldest,
+}
+
 #[derive(Clone)]
 pub struct Config {
     segment: SegmentConfig,
+    // capacity of the broadcast channel feeding subscribers
+    subscription_buffer: usize,
+    // grow index files past max_index_bytes instead of rolling to a new segment
+    index_growable: bool,
+    encryption: Option<EncryptionConfig>,
+    // only open segments when they're first accessed
+    lazy_open: bool,
+    // sealed segments are closed again once more than this are open
+    max_open_segments: Option<usize>,
+    sync_policy: SyncPolicy,
+    offset_allocator: Arc<dyn OffsetAllocator>,
+    // appends are buffered until either limit is reached, 0 records disables batching
+    batch_max_records: usize,
+    batch_max_interval: Duration,
+    // store a checksum of each record's length and payload
+    checksums: bool,
+    // the oldest segments are removed once there are more than this
+    max_segments: Option<usize>,
+    scan_on_error: SkipOrFail,
+    // fail appends whose offset isn't exactly one past the previous record's
+    strict_offsets: bool,
+    // only every index_interval-th record of a segment gets an index entry
+    index_interval: u32,
+    // close the log's files when it's dropped
+    flush_on_drop: bool,
+    // check each segment's index against its store when it's opened
+    verify_on_open: bool,
+    // fail to open inconsistent segments instead of recovering them
+    strict_recovery: bool,
+    // records between calls to the progress fn during long scans
+    progress_interval: u64,
+    // most records the log holds, and what appending past that does
+    max_total_records: Option<u64>,
+    on_full: OnFull,
+    // reads through an `AsyncLog` allowed at once, the rest wait their turn
+    max_concurrent_reads: Option<usize>,
+    // how long this many of the latest appends took is kept, 0 doesn't time appends
+    track_latency: usize,
+    // roll to a new segment once the active one is full, rather than failing appends
+    auto_roll: bool,
+    // create the next segment in the background once the active one is nearly full
+    precreate_next_segment: bool,
 }
 
 impl Config {
+    /// A config with small segments that roll after a few hundred bytes,
+    /// meant for tests and examples
+    pub fn small() -> Self {
+        Self {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 256,
+                initial_offset: 0,
+                max_record_size_kb: 128,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A config tuned for workload, so the common cases don't need every setting picked by
+    /// hand. Records aren't compressed and length prefixes have a fixed width whichever
+    /// workload it is, as neither can be configured
+    pub fn preset(workload: Workload) -> Self {
+        const MIB: u64 = 1024 * 1024;
+        match workload {
+            Workload::HighThroughput => Self {
+                segment: SegmentConfig {
+                    max_index_bytes: MIB,
+                    max_store_bytes: 64 * MIB,
+                    initial_offset: 0,
+                    max_record_size_kb: 400,
+                },
+                sync_policy: SyncPolicy::EveryN(1000),
+                batch_max_records: 100,
+                batch_max_interval: Duration::from_millis(10),
+                ..Default::default()
+            },
+            Workload::LowLatency => Self {
+                segment: SegmentConfig {
+                    max_index_bytes: 64 * 1024,
+                    max_store_bytes: MIB,
+                    initial_offset: 0,
+                    max_record_size_kb: 400,
+                },
+                sync_policy: SyncPolicy::EveryAppend,
+                ..Default::default()
+            },
+            Workload::SpaceEfficient => Self {
+                segment: SegmentConfig {
+                    max_index_bytes: 64 * 1024,
+                    max_store_bytes: 64 * MIB,
+                    initial_offset: 0,
+                    max_record_size_kb: 400,
+                },
+                // one entry per 16 records, lookups scan forward from the nearest one
+                index_interval: 16,
+                index_growable: true,
+                ..Default::default()
+            },
+        }
+    }
+
     pub fn get_max_index_bytes(&self) -> u64 {
         self.segment.max_index_bytes
     }
     pub fn get_max_store_bytes(&self) -> u64 {
         self.segment.max_store_bytes
     }
+    pub fn get_subscription_buffer(&self) -> usize {
+        self.subscription_buffer
+    }
+    pub fn is_index_growable(&self) -> bool {
+        self.index_growable
+    }
+    pub fn get_encryption(&self) -> Option<&EncryptionConfig> {
+        self.encryption.as_ref()
+    }
+    pub fn is_lazy_open(&self) -> bool {
+        self.lazy_open
+    }
+    pub fn get_max_open_segments(&self) -> Option<usize> {
+        self.max_open_segments
+    }
+    pub fn get_sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+    pub fn get_initial_offset(&self) -> u64 {
+        self.segment.initial_offset
+    }
+    pub fn get_offset_allocator(&self) -> &dyn OffsetAllocator {
+        self.offset_allocator.as_ref()
+    }
+    pub fn is_batching(&self) -> bool {
+        self.batch_max_records > 0
+    }
+    pub fn has_checksums(&self) -> bool {
+        self.checksums
+    }
+    pub fn get_max_segments(&self) -> Option<usize> {
+        self.max_segments
+    }
+    pub fn get_scan_on_error(&self) -> SkipOrFail {
+        self.scan_on_error
+    }
+    pub fn is_strict_offsets(&self) -> bool {
+        self.strict_offsets
+    }
+    pub fn get_index_interval(&self) -> u32 {
+        self.index_interval.max(1)
+    }
+    pub fn is_flush_on_drop(&self) -> bool {
+        self.flush_on_drop
+    }
+    pub fn is_verify_on_open(&self) -> bool {
+        self.verify_on_open
+    }
+    pub fn is_strict_recovery(&self) -> bool {
+        self.strict_recovery
+    }
+    pub fn get_progress_interval(&self) -> u64 {
+        self.progress_interval.max(1)
+    }
+    pub fn get_max_total_records(&self) -> Option<u64> {
+        self.max_total_records
+    }
+    pub fn get_on_full(&self) -> OnFull {
+        self.on_full
+    }
+    pub fn get_max_concurrent_reads(&self) -> Option<usize> {
+        self.max_concurrent_reads
+    }
+    pub fn get_track_latency(&self) -> usize {
+        self.track_latency
+    }
+    pub fn is_auto_roll(&self) -> bool {
+        self.auto_roll
+    }
+    pub fn is_precreate_next_segment(&self) -> bool {
+        self.precreate_next_segment
+    }
+
+    /// Every setting by name along with its value, formatted for display. The offset allocator
+    /// can't be compared so it's left out, and the encryption key is only shown as enabled
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let optional = |value: Option<usize>| value.map_or("none".to_string(), |v| v.to_string());
+        vec![
+            ("max_index_bytes", self.segment.max_index_bytes.to_string()),
+            ("max_store_bytes", self.segment.max_store_bytes.to_string()),
+            ("initial_offset", self.segment.initial_offset.to_string()),
+            ("max_record_size_kb", self.segment.max_record_size_kb.to_string()),
+            ("subscription_buffer", self.subscription_buffer.to_string()),
+            ("index_growable", self.index_growable.to_string()),
+            ("encrypted", self.encryption.is_some().to_string()),
+            ("lazy_open", self.lazy_open.to_string()),
+            ("max_open_segments", optional(self.max_open_segments)),
+            ("sync_policy", format!("{:?}", self.sync_policy)),
+            ("batch_max_records", self.batch_max_records.to_string()),
+            ("batch_max_interval", format!("{:?}", self.batch_max_interval)),
+            ("checksums", self.checksums.to_string()),
+            ("max_segments", optional(self.max_segments)),
+            ("scan_on_error", format!("{:?}", self.scan_on_error)),
+            ("strict_offsets", self.strict_offsets.to_string()),
+            ("index_interval", self.get_index_interval().to_string()),
+            ("flush_on_drop", self.flush_on_drop.to_string()),
+            ("verify_on_open", self.verify_on_open.to_string()),
+            ("strict_recovery", self.strict_recovery.to_string()),
+            ("progress_interval", self.get_progress_interval().to_string()),
+            (
+                "max_total_records",
+                self.max_total_records.map_or("none".to_string(), |max| max.to_string()),
+            ),
+            ("on_full", format!("{:?}", self.on_full)),
+            ("max_concurrent_reads", optional(self.max_concurrent_reads)),
+            ("track_latency", self.track_latency.to_string()),
+            ("auto_roll", self.auto_roll.to_string()),
+            ("precreate_next_segment", self.precreate_next_segment.to_string()),
+        ]
+    }
+
+    /// The settings that differ from `other`'s, e.g. to show a running config against the one
+    /// in a file. `old` is this config's value and `new` is `other`'s
+    pub fn diff(&self, other: &Config) -> Vec<ConfigDiff> {
+        diff_fields(&self.fields(), &other.fields())
+    }
+}
+
+/// A setting that differs between two configs, see `Config::diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+// compares fields listed in the same order, as returned by `Config::fields`
+pub(crate) fn diff_fields(
+    old: &[(&'static str, String)],
+    new: &[(&'static str, String)],
+) -> Vec<ConfigDiff> {
+    old.iter()
+        .zip(new)
+        .filter(|((_, old), (_, new))| old != new)
+        .map(|((field, old), (_, new))| ConfigDiff {
+            field,
+            old: old.clone(),
+            new: new.clone(),
+        })
+        .collect()
 }
 
 pub struct ConfigBuilder {
@@ -51,6 +370,30 @@ pub struct ConfigBuilder {
     max_store_bytes: u64,
     initial_offset: u64,
     max_record_size_kb: u16,
+    subscription_buffer: usize,
+    index_growable: bool,
+    encryption: Option<EncryptionConfig>,
+    lazy_open: bool,
+    max_open_segments: Option<usize>,
+    sync_policy: SyncPolicy,
+    offset_allocator: Arc<dyn OffsetAllocator>,
+    batch_max_records: usize,
+    batch_max_interval: Duration,
+    checksums: bool,
+    max_segments: Option<usize>,
+    scan_on_error: SkipOrFail,
+    strict_offsets: bool,
+    index_interval: u32,
+    flush_on_drop: bool,
+    verify_on_open: bool,
+    strict_recovery: bool,
+    progress_interval: u64,
+    max_total_records: Option<u64>,
+    on_full: OnFull,
+    max_concurrent_reads: Option<usize>,
+    track_latency: usize,
+    auto_roll: bool,
+    precreate_next_segment: bool,
 }
 
 impl ConfigBuilder {
@@ -72,6 +415,30 @@ impl ConfigBuilder {
             max_store_bytes,
             initial_offset,
             max_record_size_kb: 400,
+            subscription_buffer: DEFAULT_SUBSCRIPTION_BUFFER,
+            index_growable: false,
+            encryption: None,
+            lazy_open: false,
+            max_open_segments: None,
+            sync_policy: SyncPolicy::Never,
+            offset_allocator: Arc::new(SequentialAllocator),
+            batch_max_records: 0,
+            batch_max_interval: Duration::ZERO,
+            checksums: false,
+            max_segments: None,
+            scan_on_error: SkipOrFail::Fail,
+            strict_offsets: false,
+            index_interval: 1,
+            flush_on_drop: true,
+            verify_on_open: false,
+            strict_recovery: false,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            max_total_records: None,
+            on_full: OnFull::Reject,
+            max_concurrent_reads: None,
+            track_latency: 0,
+            auto_roll: true,
+            precreate_next_segment: false,
         }
     }
 
@@ -80,6 +447,181 @@ impl ConfigBuilder {
         self
     }
 
+    /// Sets how many appended records are buffered for each subscriber.
+    /// A subscriber that falls further behind than this misses the oldest records and is told
+    /// how many it missed on its next receive, see [`Subscription::recv`]
+    pub fn with_subscription_buffer(mut self, size: usize) -> Self {
+        self.subscription_buffer = size;
+        self
+    }
+
+    /// When enabled, a full index grows (doubling in size) instead of rolling to a new segment,
+    /// giving fewer, larger segments. Segments still roll when their store is full
+    pub fn with_index_growable(mut self, growable: bool) -> Self {
+        self.index_growable = growable;
+        self
+    }
+
+    /// Encrypts record payloads at rest with the given key.
+    /// The same key must be supplied every time the log is opened. A payload is encrypted in
+    /// one piece, so large values are copied into a buffer with the rest of their record
+    /// first, which unencrypted logs avoid
+    pub fn with_encryption(mut self, encryption: EncryptionConfig) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// When enabled, opening a log only opens its active segment, the others are opened
+    /// (mmapping their index and opening their store) the first time they're read. Their
+    /// lengths and offsets come from the manifest until then, a segment it doesn't describe
+    /// (or whose store changed size since) is still opened up front
+    pub fn with_lazy_open(mut self, lazy_open: bool) -> Self {
+        self.lazy_open = lazy_open;
+        self
+    }
+
+    /// Keeps at most `max` sealed segments open, closing the least recently used one when
+    /// another is opened, so a log with many segments doesn't run out of file descriptors.
+    /// A closed segment is opened again the next time it's read, the active segment is
+    /// always open on top of these. A segment still being read when it would be closed is
+    /// left open until the next one is opened
+    pub fn with_max_open_segments(mut self, max: usize) -> Self {
+        self.max_open_segments = Some(max);
+        self
+    }
+
+    /// Sets when appends are fsync'd to disk, defaults to `SyncPolicy::Never`
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets how offsets are given to appended records, defaults to consecutive offsets.
+    /// Offsets can only be 2^32 apart within a segment
+    pub fn with_offset_allocator(mut self, allocator: Arc<dyn OffsetAllocator>) -> Self {
+        self.offset_allocator = allocator;
+        self
+    }
+
+    /// Buffers appended records in memory, writing them to the store and index once
+    /// `max_records` are buffered or the oldest has been buffered for `max_interval`
+    /// (as measured by the log's clock, checked on append). Buffered records can be read
+    /// straight away but aren't persisted until they're written, see `Log::flush`
+    pub fn with_batching(mut self, max_records: usize, max_interval: Duration) -> Self {
+        self.batch_max_records = max_records;
+        self.batch_max_interval = max_interval;
+        self
+    }
+
+    /// Stores a crc32 of each record's length prefix and payload, checked on every read.
+    /// This changes the store's format, so it has to be the same every time the log is opened
+    pub fn with_checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Bounds the log to `max` segments. Rolling to a new segment past that removes the oldest
+    /// segment along with its records, the active segment is never removed
+    pub fn with_max_segments(mut self, max: usize) -> Self {
+        self.max_segments = Some(max);
+        self
+    }
+
+    /// Sets whether iterating skips over corrupt records, e.g. to recover what can be read
+    /// from a damaged log, or stops at the first one (the default)
+    pub fn with_scan_on_error(mut self, scan_on_error: SkipOrFail) -> Self {
+        self.scan_on_error = scan_on_error;
+        self
+    }
+
+    /// Checks every append is given the offset right after the previous one, failing with
+    /// `LogError::OffsetGap` otherwise. Only useful with consecutive offsets, it catches bugs
+    /// in offset allocation before they're written
+    pub fn with_strict_offsets(mut self, strict: bool) -> Self {
+        self.strict_offsets = strict;
+        self
+    }
+
+    /// Only indexes every `interval`-th record of a segment, shrinking the index by that factor.
+    /// Reading a record that isn't indexed scans the store forward from the closest indexed
+    /// record before it. Defaults to 1, indexing every record
+    pub fn with_index_interval(mut self, interval: u32) -> Self {
+        self.index_interval = interval;
+        self
+    }
+
+    /// Whether dropping the log flushes it: writing out batched records and index entries and
+    /// shrinking index files from their preallocated size. Defaults to true. Without it a
+    /// dropped log is left as after a crash, which reopening recovers from, but batched
+    /// records are lost
+    pub fn with_flush_on_drop(mut self, flush_on_drop: bool) -> Self {
+        self.flush_on_drop = flush_on_drop;
+        self
+    }
+
+    /// Checks each segment's index accounts for every record in its store when the segment is
+    /// opened, which it may not after a crash between writing a record and indexing it.
+    /// An inconsistent segment is recovered by dropping a torn record at the end of its store
+    /// and rebuilding its index, unless `with_strict_recovery` is set
+    pub fn with_verify_on_open(mut self, verify: bool) -> Self {
+        self.verify_on_open = verify;
+        self
+    }
+
+    /// Fails to open inconsistent segments found by `with_verify_on_open`, rather than
+    /// recovering them
+    pub fn with_strict_recovery(mut self, strict: bool) -> Self {
+        self.strict_recovery = strict;
+        self
+    }
+
+    /// How many records `verify`, `reindex_all` and compaction go through between calls to the
+    /// log's progress fn, see `Log::set_progress_fn`. Defaults to 1000
+    pub fn with_progress_interval(mut self, interval: u64) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Bounds the log to `max` records. Appending once it holds that many either fails or
+    /// removes the oldest segment first, as `on_full` says. Records are evicted a whole
+    /// segment at a time, so a log can hold fewer than `max` after an eviction
+    pub fn with_max_total_records(mut self, max: u64, on_full: OnFull) -> Self {
+        self.max_total_records = Some(max);
+        self.on_full = on_full;
+        self
+    }
+
+    /// Limits `AsyncLog::read` to `max` reads at a time, so a burst of readers waits for a turn
+    /// instead of all piling onto the log. Appends aren't limited
+    pub fn with_max_concurrent_reads(mut self, max: usize) -> Self {
+        self.max_concurrent_reads = Some(max);
+        self
+    }
+
+    /// Times writing each append to its segment, keeping the latest `appends` of them for
+    /// `Log::append_latencies`. 0 (the default) doesn't time appends at all
+    pub fn with_track_latency(mut self, appends: usize) -> Self {
+        self.track_latency = appends;
+        self
+    }
+
+    /// Whether a full active segment rolls over to a new one. Defaults to true. Without it the
+    /// log stays in the segments it has and appends that don't fit fail with
+    /// `LogError::SegmentFull`, e.g. to keep a fixed size log. `Log::seal` still rolls
+    pub fn with_auto_roll(mut self, auto_roll: bool) -> Self {
+        self.auto_roll = auto_roll;
+        self
+    }
+
+    /// Creates the next segment's files on a background thread once the active segment is 90%
+    /// full, so rolling over to it only has to rename its directory rather than create and
+    /// preallocate files in the middle of an append. Defaults to false. In-memory logs have no
+    /// files to create, so they ignore it
+    pub fn with_precreate_next_segment(mut self, precreate: bool) -> Self {
+        self.precreate_next_segment = precreate;
+        self
+    }
+
     pub fn build(self) -> Config {
         Config {
             segment: SegmentConfig {
@@ -88,6 +630,30 @@ impl ConfigBuilder {
                 initial_offset: self.initial_offset,
                 max_record_size_kb: self.max_record_size_kb,
             },
+            subscription_buffer: self.subscription_buffer,
+            index_growable: self.index_growable,
+            encryption: self.encryption,
+            lazy_open: self.lazy_open,
+            max_open_segments: self.max_open_segments,
+            sync_policy: self.sync_policy,
+            offset_allocator: self.offset_allocator,
+            batch_max_records: self.batch_max_records,
+            batch_max_interval: self.batch_max_interval,
+            checksums: self.checksums,
+            max_segments: self.max_segments,
+            scan_on_error: self.scan_on_error,
+            strict_offsets: self.strict_offsets,
+            index_interval: self.index_interval,
+            flush_on_drop: self.flush_on_drop,
+            verify_on_open: self.verify_on_open,
+            strict_recovery: self.strict_recovery,
+            progress_interval: self.progress_interval,
+            max_total_records: self.max_total_records,
+            on_full: self.on_full,
+            max_concurrent_reads: self.max_concurrent_reads,
+            track_latency: self.track_latency,
+            auto_roll: self.auto_roll,
+            precreate_next_segment: self.precreate_next_segment,
         }
     }
 }
@@ -101,6 +667,30 @@ impl Default for Config {
                 initial_offset: 0,
                 max_record_size_kb: 400,
             },
+            subscription_buffer: DEFAULT_SUBSCRIPTION_BUFFER,
+            index_growable: false,
+            encryption: None,
+            lazy_open: false,
+            max_open_segments: None,
+            sync_policy: SyncPolicy::Never,
+            offset_allocator: Arc::new(SequentialAllocator),
+            batch_max_records: 0,
+            batch_max_interval: Duration::ZERO,
+            checksums: false,
+            max_segments: None,
+            scan_on_error: SkipOrFail::Fail,
+            strict_offsets: false,
+            index_interval: 1,
+            flush_on_drop: true,
+            verify_on_open: false,
+            strict_recovery: false,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            max_total_records: None,
+            on_full: OnFull::Reject,
+            max_concurrent_reads: None,
+            track_latency: 0,
+            auto_roll: true,
+            precreate_next_segment: false,
         }
     }
 }
@@ -113,6 +703,96 @@ pub enum LogError {
     #[error("Record too large")]
     RecordTooLarge,
 
+    #[error("Record failed validation: {0}")]
+    ValidationFailed(String),
+
+    #[error("No segment has base offset {0}")]
+    SegmentNotFound(u64),
+
+    #[error("Segment {0} is still being appended to")]
+    ActiveSegment(u64),
+
+    #[error("Segment {0} has changed since it was compacted")]
+    StaleCompaction(u64),
+
+    // the offset is before the oldest segment, its record was removed with its segment
+    #[error("Record {0} is gone, it's before the oldest record kept")]
+    OffsetGone(u64),
+
+    // the offset is between the lowest and highest offsets, but its record was removed
+    #[error("Record {0} has been compacted away")]
+    RecordCompacted(u64),
+
+    #[error("Offset {0} is past the end of the log")]
+    OffsetPastEnd(u64),
+
+    // a record that can't be decoded, yielded when iterating with `SkipOrFail::Skip`
+    #[error("Record {0} is corrupt")]
+    Corrupt(u64),
+
+    #[error("No partition {0}")]
+    PartitionNotFound(usize),
+
+    #[error("Record {0} isn't valid utf-8")]
+    InvalidUtf8(u64),
+
+    #[error("Record {0} has expired")]
+    RecordExpired(u64),
+
+    #[error("Manifest {0} is corrupt or doesn't match the log's segments")]
+    CorruptManifest(PathBuf),
+
+    #[error("The log's writer has stopped")]
+    WriterStopped,
+
+    #[error("Log holds as many records as it can")]
+    LogFull,
+
+    // the active segment, with this base offset, is full and rolling is turned off
+    #[error("Segment {0} is full")]
+    SegmentFull(u64),
+
+    #[error("Cancelled by the progress fn after {0} records")]
+    Cancelled(u64),
+
+    #[error("Cannot rewind to offset {0}, it's not in the log")]
+    InvalidRewind(u64),
+
+    #[error("Log was created with {field} {stored} but opened with {supplied}")]
+    ConfigMismatch {
+        field: String,
+        stored: String,
+        supplied: String,
+    },
+
+    #[error("Log is a read only snapshot")]
+    ReadOnly,
+
+    // the log was reset or rewound since the checkpoint was taken
+    #[error("Log no longer has the records checkpoint {0} was taken after")]
+    CheckpointMismatch(u64),
+
+    // segments are partly rewritten into another format, see `Log::open_and_migrate`
+    #[error("Log {0} is part way through a migration")]
+    MigrationPending(PathBuf),
+
+    #[error("Expected to append offset {expected} but got {got}")]
+    OffsetGap { expected: u64, got: u64 },
+
+    // appending at this offset would leave no offset for the next record, without wrapping
+    #[error("No offsets left after {0}")]
+    OffsetExhausted(u64),
+
+    #[error("Expected next offset {expected} but it was {actual}")]
+    OffsetConflict { expected: u64, actual: u64 },
+
+    #[error("Producer {producer} sent sequence {sequence} after {last}")]
+    StaleSequence {
+        producer: u64,
+        sequence: u64,
+        last: u64,
+    },
+
     #[error(transparent)]
     ParseIntError(#[from] ParseIntError),
 
@@ -125,379 +805,5567 @@ pub enum LogError {
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
+    // an io error along with what was being done when it happened
+    #[error("{context}{}: {source}", path.as_ref().map(|p| format!(" {}", p.display())).unwrap_or_default())]
+    Io {
+        source: std::io::Error,
+        context: String,
+        path: Option<PathBuf>,
+    },
+
     #[error(transparent)]
     SegmentErrors(#[from] SegmentError),
 }
+
+/// Adds what was being done, and to which path, to io errors
+trait IoContext<T> {
+    fn context(self, context: &str, path: &Path) -> Result<T, LogError>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn context(self, context: &str, path: &Path) -> Result<T, LogError> {
+        self.map_err(|source| LogError::Io {
+            source,
+            context: context.to_string(),
+            path: Some(path.to_path_buf()),
+        })
+    }
+}
+
+/// Adds what was being done, and to which segment's directory, to io errors from a segment,
+/// its store or its index. Other segment errors are passed on as they are
+trait SegmentContext<T> {
+    fn segment_context(self, context: &str, dir: &Path) -> Result<T, LogError>;
+}
+
+impl<T> SegmentContext<T> for Result<T, SegmentError> {
+    fn segment_context(self, context: &str, dir: &Path) -> Result<T, LogError> {
+        self.map_err(|e| with_segment_context(e, context, dir))
+    }
+}
+
+fn with_segment_context(e: SegmentError, context: &str, dir: &Path) -> LogError {
+    match e {
+        SegmentError::IOError(source)
+        | SegmentError::StoreErrors(StoreError::IOError(source))
+        | SegmentError::IndexErrors(IndexError::IOError(source)) => LogError::Io {
+            source,
+            context: context.to_string(),
+            path: Some(dir.to_path_buf()),
+        },
+        e => e.into(),
+    }
+}
 pub struct Log {
     dir: PathBuf, // where we store segments
     config: Arc<Config>,
     active_segment: usize,
-    segments: Vec<Segment>,
+    segments: Vec<LazySegment>,
+    in_memory: bool, // segments are backed by memory instead of files under dir
+    subscribers: broadcast::Sender<Record>,
+    validator: Option<Validator>,
+    progress_fn: Option<ProgressFn>,
+    clock: Arc<dyn Clock>,
+    batch: VecDeque<Record>, // appended records not yet written, when batching
+    batch_started_at: u64,   // when the oldest record in the batch was appended
+    producers: HashMap<u64, (u64, u64)>, // last sequence appended by each producer, and its offset
+    drained: u64, // the next offset `drain` returns, records before it were already drained
+    snapshot: bool, // made by `Log::snapshot`, so it can't be changed and its dir is temporary
+    latencies: VecDeque<Duration>, // how long the latest appends took, see `with_track_latency`
+    recent_contents: VecDeque<(u64, u64)>, // content hash and offset of recent dedup appends
+    precreated: Option<JoinHandle<Result<Segment, SegmentError>>>, // next segment, ahead of a roll
+    // base offsets of the open sealed segments, least recently used first, when there's a
+    // limit on how many can be, see `ConfigBuilder::with_max_open_segments`
+    open_segments: Mutex<VecDeque<u64>>,
+}
+
+// the base offset a segment directory is named after, only as `open_segment` names it
+// so e.g. `007` or `+7` aren't taken for segment 7
+fn parse_base_offset(name: &str) -> Option<u64> {
+    name.parse::<u64>()
+        .ok()
+        .filter(|base_offset| base_offset.to_string() == name)
+}
+
+// fsyncs a directory so entries created in it survive a crash
+fn sync_dir(dir: &Path) -> Result<(), LogError> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .context("Cannot sync directory", dir)
+}
+
+// checks a record before it's appended, an error rejects the record
+pub type Validator = Box<dyn Fn(&Record) -> Result<(), String> + Send + Sync>;
+
+/// What `Log::append_idempotent` did with a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppendOutcome {
+    pub offset: AbsOffset,
+    // the record was a retry of one already appended, whose offset this is
+    pub deduplicated: bool,
+}
+
+/// What compacting a log's sealed segments would remove, see `Log::compaction_estimate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionEstimate {
+    pub records: u64,
+    // bytes the segments' stores would shrink by
+    pub bytes: u64,
+}
+
+/// A position in the log to pick up from, see `Log::checkpoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    // every record before it had been appended
+    pub next_offset: u64,
+    // offset of the last record by then along with its digest, none for an empty log
+    pub last: Option<(u64, u64)>,
+}
+
+// whether compaction keeps the record at offset: it has no key, or its key isn't written
+// again later. `latest` is the offset each key was last written at
+fn survives_compaction(latest: &HashMap<Vec<u8>, u64>, offset: u64, record: &Record) -> bool {
+    match &record.key {
+        Some(key) => latest.get(key).is_none_or(|latest| *latest <= offset),
+        None => true,
+    }
+}
+
+// told (done, total) records during long scans, returning false cancels the scan
+pub type ProgressFn = Box<dyn Fn(u64, u64) -> bool + Send + Sync>;
+
+// counts the records a long scan has gone through, calling the progress fn every `every`
+struct Ticker<'a> {
+    progress_fn: Option<&'a (dyn Fn(u64, u64) -> bool + Send + Sync)>,
+    every: u64,
+    done: u64,
+    total: u64,
+}
+
+impl Ticker<'_> {
+    // called after each record, false once the scan should stop
+    fn tick(&mut self) -> bool {
+        self.done += 1;
+        match self.progress_fn {
+            Some(progress_fn) if self.done.is_multiple_of(self.every) || self.done == self.total => {
+                progress_fn(self.done, self.total)
+            }
+            _ => true,
+        }
+    }
+
+    // reports a scan stopped by `tick` as cancelled after the records done so far
+    fn check<T>(&self, result: Result<T, SegmentError>) -> Result<T, LogError> {
+        match result {
+            Err(SegmentError::Cancelled) => Err(LogError::Cancelled(self.done)),
+            result => Ok(result?),
+        }
+    }
 }
 
 impl Log {
-    fn new(dir: PathBuf, config: Option<Config>) -> Result<Self, LogError> {
+    /// Opens the log in `dir`, creating the directory if it doesn't exist. A directory without
+    /// any segments gets one empty segment starting at the config's initial offset
+    pub fn new(dir: PathBuf, config: Option<Config>) -> Result<Self, LogError> {
         if (!dir.exists()) {
-            std::fs::create_dir(&dir)?
+            std::fs::create_dir(&dir).context("Cannot create log directory", &dir)?
         };
+        let config = config.unwrap_or_default();
+        let (subscribers, _) = broadcast::channel(config.subscription_buffer.max(1));
         let mut l = Log {
             dir,
-            config: Arc::new(config.unwrap_or_else(|| Default::default())),
+            config: Arc::new(config),
             active_segment: 0,
             segments: vec![],
+            in_memory: false,
+            subscribers,
+            validator: None,
+            progress_fn: None,
+            clock: Arc::new(SystemClock),
+            batch: VecDeque::new(),
+            batch_started_at: 0,
+            producers: HashMap::new(),
+            drained: 0,
+            snapshot: false,
+            latencies: VecDeque::new(),
+            recent_contents: VecDeque::new(),
+            precreated: None,
+            open_segments: Mutex::new(VecDeque::new()),
         };
 
         l.setup()?;
         Ok(l)
     }
 
-    fn setup(&mut self) -> Result<(), LogError> {
-        let mut base_offsets: Vec<u64> = vec![];
+    /// Creates a log whose segments live entirely in memory, nothing is written to disk
+    pub fn in_memory(config: Config) -> Result<Self, LogError> {
+        let (subscribers, _) = broadcast::channel(config.subscription_buffer.max(1));
+        let mut l = Log {
+            dir: PathBuf::new(),
+            config: Arc::new(config),
+            active_segment: 0,
+            segments: vec![],
+            in_memory: true,
+            subscribers,
+            validator: None,
+            progress_fn: None,
+            clock: Arc::new(SystemClock),
+            batch: VecDeque::new(),
+            batch_started_at: 0,
+            producers: HashMap::new(),
+            drained: 0,
+            snapshot: false,
+            latencies: VecDeque::new(),
+            recent_contents: VecDeque::new(),
+            precreated: None,
+            open_segments: Mutex::new(VecDeque::new()),
+        };
+
+        l.setup()?;
+        Ok(l)
+    }
+
+    /// Opens the log in `dir` like `new`, first rewriting its segments in the format `config`
+    /// asks for when they were written with different checksums or a different index interval,
+    /// e.g. to add checksums to an existing log. Each segment's store is rewritten next to it
+    /// and renamed over it, and its index rebuilt. Progress is kept in the log's directory, so
+    /// an interrupted migration carries on from where it stopped when this is called again,
+    /// while `new` refuses to open the log. Any other difference in format fails with
+    /// `LogError::ConfigMismatch`, as it does for `new`
+    pub fn open_and_migrate(dir: PathBuf, config: Config) -> Result<Self, LogError> {
+        let migration = match Migration::read(&dir).context("Cannot read log migration", &dir)? {
+            Some(migration) => Some(migration),
+            None => Self::plan_migration(&dir, &config)?,
+        };
+        if let Some(migration) = migration {
+            Self::migrate(&dir, &config, migration)?;
+        }
+        Self::new(dir, Some(config))
+    }
+
+    // the migration a log written in another format needs to be opened with config, if any
+    fn plan_migration(dir: &Path, config: &Config) -> Result<Option<Migration>, LogError> {
+        let Some(stored) = Manifest::read(dir).context("Cannot read log manifest", dir)? else {
+            return Ok(None);
+        };
+        let supplied = Manifest::new(config, vec![]);
+        if stored.checksums == supplied.checksums
+            && stored.index_interval == supplied.index_interval
+        {
+            // `new` refuses any other difference
+            return Ok(None);
+        }
+        let migrated = Manifest {
+            checksums: supplied.checksums,
+            index_interval: supplied.index_interval,
+            segments: vec![],
+            ..stored.clone()
+        };
+        if let Some(diff) = migrated.mismatch(&supplied) {
+            return Err(LogError::ConfigMismatch {
+                field: diff.field.to_string(),
+                stored: diff.old,
+                supplied: diff.new,
+            });
+        }
+        Ok(Some(Migration {
+            from_checksums: stored.checksums,
+            from_index_interval: stored.index_interval,
+            checksums: supplied.checksums,
+            index_interval: supplied.index_interval,
+            segments: stored.segments,
+            done: vec![],
+            current: None,
+        }))
+    }
+
+    // rewrites the segments migration hasn't done yet, then rebuilds every index and writes
+    // the manifest in the new format. Each step is written down before the next one starts
+    fn migrate(dir: &Path, config: &Config, mut migration: Migration) -> Result<(), LogError> {
+        // resuming has to carry on to the same format
+        let fields = [
+            ("checksums", migration.checksums.to_string(), config.has_checksums().to_string()),
+            (
+                "index_interval",
+                migration.index_interval.to_string(),
+                config.get_index_interval().to_string(),
+            ),
+        ];
+        let mismatch = fields.into_iter().find(|(_, stored, supplied)| stored != supplied);
+        if let Some((field, stored, supplied)) = mismatch {
+            return Err(LogError::ConfigMismatch {
+                field: field.to_string(),
+                stored,
+                supplied,
+            });
+        }
+        migration.write(dir).context("Cannot write log migration", dir)?;
+
+        let mut from = config.clone();
+        from.checksums = migration.from_checksums;
+        from.index_interval = migration.from_index_interval;
+        let (from, to) = (Arc::new(from), Arc::new(config.clone()));
+        for base in migration.segments.clone() {
+            if migration.done.contains(&base) {
+                continue;
+            }
+            let segment_dir = dir.join(base.to_string());
+            let store = segment_dir.join(".store");
+            let rewritten = segment_dir.join(".store.migrate");
+            if migration.current != Some(base) {
+                // left over from an interrupted rewrite, which may not have finished
+                let _ = std::fs::remove_file(&rewritten);
+                Self::rewrite_store(&segment_dir, base, from.clone(), to.clone(), &rewritten)?;
+                migration.current = Some(base);
+                migration.write(dir).context("Cannot write log migration", dir)?;
+            }
+            // already gone if it was renamed before being interrupted
+            if rewritten.exists() {
+                std::fs::rename(&rewritten, &store).context("Cannot replace store", &store)?;
+            }
+            migration.current = None;
+            migration.done.push(base);
+            migration.write(dir).context("Cannot write log migration", dir)?;
+        }
 
-        // read all segment files
-        for files in std::fs::read_dir(&self.dir)? {
-            let file = files?;
-            let path = file.path();
+        // the old indexes point into the old stores
+        for &base in &migration.segments {
+            let segment_dir = dir.join(base.to_string());
+            let index = segment_dir.join(".index");
+            match std::fs::remove_file(&index) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).context("Cannot remove index", &index)
+                }
+                _ => {}
+            }
+            let mut segment = Segment::new(segment_dir, AbsOffset(base), to.clone())?;
+            segment.rebuild_index()?;
+            segment.close()?;
+        }
+        Manifest::new(config, migration.segments)
+            .write(dir, true)
+            .context("Cannot write log manifest", dir)?;
+        let path = dir.join(MIGRATION_FILE);
+        std::fs::remove_file(&path).context("Cannot remove log migration", &path)?;
+        sync_dir(dir)
+    }
 
-            let r = path
-                .file_stem()
-                .and_then(|file_name| file_name.to_str())
-                .map_or_else(
-                    || Err(LogError::InvalidSegmentFile(file.path())),
-                    |stripped| Ok(stripped),
-                )?;
+    // writes the records of the segment in segment_dir, as stored in the `from` format,
+    // to a new store at `path` in the `to` format, synced so it can replace the old one
+    fn rewrite_store(
+        segment_dir: &Path,
+        base: u64,
+        from: Arc<Config>,
+        to: Arc<Config>,
+        path: &Path,
+    ) -> Result<(), LogError> {
+        let segment = Segment::new(segment_dir.to_path_buf(), AbsOffset(base), from)?;
+        let mut store = Store::new(path.to_path_buf(), to)?;
+        for (offset, mut record) in segment.records()? {
+            // records appended already encoded may not carry their offset
+            record.offset = Some(offset.0);
+            store.append_framed(record.encode_to_vec())?;
+        }
+        store.sync()?;
+        Ok(())
+    }
 
-            let base_offset = r.parse::<u64>()?;
-            base_offsets.push(base_offset);
+    fn setup(&mut self) -> Result<(), LogError> {
+        if self.in_memory {
+            if self.segments.is_empty() {
+                self.new_segment(self.config.segment.initial_offset)?;
+            }
+            return Ok(());
         }
+        // its segments aren't all in the same format until the migration is finished
+        if self.dir.join(MIGRATION_FILE).exists() {
+            return Err(LogError::MigrationPending(self.dir.clone()));
+        }
+
+        let manifest_path = self.dir.join(MANIFEST_FILE);
+        let manifest = match Manifest::read(&self.dir) {
+            Ok(manifest) => manifest,
+            // a corrupt manifest is rebuilt from the segments in the directory
+            Err(e) if e.kind() == io::ErrorKind::InvalidData && !self.config.strict_recovery => None,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(LogError::CorruptManifest(manifest_path))
+            }
+            Err(e) => return Err(e).context("Cannot read log manifest", &manifest_path),
+        };
+
+        // what the manifest knows about sealed segments, to open them lazily
+        let mut sealed: HashMap<u64, SegmentMeta> = HashMap::new();
+        let mut base_offsets = match manifest {
+            Some(manifest) => {
+                sealed.extend(manifest.sealed.iter().copied());
+                // refuse configs that would misread the files already on disk
+                let supplied = Manifest::new(&self.config, vec![]);
+                if let Some(diff) = manifest.mismatch(&supplied) {
+                    return Err(LogError::ConfigMismatch {
+                        field: diff.field.to_string(),
+                        stored: diff.old,
+                        supplied: diff.new,
+                    });
+                }
+
+                // segments removed from, or added to, the directory behind the log's back.
+                // Only checking for missing ones avoids scanning the directory
+                let mut listed = manifest.segments;
+                let tampered = listed
+                    .iter()
+                    .any(|base| !self.dir.join(base.to_string()).is_dir())
+                    || (self.config.verify_on_open && {
+                        let mut scanned = self.scan_segments()?;
+                        scanned.sort();
+                        listed.sort();
+                        scanned != listed
+                    });
+                match tampered {
+                    false => listed,
+                    true if self.config.strict_recovery => {
+                        return Err(LogError::CorruptManifest(manifest_path))
+                    }
+                    true => self.scan_segments()?,
+                }
+            }
+            None => self.scan_segments()?,
+        };
 
         // arrange base offsets in ascending order
 
         base_offsets.sort();
 
+        // the active segment is always open
+        let active = base_offsets.last().copied();
         for offset in base_offsets {
-            self.new_segment(offset)?;
+            let meta = match sealed.get(&offset) {
+                Some(meta) if self.config.lazy_open && Some(offset) != active => {
+                    self.still_describes(offset, meta).then_some(*meta)
+                }
+                _ => None,
+            };
+            self.open_segment(offset, meta)?;
         }
         if self.segments.is_empty() {
             // create a new segment
             self.new_segment(self.config.segment.initial_offset)?;
         }
+        self.write_manifest()?;
 
         Ok(())
     }
 
-    fn new_segment(&mut self, offset: u64) -> Result<(), LogError> {
-        // create segment directory under log directory
-        let segment_dir = self.dir.join(offset.to_string());
-        if !segment_dir.exists() {
-            std::fs::create_dir(&segment_dir)?;
+    // base offsets of the segments in the log's directory. Each segment is a directory named
+    // after its base offset, anything else (stray files, other directories) is left alone
+    fn scan_segments(&self) -> Result<Vec<u64>, LogError> {
+        let mut base_offsets: Vec<u64> = vec![];
+
+        for entry in std::fs::read_dir(&self.dir).context("Cannot read log directory", &self.dir)? {
+            let entry = entry.context("Cannot read log directory", &self.dir)?;
+            let file_type = entry.file_type().context("Cannot read log directory", &entry.path())?;
+            if !file_type.is_dir() {
+                continue;
+            }
+            if let Some(base_offset) = entry.file_name().to_str().and_then(parse_base_offset) {
+                base_offsets.push(base_offset);
+            }
         }
-        let segment = Segment::new(segment_dir, offset, self.config.clone())?;
-        let len_segments = self.segments.len();
-        self.segments.push(segment);
-        self.active_segment = len_segments;
+        Ok(base_offsets)
+    }
 
-        Ok(())
+    // whether a sealed segment's store is still the size meta was taken at. A store that was
+    // written to after the manifest, or cut short by recovering from a crash, is opened instead
+    fn still_describes(&self, base_offset: u64, meta: &SegmentMeta) -> bool {
+        let store = self.dir.join(base_offset.to_string()).join(".store");
+        std::fs::metadata(store).is_ok_and(|store| store.len() == meta.store_size)
     }
 
-    pub fn append(&mut self, record: Record) -> Result<u64, LogError> {
-        if record.value.len() > (self.config.segment.max_record_size_kb as usize) {
-            return Err(LogError::RecordTooLarge);
+    // lists the log's current segments in its manifest, along with what's known about the
+    // sealed ones
+    fn write_manifest(&self) -> Result<(), LogError> {
+        if self.in_memory {
+            return Ok(());
         }
-        let mut active_segment = &mut self.segments[self.active_segment];
+        let segments = self.segments.iter().map(|segment| segment.base_offset).collect();
+        let sealed = self.segments[..self.active_segment]
+            .iter()
+            .map(|segment| (segment.base_offset, segment.meta()))
+            .collect();
+        let sync = self.config.sync_policy != SyncPolicy::Never;
+        Manifest {
+            sealed,
+            ..Manifest::new(&self.config, segments)
+        }
+        .write(&self.dir, sync)
+        .context("Cannot write log manifest", &self.dir)?;
+        if sync {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
 
-        match active_segment.append(record) {
-            Ok(offset) => {
-                if active_segment.is_maxed() {
-                    self.new_segment(offset + 1)?;
+    fn new_segment(&mut self, offset: u64) -> Result<(), LogError> {
+        self.open_segment(offset, None)?;
+        self.evict_segments();
+        self.write_manifest()
+    }
+
+    // rolls to a new segment at offset because the active one is full, unless auto roll is off
+    fn roll_full(&mut self, offset: u64) -> Result<(), LogError> {
+        if !self.config.auto_roll {
+            let base_offset = self.segments[self.active_segment].base_offset;
+            return Err(LogError::SegmentFull(base_offset));
+        }
+        self.new_segment(offset)
+    }
+
+    // removes the oldest segments while there are more than max_segments
+    fn evict_segments(&mut self) {
+        let Some(max) = self.config.max_segments else {
+            return;
+        };
+        while self.segments.len() > max.max(1) {
+            let segment = self.segments.remove(0);
+            self.active_segment -= 1;
+            self.delete_segment(segment);
+        }
+    }
+
+    // removes a segment's files along with its directory
+    fn delete_segment(&self, mut segment: LazySegment) {
+        let (store, _) = segment.paths();
+        segment.remove();
+        if !self.in_memory {
+            if let Some(dir) = store.parent() {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+    }
+
+    // opens the segment at offset as the active one, or only describes it by meta when given
+    // one, to be opened on first access
+    fn open_segment(&mut self, offset: u64, meta: Option<SegmentMeta>) -> Result<(), LogError> {
+        if self.in_memory {
+            let len_segments = self.segments.len();
+            let mut segment = Segment::in_memory(AbsOffset(offset), self.config.clone());
+            segment.clock = self.clock.clone();
+            self.segments.push(LazySegment::loaded(segment));
+            self.active_segment = len_segments;
+            return Ok(());
+        }
+
+        // create segment directory under log directory
+        let segment_dir = self.dir.join(offset.to_string());
+        let created = !segment_dir.exists();
+        // a segment created ahead of time only needs to be moved into place
+        let precreated = if created && meta.is_none() {
+            self.take_precreated(&segment_dir, offset)
+        } else {
+            None
+        };
+        if created && precreated.is_none() {
+            std::fs::create_dir(&segment_dir).context("Cannot create segment directory", &segment_dir)?;
+        }
+        let segment = match (precreated, meta) {
+            (Some(segment), _) => segment,
+            (None, Some(meta)) => LazySegment::new(
+                segment_dir.clone(),
+                AbsOffset(offset),
+                self.config.clone(),
+                self.clock.clone(),
+                meta,
+            ),
+            (None, None) => {
+                match Segment::new(segment_dir.clone(), AbsOffset(offset), self.config.clone())
+                    .segment_context("Cannot open segment", &segment_dir)
+                {
+                    Ok(mut segment) => {
+                        segment.clock = self.clock.clone();
+                        LazySegment::loaded(segment)
+                    }
+                    Err(e) => {
+                        // don't leave an empty segment behind to be picked up on the next open
+                        if created {
+                            let _ = std::fs::remove_dir_all(&segment_dir);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        if created && self.config.sync_policy != SyncPolicy::Never {
+            // persist the new store and index entries, then the segment directory itself
+            sync_dir(&segment_dir)?;
+            sync_dir(&self.dir)?;
+        }
+        // only point at the segment once it's been fully set up
+        let len_segments = self.segments.len();
+        self.segments.push(segment);
+        self.active_segment = len_segments;
+        // the previous active segment is sealed now, so it counts towards the open ones
+        if len_segments > 0 && self.segments[len_segments - 1].is_loaded() {
+            self.touch(len_segments - 1)?;
+        }
+
+        Ok(())
+    }
+
+    // the ith segment, opening it if it isn't already
+    fn segment(&self, i: usize) -> Result<SegmentRef<'_>, LogError> {
+        self.touch(i)?;
+        let segment = &self.segments[i];
+        segment
+            .get()
+            .segment_context("Cannot open segment", segment.dir())
+    }
+
+    // the ith segment to change, opening it if it isn't already
+    fn segment_mut(&mut self, i: usize) -> Result<&mut Segment, LogError> {
+        self.touch(i)?;
+        let segment = &mut self.segments[i];
+        let dir = segment.dir().to_path_buf();
+        segment
+            .get_mut()
+            .segment_context("Cannot open segment", &dir)
+    }
+
+    // the segment appends go to, which is always open
+    fn active(&mut self) -> Result<&mut Segment, SegmentError> {
+        self.segments[self.active_segment].get_mut()
+    }
+
+    // marks the ith segment as the most recently used open one, closing the least recently
+    // used sealed segments past `max_open_segments`. The active segment isn't counted, and
+    // neither is the ith one, which is about to be used
+    fn touch(&self, i: usize) -> Result<(), LogError> {
+        let Some(max) = self.config.max_open_segments else {
+            return Ok(());
+        };
+        if i == self.active_segment {
+            return Ok(());
+        }
+        let base_offset = self.segments[i].base_offset;
+        let mut open = self.open_segments.lock().unwrap_or_else(|e| e.into_inner());
+        open.retain(|open| *open != base_offset);
+        open.push_back(base_offset);
+
+        let mut j = 0;
+        while open.len() > max && j + 1 < open.len() {
+            let closed = match self
+                .segments
+                .binary_search_by_key(&open[j], |s| s.base_offset)
+            {
+                Ok(k) if k != self.active_segment => {
+                    let unloaded = self.segments[k].unload();
+                    if unloaded.is_err() {
+                        // unloaded anyway, so it's no longer open
+                        open.remove(j);
+                    }
+                    unloaded.segment_context("Cannot close segment", self.segments[k].dir())?
+                }
+                // removed since, or the active segment again after a rewind
+                _ => true,
+            };
+            if closed {
+                open.remove(j);
+            } else {
+                // still being read, it's closed once it's next in line
+                j += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // starts creating the next segment in the background once the active one is nearly full,
+    // see `ConfigBuilder::with_precreate_next_segment`
+    fn precreate_if_nearly_full(&mut self) {
+        if !self.config.precreate_next_segment
+            || self.in_memory
+            || self.precreated.is_some()
+            || !self.active().is_ok_and(|segment| segment.is_nearly_full())
+        {
+            return;
+        }
+        let dir = self.dir.join(PRECREATED_DIR);
+        let config = self.config.clone();
+        self.precreated = Some(std::thread::spawn(
+            move || -> Result<Segment, SegmentError> {
+                // left behind by a log that wasn't dropped
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir)?;
+                }
+                std::fs::create_dir(&dir)?;
+                // its base offset isn't known until the roll
+                Segment::new(dir, AbsOffset(0), config)
+            },
+        ));
+    }
+
+    // the segment created ahead of the roll to offset, moved into segment_dir. None when there
+    // isn't one or it couldn't be created, so the roll creates a segment itself
+    fn take_precreated(&mut self, segment_dir: &Path, offset: u64) -> Option<LazySegment> {
+        let precreated = self.precreated.take()?.join();
+        let dir = self.dir.join(PRECREATED_DIR);
+        match precreated {
+            Ok(Ok(mut segment)) if std::fs::rename(&dir, segment_dir).is_ok() => {
+                segment.rebase_empty(segment_dir, AbsOffset(offset));
+                segment.clock = self.clock.clone();
+                Some(LazySegment::loaded(segment))
+            }
+            _ => {
+                let _ = std::fs::remove_dir_all(&dir);
+                None
+            }
+        }
+    }
+
+    // waits for a segment being created ahead of a roll, and removes it
+    fn discard_precreated(&mut self) {
+        if let Some(precreated) = self.precreated.take() {
+            drop(precreated.join());
+            let _ = std::fs::remove_dir_all(self.dir.join(PRECREATED_DIR));
+        }
+    }
+
+    /// The config the log was opened with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Sets the clock used to timestamp appended records, defaults to the system clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        for segment in &mut self.segments {
+            segment.set_clock(clock.clone());
+        }
+        self.clock = clock;
+    }
+
+    /// Sets a validator that every record must pass before it's appended.
+    /// A rejected record isn't written and `append` returns `LogError::ValidationFailed`
+    pub fn set_validator(&mut self, validator: Validator) {
+        self.validator = Some(validator);
+    }
+
+    /// Sets a fn that `verify`, `reindex_all` and compaction call every
+    /// `ConfigBuilder::with_progress_interval` records with the number of records done and the
+    /// total. Returning false cancels the scan, which fails with `LogError::Cancelled`
+    pub fn set_progress_fn(&mut self, progress_fn: ProgressFn) {
+        self.progress_fn = Some(progress_fn);
+    }
+
+    // tracks a scan through total records
+    fn ticker(&self, total: u64) -> Ticker<'_> {
+        Ticker {
+            progress_fn: self.progress_fn.as_deref(),
+            every: self.config.get_progress_interval(),
+            done: 0,
+            total,
+        }
+    }
+
+    pub fn append(&mut self, record: Record) -> Result<AbsOffset, LogError> {
+        self.append_cow(Cow::Owned(record)).map(AbsOffset)
+    }
+
+    /// Appends record, returning its offset along with the base offset of the segment it was
+    /// written to, e.g. to assign segments to replicas. A batched record isn't written yet,
+    /// so it gets the active segment's, which is where it's written unless the segment fills
+    pub fn append_located(&mut self, record: Record) -> Result<(AbsOffset, AbsOffset), LogError> {
+        let offset = self.append(record)?;
+        // not the active segment when appending filled it and rolled to a new one
+        let i = self.segment_index(offset.0).unwrap_or(self.active_segment);
+        Ok((offset, AbsOffset(self.segments[i].base_offset)))
+    }
+
+    /// Appends a borrowed record, so one record can be appended over and over without
+    /// cloning it. The offset and timestamp it's given are only written to the log, the
+    /// record is left as it was. It's still cloned when batching or when there are subscribers
+    pub fn append_ref(&mut self, record: &Record) -> Result<AbsOffset, LogError> {
+        self.append_cow(Cow::Borrowed(record)).map(AbsOffset)
+    }
+
+    // appends a record that's only cloned when it has to be kept
+    fn append_cow(&mut self, record: Cow<'_, Record>) -> Result<u64, LogError> {
+        self.check_writable()?;
+        if let Some(validator) = &self.validator {
+            validator(record.as_ref()).map_err(LogError::ValidationFailed)?;
+        }
+        self.make_room(1)?;
+
+        // only pay for the clone when someone is listening
+        let published = if self.subscribers.receiver_count() > 0 {
+            Some(record.as_ref().clone())
+        } else {
+            None
+        };
+
+        let offset = if self.config.is_batching() {
+            self.batch_record(record.into_owned())?
+        } else {
+            self.append_record(&record)?
+        };
+
+        if let Some(mut record) = published {
+            record.offset = Some(offset);
+            // an error only means every subscriber has gone away
+            let _ = self.subscribers.send(record);
+        }
+        Ok(offset)
+    }
+
+    // makes room for `records` more records in a log bounded by `with_max_total_records`
+    fn make_room(&mut self, records: u64) -> Result<(), LogError> {
+        let Some(max) = self.config.max_total_records else {
+            return Ok(());
+        };
+        if self.config.on_full == OnFull::EvictOldest {
+            let mut evicted = false;
+            while self.len() + records > max
+                && self.segments.iter().any(|segment| segment.len() > 0)
+            {
+                if self.segments.len() == 1 {
+                    // the active segment holds every record, roll so it can be evicted
+                    let next_offset = self.segments[self.active_segment].next_offset();
+                    self.new_segment(next_offset.0)?;
+                    continue;
+                }
+                let segment = self.segments.remove(0);
+                self.active_segment -= 1;
+                self.delete_segment(segment);
+                evicted = true;
+            }
+            if evicted {
+                self.write_manifest()?;
+            }
+        }
+        if self.len() + records > max {
+            return Err(LogError::LogFull);
+        }
+        Ok(())
+    }
+
+    /// Appends records as one record set, a single frame in the store, saving the framing
+    /// of every record but the first. Each record still gets its own offset and is read like
+    /// any other. Returns their offsets. Either every record is appended or none are, a set
+    /// too large for an empty segment fails with `LogError::RecordTooLarge`.
+    /// Batched records are written out first, sets aren't batched
+    pub fn append_set(&mut self, records: Vec<Record>) -> Result<Vec<AbsOffset>, LogError> {
+        self.check_writable()?;
+        if records.is_empty() {
+            return Ok(vec![]);
+        }
+        if let Some(validator) = &self.validator {
+            for record in &records {
+                validator(record).map_err(LogError::ValidationFailed)?;
+            }
+        }
+        let max_record_size = self.config.segment.max_record_size_kb as usize;
+        if records.iter().any(|record| record.value.len() > max_record_size) {
+            return Err(LogError::RecordTooLarge);
+        }
+        self.make_room(records.len() as u64)?;
+        self.flush_batch()?;
+        // the set is timed as one append
+        let started = self.start_timer();
+        self.roll_if_index_full()?;
+
+        // a set that doesn't fit is retried once on a new segment
+        let full = |e: &SegmentError| {
+            matches!(
+                e,
+                SegmentError::StoreErrors(StoreError::StoreFullError)
+                    | SegmentError::IndexErrors(IndexError::IndexFullError)
+            )
+        };
+        let offsets = match self.active()?.append_set(&records) {
+            Ok(offsets) => offsets,
+            Err(e) if full(&e) => {
+                let active_segment = &self.segments[self.active_segment];
+                let next_offset = active_segment.next_offset().0;
+                if next_offset == active_segment.base_offset {
+                    return Err(LogError::RecordTooLarge);
+                }
+                self.roll_full(next_offset)?;
+                match self.active()?.append_set(&records) {
+                    Ok(offsets) => offsets,
+                    Err(e) if full(&e) => return Err(LogError::RecordTooLarge),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let next_offset = offsets[offsets.len() - 1].0 + 1;
+        if self.active()?.is_maxed() && self.config.auto_roll {
+            // the set is already appended, see `write_record`
+            let _ = self.new_segment(next_offset);
+        } else {
+            self.precreate_if_nearly_full();
+        }
+        self.record_latency(started);
+        if self.subscribers.receiver_count() > 0 {
+            for (mut record, offset) in records.into_iter().zip(&offsets) {
+                record.offset = Some(offset.0);
+                let _ = self.subscribers.send(record);
+            }
+        }
+        Ok(offsets)
+    }
+
+    // gives record its offset and adds it to the batch, writing the batch out once it's full
+    fn batch_record(&mut self, mut record: Record) -> Result<u64, LogError> {
+        if record.value.len() > (self.config.segment.max_record_size_kb as usize) {
+            return Err(LogError::RecordTooLarge);
+        }
+
+        let offset = match self.batch.back() {
+            Some(last) => self
+                .config
+                .get_offset_allocator()
+                .next(last.offset.unwrap_or_default(), &record),
+            None => self.active()?.next_offset_for(&record).0,
+        };
+        // checked here as well as by the segment, so the batch never holds an offset that
+        // can't be written
+        if offset == u64::MAX {
+            return Err(LogError::OffsetExhausted(offset));
+        }
+        let now = self.clock.now_millis();
+        record.offset = Some(offset);
+        if record.timestamp.is_none() {
+            record.timestamp = Some(now);
+        }
+
+        if self.batch.is_empty() {
+            self.batch_started_at = now;
+        }
+        self.batch.push_back(record);
+
+        if self.batch.len() >= self.config.batch_max_records
+            || now.saturating_sub(self.batch_started_at)
+                >= self.config.batch_max_interval.as_millis() as u64
+        {
+            self.flush_batch()?;
+        }
+        Ok(offset)
+    }
+
+    /// Writes out any batched records, see `ConfigBuilder::with_batching`, along with appends
+    /// the stores are still buffering, and syncs them to disk whatever the sync policy.
+    /// If a record can't be written it's left in the batch, along with every record after it
+    pub fn flush(&mut self) -> Result<(), LogError> {
+        self.flush_batch()?;
+        for segment in &mut self.segments {
+            segment.sync().segment_context("Cannot flush segment", segment.dir())?;
+        }
+        Ok(())
+    }
+
+    // writes out batched records and then, unless the sync policy is `Never`, syncs them
+    // all at once, rather than after each like an unbatched append
+    fn flush_batch(&mut self) -> Result<(), LogError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let base_offset = self.segments.last().map_or(0, |segment| segment.base_offset);
+        let mut result = Ok(());
+        while let Some(record) = self.batch.pop_front() {
+            // the segment assigns the same offset the record was given when it was batched
+            if let Err(e) = self.append_record(&record) {
+                self.batch.push_front(record);
+                result = Err(e);
+                break;
+            }
+        }
+
+        // whatever was written goes out, including to segments rolled mid-batch
+        let sync = self.config.get_sync_policy() != SyncPolicy::Never;
+        for segment in self.segments.iter_mut().filter(|s| s.base_offset >= base_offset) {
+            let flushed = if sync { segment.sync() } else { segment.flush() };
+            flushed.segment_context("Cannot flush segment", segment.dir())?;
+        }
+        result
+    }
+
+    /// Appends record only if it would be assigned `expected_next`, so writers that
+    /// read the log before appending can detect that someone else appended in between
+    pub fn append_if(
+        &mut self,
+        expected_next: AbsOffset,
+        record: Record,
+    ) -> Result<AbsOffset, LogError> {
+        let actual = self.next_offset();
+        if actual != expected_next {
+            return Err(LogError::OffsetConflict {
+                expected: expected_next.0,
+                actual: actual.0,
+            });
+        }
+        self.append(record)
+    }
+
+    /// Appends each of records on its own, returning what happened to each in order. Unlike
+    /// appending them one by one with `?`, a record that fails, e.g. because it's too large,
+    /// is skipped and the records after it are still appended
+    pub fn try_append_batch<I>(&mut self, records: I) -> Vec<Result<AbsOffset, LogError>>
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        records
+            .into_iter()
+            .map(|record| self.append(record))
+            .collect()
+    }
+
+    /// Appends record as number `sequence` from `producer`, unless it's a retry of the last
+    /// record appended for that producer, which is returned as deduplicated along with the
+    /// offset it got then. Sequences before the last fail with `LogError::StaleSequence`.
+    /// Only the last sequence of each producer is kept, and only until the log is dropped
+    pub fn append_idempotent(
+        &mut self,
+        producer: u64,
+        sequence: u64,
+        record: Record,
+    ) -> Result<AppendOutcome, LogError> {
+        if let Some(&(last, offset)) = self.producers.get(&producer) {
+            if sequence == last {
+                return Ok(AppendOutcome {
+                    offset: AbsOffset(offset),
+                    deduplicated: true,
+                });
+            }
+            if sequence < last {
+                return Err(LogError::StaleSequence {
+                    producer,
+                    sequence,
+                    last,
+                });
+            }
+        }
+        let offset = self.append(record)?;
+        self.producers.insert(producer, (sequence, offset.0));
+        Ok(AppendOutcome {
+            offset,
+            deduplicated: false,
+        })
+    }
+
+    /// Appends record unless one with the same key and value was among the last 1024 records
+    /// appended through this, returning the earlier record's offset as
+    /// deduplicated. Records are matched by `Record::content_hash`, then compared, so a hash
+    /// collision still appends. Like `append_idempotent` it only remembers until the log is
+    /// dropped, and an earlier record that can't be read anymore doesn't count
+    pub fn append_dedup_by_content(&mut self, record: Record) -> Result<AppendOutcome, LogError> {
+        let hash = record.content_hash();
+        let earlier = self
+            .recent_contents
+            .iter()
+            .rev()
+            .filter(|(seen, _)| *seen == hash)
+            .map(|(_, offset)| *offset)
+            .find(|offset| {
+                self.read_cow(*offset).is_ok_and(|seen| {
+                    seen.key == record.key && seen.value == record.value
+                })
+            });
+        if let Some(offset) = earlier {
+            return Ok(AppendOutcome {
+                offset: AbsOffset(offset),
+                deduplicated: true,
+            });
+        }
+
+        let offset = self.append(record)?;
+        if self.recent_contents.len() >= CONTENT_DEDUP_WINDOW {
+            self.recent_contents.pop_front();
+        }
+        self.recent_contents.push_back((hash, offset.0));
+        Ok(AppendOutcome {
+            offset,
+            deduplicated: false,
+        })
+    }
+
+    /// Returns the offset the next appended record will get
+    pub fn next_offset(&self) -> AbsOffset {
+        match self.batch.back().and_then(|record| record.offset) {
+            Some(offset) => AbsOffset(offset + 1),
+            None => self.segments[self.active_segment].next_offset(),
+        }
+    }
+
+    /// Writes every record in the log, oldest first, see `Segment::dump` for the format
+    pub fn dump<W: Write>(&self, mut out: W) -> Result<(), LogError> {
+        for i in 0..self.segments.len() {
+            self.segment(i)?.dump(&mut out)?;
+        }
+        Ok(())
+    }
+
+    /// Appends every record in `other` to this log, in offset order. Records keep their keys,
+    /// headers and timestamps but get new offsets. Returns the offset in `other` to pass to
+    /// `merge_from` to pick up records appended to it since
+    pub fn merge(&mut self, other: &Log) -> Result<AbsOffset, LogError> {
+        self.merge_from(other, AbsOffset(0))
+    }
+
+    /// Like `merge`, starting from offset `from` in `other`. If merging fails part way,
+    /// calling it again from the offset after the last merged record resumes without duplicates
+    pub fn merge_from(&mut self, other: &Log, from: AbsOffset) -> Result<AbsOffset, LogError> {
+        let mut next = from;
+        for record in other.iter_from(from) {
+            let mut record = record?;
+            next = record.offset.map_or(next, |offset| AbsOffset(offset + 1));
+            record.offset = None;
+            self.append(record)?;
+        }
+        Ok(next.max(other.next_offset()))
+    }
+
+    /// Appends a record whose value is `value`
+    pub fn append_str(&mut self, value: &str) -> Result<AbsOffset, LogError> {
+        self.append(Record::builder().value(value).build())
+    }
+
+    /// Reads the value of the record at offset as a string
+    pub fn read_str(&self, offset: AbsOffset) -> Result<String, LogError> {
+        let record = self.read(offset)?;
+        String::from_utf8(record.value).map_err(|_| LogError::InvalidUtf8(offset.0))
+    }
+
+    /// Seals the active segment: writes out any batched records, trims its index file to its
+    /// entries and rolls to a new segment, even if the active one isn't full. A sealed segment's
+    /// files no longer change, e.g. so they can be backed up.
+    /// Returns the base offset of the new active segment
+    pub fn seal(&mut self) -> Result<AbsOffset, LogError> {
+        self.check_writable()?;
+        self.flush()?;
+        let active_segment = &mut self.segments[self.active_segment];
+        let offset = active_segment.next_offset();
+        if offset.0 == active_segment.base_offset {
+            // nothing in it to seal
+            return Ok(offset);
+        }
+        active_segment
+            .close()
+            .segment_context("Cannot seal segment", active_segment.dir())?;
+        self.new_segment(offset.0)?;
+        Ok(offset)
+    }
+
+    /// Returns a subscription that receives every record appended from now on
+    pub fn subscribe(&self) -> Subscription {
+        Subscription::new(self.subscribers.subscribe())
+    }
+
+    // writes record to the active segment, timing it when latencies are tracked
+    fn append_record(&mut self, record: &Record) -> Result<u64, LogError> {
+        let started = self.start_timer();
+        let offset = self.write_record(record)?;
+        self.record_latency(started);
+        Ok(offset)
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<u64, LogError> {
+        if record.value.len() > (self.config.segment.max_record_size_kb as usize) {
+            return Err(LogError::RecordTooLarge);
+        }
+        self.roll_if_index_full()?;
+        let config = self.config.clone();
+        let active_segment = self.active()?;
+        // asked for once, a roll below doesn't change what follows the last offset
+        let record_offset = active_segment.next_offset_for(record);
+        if config.strict_offsets && record_offset.0 != active_segment.next_offset {
+            return Err(LogError::OffsetGap {
+                expected: active_segment.next_offset,
+                got: record_offset.0,
+            });
+        }
+
+        match active_segment.append_at(record, record_offset) {
+            Ok(AbsOffset(offset)) => {
+                if active_segment.is_maxed() && config.auto_roll {
+                    // the record is already appended, so don't report it as failed if rolling
+                    // fails. The next append finds the segment full and tries to roll again
+                    let _ = self.new_segment(offset + 1);
+                } else {
+                    self.precreate_if_nearly_full();
                 }
                 Ok(offset)
             }
             Err(e ) => {
                 match e {
-                    SegmentError::StoreFull(record) => {
-                        let offset = self.segments[self.active_segment].next_offset;
-                        let _  = self.new_segment(offset)?;
-                        let r = self.segments[self.active_segment].append(record)?;
-                        Ok(r)
+                    SegmentError::StoreErrors(StoreError::StoreFullError) => {
+                        let active_segment = &self.segments[self.active_segment];
+                        let offset = active_segment.next_offset().0;
+                        // the record doesn't fit in an empty segment, so rolling won't help
+                        if offset == active_segment.base_offset {
+                            return Err(LogError::RecordTooLarge);
+                        }
+                        self.roll_full(offset)?;
+                        match self.active()?.append_at(record, record_offset) {
+                            Ok(r) => Ok(r.0),
+                            Err(SegmentError::StoreErrors(StoreError::StoreFullError)) => {
+                                Err(LogError::RecordTooLarge)
+                            }
+                            Err(e) => Err(LogError::SegmentErrors(e)),
+                        }
                     },
+                    SegmentError::OffsetExhausted(offset) => Err(LogError::OffsetExhausted(offset)),
                     x =>   Err(LogError::SegmentErrors(x))
                 }
             }
         }
-    }
+    }
+
+    /// Appends an already encoded record, as received from a leader, without re-encoding it.
+    /// `offset` must be the log's next offset. The record is only decoded when a validator is set
+    /// or there are subscribers to publish it to
+    pub fn append_encoded(
+        &mut self,
+        offset: AbsOffset,
+        encoded: &[u8],
+    ) -> Result<AbsOffset, LogError> {
+        self.check_writable()?;
+        self.flush_batch()?;
+        if encoded.len() > (self.config.segment.max_record_size_kb as usize) {
+            return Err(LogError::RecordTooLarge);
+        }
+
+        let published = if self.validator.is_some() || self.subscribers.receiver_count() > 0 {
+            let record: Record =
+                prost::Message::decode(encoded).map_err(SegmentError::DecodeError)?;
+            if let Some(validator) = &self.validator {
+                validator(&record).map_err(LogError::ValidationFailed)?;
+            }
+            Some(record)
+        } else {
+            None
+        };
+
+        let started = self.start_timer();
+        self.roll_if_index_full()?;
+        let active_segment = self.active()?;
+        if !active_segment.store.can_store_record(encoded.len()) {
+            // the record doesn't fit in an empty segment, so rolling won't help
+            if active_segment.next_offset == active_segment.base_offset {
+                return Err(LogError::RecordTooLarge);
+            }
+            let next_offset = active_segment.next_offset;
+            self.roll_full(next_offset)?;
+        }
+
+        let active_segment = self.active()?;
+        let offset = active_segment.append_encoded(offset, encoded)?;
+        if active_segment.is_maxed() && self.config.auto_roll {
+            self.new_segment(offset.0 + 1)?;
+        } else {
+            self.precreate_if_nearly_full();
+        }
+        self.record_latency(started);
+
+        if let Some(record) = published {
+            let _ = self.subscribers.send(record);
+        }
+        Ok(offset)
+    }
+
+    // starts timing an append, when latencies are tracked
+    fn start_timer(&self) -> Option<Instant> {
+        (self.config.track_latency > 0).then(Instant::now)
+    }
+
+    // keeps how long the append timed from `started` took, dropping the oldest kept
+    fn record_latency(&mut self, started: Option<Instant>) {
+        let Some(started) = started else {
+            return;
+        };
+        if self.latencies.len() >= self.config.track_latency {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(started.elapsed());
+    }
+
+    /// How long the latest appends took to write to their segment, as many as
+    /// `ConfigBuilder::with_track_latency` keeps. Batched records are timed as they're written
+    /// out, and a record set is a single append. Empty stats when latencies aren't tracked
+    pub fn append_latencies(&self) -> LatencyStats {
+        LatencyStats::from_latencies(self.latencies.iter().copied())
+    }
+
+    // the active segment's index can fill up before its store does (when max_index_bytes isn't
+    // a multiple of the entry length), so roll over to a new segment before appending to it
+    fn roll_if_index_full(&mut self) -> Result<(), LogError> {
+        let active_segment = self.active()?;
+        if active_segment.index.is_full() && active_segment.next_offset != active_segment.base_offset
+        {
+            let next_offset = active_segment.next_offset;
+            self.roll_full(next_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the record at offset as it's stored, without decoding it
+    pub fn read_encoded(&self, AbsOffset(offset): AbsOffset) -> Result<Vec<u8>, LogError> {
+        self.check_range(offset)?;
+        let active_segment = self.segment_index(offset).unwrap_or(0);
+        let record = self
+            .segment(active_segment)?
+            .read_encoded(AbsOffset(offset))
+            .map_err(|e| self.read_failed(active_segment, offset, e))?;
+        Ok(record)
+    }
+
+    /// Reads the record at offset along with the position of the record in its segment's store file.
+    /// The position is where the record's length prefix starts
+    pub fn read_with_position(
+        &self,
+        AbsOffset(offset): AbsOffset,
+    ) -> Result<(Record, StorePos), LogError> {
+        self.check_range(offset)?;
+        let active_segment = self.segment_index(offset).unwrap_or(0);
+        let (record, position) = self
+            .segment(active_segment)?
+            .read_encoded_with_position(AbsOffset(offset))
+            .map_err(|e| self.read_failed(active_segment, offset, e))?;
+        let record: Record = prost::Message::decode(&record[..]).map_err(SegmentError::DecodeError)?;
+        Ok((record, position))
+    }
+
+    /// Finds where the record at offset lives on disk: the index of its segment, as in
+    /// `segment_paths`, and its position in that segment's store file. The position points at
+    /// the record's length prefix, for readers that map the store file themselves.
+    /// Records still buffered by batching have no position yet
+    pub fn position_of(&self, AbsOffset(offset): AbsOffset) -> Result<(usize, StorePos), LogError> {
+        self.check_range(offset)?;
+        let segment = self.segment_index(offset).unwrap_or(0);
+        let position = self
+            .segment(segment)?
+            .position_of(AbsOffset(offset))
+            .map_err(|e| self.read_failed(segment, offset, e))?;
+        Ok((segment, position))
+    }
+
+    pub fn read(&self, AbsOffset(offset): AbsOffset) -> Result<Record, LogError> {
+        let mut record = self.read_cow(offset)?.into_owned();
+        // records appended already encoded may not carry their offset
+        record.offset.get_or_insert(offset);
+        Ok(record)
+    }
+
+    /// Reads the value of the record at offset into buf, replacing what's in it, and returns
+    /// the record's offset. Lets callers reuse one buffer across reads, and batched records
+    /// are copied from without cloning the whole record
+    pub fn read_into(
+        &self,
+        AbsOffset(offset): AbsOffset,
+        buf: &mut Vec<u8>,
+    ) -> Result<AbsOffset, LogError> {
+        let record = self.read_cow(offset)?;
+        buf.clear();
+        buf.extend_from_slice(&record.value);
+        Ok(AbsOffset(record.offset.unwrap_or(offset)))
+    }
+
+    // the record at offset, borrowed when it's still batched
+    fn read_cow(&self, offset: u64) -> Result<Cow<'_, Record>, LogError> {
+        self.check_range(offset)?;
+        let record = match self.batched(offset) {
+            Some(record) => Cow::Borrowed(record),
+            None => {
+                let active_segment = self.segment_index(offset).unwrap_or(0);
+                let record = self
+                    .segment(active_segment)?
+                    .read(AbsOffset(offset))
+                    .map_err(|e| self.read_failed(active_segment, offset, e))?;
+                Cow::Owned(record)
+            }
+        };
+        if self.is_expired(&record) {
+            return Err(LogError::RecordExpired(offset));
+        }
+        Ok(record)
+    }
+
+    /// Checks there's a record at offset, failing with `OffsetGone` for an offset before the
+    /// oldest segment (removed by truncating, draining or eviction), `OffsetPastEnd` for one
+    /// that hasn't been appended yet and `RecordCompacted` for one in between without a record,
+    /// e.g. compacted away or skipped by the offset allocator. Reads of a single record fail
+    /// the same way, without looking the offset up twice. Expired records are still found
+    pub fn validate_offset(&self, AbsOffset(offset): AbsOffset) -> Result<(), LogError> {
+        self.check_range(offset)?;
+        if self.batched(offset).is_some() {
+            return Ok(());
+        }
+        let Some(i) = self.segment_index(offset) else {
+            // in a gap after a segment's last offset, or among the batched records
+            return Err(LogError::RecordCompacted(offset));
+        };
+        self.segment(i)?
+            .position_of(AbsOffset(offset))
+            .map(|_| ())
+            .map_err(|e| self.read_failed(i, offset, e))
+    }
+
+    // what reading offset from the ith segment failed with. A missing index entry for an
+    // offset within the log means its record was compacted away
+    fn read_failed(&self, i: usize, offset: u64, e: SegmentError) -> LogError {
+        match e {
+            // removed with its segment since the read found the segment
+            SegmentError::OffsetNotInSegment(missing) if missing < self.segments[i].base_offset => {
+                LogError::OffsetGone(offset)
+            }
+            SegmentError::OffsetNotInSegment(_) => LogError::RecordCompacted(offset),
+            e => with_segment_context(e, "Cannot read segment", self.segments[i].dir()),
+        }
+    }
+
+    // the bounds checks of `validate_offset`, a read then finds whether there's a record
+    fn check_range(&self, offset: u64) -> Result<(), LogError> {
+        if offset >= self.next_offset().0 {
+            return Err(LogError::OffsetPastEnd(offset));
+        }
+        if offset < self.segments[0].base_offset {
+            return Err(LogError::OffsetGone(offset));
+        }
+        Ok(())
+    }
+
+    /// Reads the record at offset, with an offset that doesn't need unwrapping
+    pub fn read_appended(&self, offset: AbsOffset) -> Result<AppendedRecord, LogError> {
+        Ok(AppendedRecord::new(offset, self.read(offset)?))
+    }
+
+    // the batched record at offset, if it hasn't been written yet
+    fn batched(&self, offset: u64) -> Option<&Record> {
+        self.batch.iter().find(|record| record.offset == Some(offset))
+    }
+
+    // expired records are kept until they're compacted away, they just can't be read
+    fn is_expired(&self, record: &Record) -> bool {
+        record
+            .expire_at_millis
+            .is_some_and(|expire_at| expire_at <= self.clock.now_millis())
+    }
+
+    // index of the segment holding offset
+    fn segment_index(&self, offset: u64) -> Option<usize> {
+        // segments are sorted by base offset, so the only one that can hold offset is the last
+        // one whose base offset is less than or equal to it. No segment gets opened
+        let i = self
+            .segments
+            .iter()
+            .rposition(|segment| segment.base_offset <= offset)?;
+        (offset < self.segments[i].next_offset().0).then_some(i)
+    }
+
+    fn close(&mut self) {
+        self.discard_precreated();
+        // nothing to do with an error here, the records stay in the batch
+        let _ = self.flush();
+        for segment in &mut self.segments {
+            if let Err(e) = segment.close() {
+                eprintln!("Cannot close segment {}: {}", segment.base_offset, e);
+            }
+        }
+    }
+
+    fn remove(&mut self) -> Result<(), LogError> {
+        self.close();
+
+        if self.in_memory {
+            self.segments.clear();
+            return Ok(());
+        }
+
+        std::fs::remove_dir(&self.dir).context("Cannot remove log directory", &self.dir)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), LogError> {
+        self.remove()?;
+        self.setup()
+    }
+
+    /// Returns the offset of the oldest record in the log, or None if the log is empty.
+    /// Segments that can't be opened are skipped
+    pub fn lowest_offset(&self) -> Option<AbsOffset> {
+        (0..self.segments.len())
+            .filter(|i| self.segments[*i].len() > 0)
+            .find_map(|i| self.segment(i).ok()?.first_offset())
+    }
+
+    /// The smallest offset at or after `offset` that has a record, e.g. for a consumer resuming
+    /// from an offset that may have been removed since. Skips segments removed by retention and
+    /// offsets without a record, like `validate_offset` expired records are still found.
+    /// Returns None when there's no record from offset on
+    pub fn first_offset_at_or_after(&self, offset: AbsOffset) -> Option<AbsOffset> {
+        // segments are sorted, so binary search for the first one that ends after offset
+        let start = self
+            .segments
+            .partition_point(|segment| segment.next_offset() <= offset);
+        (start..self.segments.len())
+            .find_map(|i| self.segment(i).ok()?.seek(offset))
+            .or_else(|| {
+                self.batch
+                    .iter()
+                    .filter_map(|record| record.offset.map(AbsOffset))
+                    .find(|batched| *batched >= offset)
+            })
+    }
+
+    /// Returns the offset of the newest record in the log, or None if the log is empty.
+    /// Segments that can't be opened are skipped
+    pub fn highest_offset(&self) -> Option<AbsOffset> {
+        if let Some(offset) = self.batch.back().and_then(|record| record.offset) {
+            return Some(AbsOffset(offset));
+        }
+        (0..self.segments.len())
+            .rev()
+            .filter(|i| self.segments[*i].len() > 0)
+            .find_map(|i| self.segment(i).ok()?.last_offset())
+    }
+
+    fn truncate(&mut self, lowest: u64) {
+        let mut segments: Vec<LazySegment> = vec![];
+
+        let mut segment_index_to_remove: Vec<usize> = vec![];
+
+        for (i, segment) in self.segments.iter_mut().enumerate() {
+            if segment.next_offset().0 <= lowest + 1 {
+                segment.remove();
+                segment_index_to_remove.push(i)
+            }
+        }
+
+        // remove from the back so earlier indices stay valid
+        for index in segment_index_to_remove.into_iter().rev() {
+            self.segments.remove(index);
+        }
+        self.active_segment = self.segments.len().saturating_sub(1);
+        // a manifest listing removed segments is caught when the log is next opened
+        let _ = self.write_manifest();
+    }
+
+    /// The records from `start` up to, but not including, `end`, in offset order. Offsets
+    /// without a record are skipped instead of failing the read, so a range spans records
+    /// compacted away, even every record of a segment, and starts from the oldest segment if
+    /// `start` is before it. Expired records are skipped too, as with `iter_from`
+    pub fn read_range(
+        &self,
+        start: AbsOffset,
+        AbsOffset(end): AbsOffset,
+    ) -> Result<Vec<Record>, LogError> {
+        self.iter_from(start)
+            .take_while(|record| {
+                record
+                    .as_ref()
+                    .map_or(true, |record| record.offset.unwrap_or_default() < end)
+            })
+            .collect()
+    }
+
+    /// A checkpoint after every record appended so far, batched ones included, to get the
+    /// records appended since from `Log::since`. It identifies the last record by its content
+    /// and timestamp as well as its offset, so a log that's been reset or rewound and appended
+    /// to again isn't taken for the one it was taken of. It can be serialized to be kept
+    /// outside the log
+    pub fn checkpoint(&self) -> Result<Checkpoint, LogError> {
+        let last = match self.highest_offset() {
+            Some(AbsOffset(offset)) => Some((offset, self.record_digest(offset)?)),
+            None => None,
+        };
+        Ok(Checkpoint {
+            next_offset: self.next_offset().0,
+            last,
+        })
+    }
+
+    /// The records appended after `checkpoint` was taken, along with their offsets, skipped
+    /// like `iter_from` does. Fails with `CheckpointMismatch` if the log was reset or rewound
+    /// past the checkpoint since, and with `OffsetGone` once the record the checkpoint was
+    /// taken after has been removed, e.g. by eviction, as there's no telling what came after it
+    pub fn since(
+        &self,
+        checkpoint: &Checkpoint,
+    ) -> Result<impl Iterator<Item = Result<(AbsOffset, Record), LogError>> + '_, LogError> {
+        if checkpoint.next_offset > self.next_offset().0 {
+            return Err(LogError::CheckpointMismatch(checkpoint.next_offset));
+        }
+        if let Some((offset, digest)) = checkpoint.last {
+            if self.record_digest(offset)? != digest {
+                return Err(LogError::CheckpointMismatch(checkpoint.next_offset));
+            }
+        }
+        Ok(self
+            .iter_from(AbsOffset(checkpoint.next_offset))
+            .map(|record| {
+                record.map(|record| (AbsOffset(record.offset.unwrap_or_default()), record))
+            }))
+    }
+
+    // tells the record at offset apart from another appended at the same offset, after a reset
+    // or a rewind, by its content and the time it was appended. Stays the same across builds
+    fn record_digest(&self, offset: u64) -> Result<u64, LogError> {
+        self.check_range(offset)?;
+        let record = match self.batched(offset) {
+            Some(record) => Cow::Borrowed(record),
+            None => {
+                let i = self.segment_index(offset).unwrap_or(0);
+                let record = self
+                    .segment(i)?
+                    .read(AbsOffset(offset))
+                    .map_err(|e| self.read_failed(i, offset, e))?;
+                Cow::Owned(record)
+            }
+        };
+        let timestamp = record.timestamp.unwrap_or_default();
+        Ok(record.content_hash().rotate_left(1) ^ timestamp)
+    }
+
+    /// A reader over the records from `start` up to, but not including, `end`, e.g. to
+    /// `io::copy` them to a file or socket. Records are skipped like `read_range` does, and
+    /// `bytes` says which of each record's bytes are read
+    pub fn reader(
+        &self,
+        start: AbsOffset,
+        AbsOffset(end): AbsOffset,
+        bytes: ReadBytes,
+    ) -> LogReader<'_> {
+        LogReader {
+            records: self.iter_from(start),
+            end,
+            bytes,
+            pending: vec![],
+            position: 0,
+            done: false,
+        }
+    }
+
+    /// The last `n` records in the log along with their offsets, in offset order. Only the
+    /// newest segments holding them are read. Returns every record if there are fewer than `n`
+    pub fn read_tail(&self, n: usize) -> Result<Vec<(AbsOffset, Record)>, LogError> {
+        // collected newest first, then put back in order
+        let mut tail: Vec<(AbsOffset, Record)> = self
+            .batch
+            .iter()
+            .rev()
+            .take(n)
+            .map(|record| (AbsOffset(record.offset.unwrap_or_default()), record.clone()))
+            .collect();
+        for i in (0..self.segments.len()).rev() {
+            if tail.len() >= n {
+                break;
+            }
+            let records = self.segment(i)?.tail((n - tail.len()) as u64)?;
+            tail.extend(records.into_iter().rev());
+        }
+        tail.reverse();
+        Ok(tail)
+    }
+
+    /// Removes the record at offset and every one after it, so the next append gets `offset`,
+    /// e.g. to replay records from there. Segments after the one holding offset are removed
+    /// and that one is truncated to become the active segment. Offsets before the oldest
+    /// segment have already been removed and can't be rewound to
+    pub fn rewind_to(&mut self, AbsOffset(offset): AbsOffset) -> Result<(), LogError> {
+        self.check_writable()?;
+        self.flush()?;
+        if offset < self.segments[0].base_offset || offset > self.next_offset().0 {
+            return Err(LogError::InvalidRewind(offset));
+        }
+        let i = self
+            .segments
+            .iter()
+            .rposition(|segment| segment.base_offset <= offset)
+            .unwrap_or(0);
+        while self.segments.len() > i + 1 {
+            let segment = self.segments.pop().expect("there are segments after i");
+            self.delete_segment(segment);
+        }
+        self.active_segment = i;
+        self.write_manifest()?;
+        let segment = &mut self.segments[i];
+        let dir = segment.dir().to_path_buf();
+        segment
+            .get_mut()
+            .and_then(|segment| segment.truncate_from(AbsOffset(offset)))
+            .segment_context("Cannot truncate segment", &dir)?;
+        // records appended from offset again haven't been drained
+        self.drained = self.drained.min(offset);
+        Ok(())
+    }
+
+    /// Removes and returns the records from the oldest one through `up_to`, for using the log
+    /// as a work queue. Only whole segments are removed, so the records of a partly drained
+    /// segment stay readable, but aren't returned by later drains. A reopened log doesn't know
+    /// where the last drain stopped, so its first drain returns them again
+    pub fn drain(
+        &mut self,
+        AbsOffset(up_to): AbsOffset,
+    ) -> Result<Vec<(AbsOffset, Record)>, LogError> {
+        self.check_writable()?;
+        self.flush()?;
+        let mut drained = vec![];
+        for record in self.iter_from(AbsOffset(self.drained)) {
+            let record = record?;
+            let offset = record.offset.unwrap_or_default();
+            if offset > up_to {
+                break;
+            }
+            drained.push((AbsOffset(offset), record));
+        }
+
+        // roll a drained active segment so it can be removed too
+        let active_segment = &self.segments[self.active_segment];
+        let next_offset = active_segment.next_offset().0;
+        if active_segment.len() > 0 && next_offset <= up_to.saturating_add(1) {
+            self.new_segment(next_offset)?;
+        }
+        let mut removed = false;
+        while self.active_segment > 0 && self.segments[0].next_offset().0 <= up_to.saturating_add(1)
+        {
+            let segment = self.segments.remove(0);
+            self.active_segment -= 1;
+            self.delete_segment(segment);
+            removed = true;
+        }
+        if removed {
+            self.write_manifest()?;
+        }
+        // up_to can be past the last record, which doesn't drain records appended later
+        self.drained = self
+            .drained
+            .max(up_to.saturating_add(1).min(self.next_offset().0));
+        Ok(drained)
+    }
+
+    /// Compacts the sealed segment starting at `base_offset`, removing every record whose key
+    /// has been written again at a later offset. Records without a key are kept. Segments can
+    /// be compacted one at a time, returns the number of records removed
+    pub fn compact_segment(&mut self, base_offset: AbsOffset) -> Result<u64, LogError> {
+        let compaction = self.prepare_compaction(base_offset)?;
+        self.commit_compaction(compaction)
+    }
+
+    /// The slow half of `compact_segment`: writes a compacted copy of the segment next to it.
+    /// It only needs shared access, so with the log behind a `RwLock` readers carry on while
+    /// it runs under a read lock. The copy is swapped in with `commit_compaction`
+    pub fn prepare_compaction(
+        &self,
+        AbsOffset(base_offset): AbsOffset,
+    ) -> Result<CompactedSegment, LogError> {
+        let i = self.compactable_segment(base_offset)?;
+        let latest = self.key_index()?;
+        let mut ticker = self.ticker(self.segments[i].len());
+        let compacted = self.segment(i)?.write_compacted(
+            |offset, record| survives_compaction(&latest, offset.0, record),
+            &mut || ticker.tick(),
+        );
+        ticker.check(compacted)
+    }
+
+    /// What compacting every sealed segment would remove right now, without rewriting anything,
+    /// to tell whether it's worth it. Records in a record set are counted as if they were
+    /// stored on their own, as compaction rewrites them
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate, LogError> {
+        let latest = self.key_index()?;
+        let mut estimate = CompactionEstimate::default();
+        for i in 0..self.segments.len() {
+            if i == self.active_segment {
+                continue;
+            }
+            let segment = self.segment(i)?;
+            for (offset, record) in segment.records()? {
+                if !survives_compaction(&latest, offset.0, &record) {
+                    estimate.records += 1;
+                    estimate.bytes += segment.store.framed_len(record.encoded_len());
+                }
+            }
+        }
+        Ok(estimate)
+    }
+
+    /// Swaps a compacted copy of a segment in, which is quick enough to do under a write lock.
+    /// Fails if the segment has been compacted since the copy was written
+    pub fn commit_compaction(&mut self, compaction: CompactedSegment) -> Result<u64, LogError> {
+        self.check_writable()?;
+        let i = self.compactable_segment(compaction.base_offset)?;
+        let segment = self.segment_mut(i)?;
+        if segment.generation != compaction.generation {
+            let base_offset = compaction.base_offset;
+            compaction.discard();
+            return Err(LogError::StaleCompaction(base_offset));
+        }
+        Ok(segment.swap_compacted(compaction)?)
+    }
+
+    // index of the segment starting at base_offset
+    fn segment_at(&self, base_offset: u64) -> Result<usize, LogError> {
+        self.segments
+            .iter()
+            .position(|segment| segment.base_offset == base_offset)
+            .ok_or(LogError::SegmentNotFound(base_offset))
+    }
+
+    // index of the sealed segment starting at base_offset
+    fn compactable_segment(&self, base_offset: u64) -> Result<usize, LogError> {
+        let i = self.segment_at(base_offset)?;
+        if i == self.active_segment {
+            return Err(LogError::ActiveSegment(base_offset));
+        }
+        Ok(i)
+    }
+
+    /// Every key in the log along with the offset it was last written at, in offset order.
+    /// Records without a key are skipped. Reads the whole log up front
+    pub fn iter_keys(&self) -> Result<impl Iterator<Item = (Vec<u8>, AbsOffset)>, LogError> {
+        let mut keys: Vec<(Vec<u8>, AbsOffset)> = self
+            .key_index()?
+            .into_iter()
+            .map(|(key, offset)| (key, AbsOffset(offset)))
+            .collect();
+        keys.sort_by_key(|(_, offset)| *offset);
+        Ok(keys.into_iter())
+    }
+
+    // the latest offset each key was written at
+    fn key_index(&self) -> Result<HashMap<Vec<u8>, u64>, LogError> {
+        let mut latest = HashMap::new();
+        for i in 0..self.segments.len() {
+            for (offset, record) in self.segment(i)?.records()? {
+                if let Some(key) = record.key {
+                    latest.insert(key, offset.0);
+                }
+            }
+        }
+        for record in &self.batch {
+            if let (Some(key), Some(offset)) = (&record.key, record.offset) {
+                latest.insert(key.clone(), offset);
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Returns the base offset, store path and index path of every segment
+    pub fn segment_paths(&self) -> Vec<(AbsOffset, PathBuf, PathBuf)> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let (store, index) = segment.paths();
+                (AbsOffset(segment.base_offset), store, index)
+            })
+            .collect()
+    }
+
+    /// An independent, read only copy of the log as it is now, for long running reads that
+    /// shouldn't hold up appends. Segments are copied into a temporary directory (or into
+    /// memory for an in memory log) that's removed when the snapshot is dropped. Borrowing the
+    /// log keeps appends out while it's copied, batched records are carried over as they are.
+    /// Appending to, draining, rewinding or compacting the snapshot fails with
+    /// `LogError::ReadOnly`
+    pub fn snapshot(&self) -> Result<Log, LogError> {
+        let dir = if self.in_memory {
+            PathBuf::new()
+        } else {
+            let id = RandomState::new().build_hasher().finish();
+            let dir = std::env::temp_dir().join(format!("rustlog-snapshot-{:x}", id));
+            std::fs::create_dir(&dir).context("Cannot create snapshot directory", &dir)?;
+            dir
+        };
+        let (subscribers, _) = broadcast::channel(self.config.subscription_buffer.max(1));
+        // dropped on an error, which removes what's been copied so far
+        let mut snapshot = Log {
+            dir,
+            config: self.config.clone(),
+            active_segment: self.active_segment,
+            segments: vec![],
+            in_memory: self.in_memory,
+            subscribers,
+            validator: None,
+            progress_fn: None,
+            clock: self.clock.clone(),
+            batch: self.batch.clone(),
+            batch_started_at: self.batch_started_at,
+            producers: self.producers.clone(),
+            drained: self.drained,
+            snapshot: true,
+            latencies: VecDeque::new(),
+            recent_contents: VecDeque::new(),
+            precreated: None,
+            open_segments: Mutex::new(VecDeque::new()),
+        };
+        for i in 0..self.segments.len() {
+            let mut segment_dir = None;
+            if !self.in_memory {
+                let dir = snapshot.dir.join(self.segments[i].base_offset.to_string());
+                std::fs::create_dir(&dir).context("Cannot create segment directory", &dir)?;
+                segment_dir = Some(dir);
+            }
+            // opens a segment that hasn't been yet, see `ConfigBuilder::with_lazy_open`
+            let copy = self.segment(i)?.copy_to(segment_dir.as_deref())?;
+            snapshot.segments.push(LazySegment::loaded(copy));
+        }
+        Ok(snapshot)
+    }
+
+    // snapshots can be read but not changed
+    fn check_writable(&self) -> Result<(), LogError> {
+        if self.snapshot {
+            return Err(LogError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the index of every segment from its store, returning the base offset of each
+    /// segment along with the number of entries in its rebuilt index. If the progress fn
+    /// cancels it, the segments before the one being rebuilt keep their new index and the
+    /// rest are left as they were
+    pub fn reindex_all(&mut self) -> Result<Vec<(AbsOffset, u64)>, LogError> {
+        let total = self.segments.iter().map(|segment| segment.len()).sum();
+        // not `self.ticker`, so the segments can be borrowed mutably alongside it
+        let mut ticker = Ticker {
+            progress_fn: self.progress_fn.as_deref(),
+            every: self.config.get_progress_interval(),
+            done: 0,
+            total,
+        };
+        let mut entries = vec![];
+        for i in 0..self.segments.len() {
+            self.touch(i)?;
+            let segment = &mut self.segments[i];
+            let rebuilt = segment
+                .get_mut()
+                .and_then(|segment| segment.rebuild_index_with(&mut || ticker.tick()));
+            entries.push((AbsOffset(segment.base_offset), ticker.check(rebuilt)?));
+        }
+        // the rebuilt indexes can count a different number of records
+        self.write_manifest()?;
+        Ok(entries)
+    }
+
+    /// Rebuilds the index of the segment starting at `base_offset`, see `reindex_all`
+    pub fn reindex_segment(&mut self, AbsOffset(base_offset): AbsOffset) -> Result<u64, LogError> {
+        let i = self.segment_at(base_offset)?;
+        let entries = self.segment_mut(i)?.rebuild_index()?;
+        self.write_manifest()?;
+        Ok(entries)
+    }
+
+    /// Reads and decodes every record in the log, failing at the first one that can't be,
+    /// e.g. because its checksum doesn't match. Returns the number of records read
+    pub fn verify(&self) -> Result<u64, LogError> {
+        let mut ticker = self.ticker(self.segments.iter().map(|segment| segment.len()).sum());
+        let mut records = 0;
+        for i in 0..self.segments.len() {
+            let verified = self.segment(i)?.verify(&mut || ticker.tick());
+            records += ticker.check(verified)?;
+        }
+        Ok(records)
+    }
+
+    /// Checks every record in the log against its checksum without decoding it, failing at the
+    /// first one that doesn't match, like `verify`. Much quicker when only damage to the
+    /// files matters, but only catches it with `ConfigBuilder::with_checksums`. Returns the
+    /// number of frames checked, see `Segment::verify_checksums_only`
+    pub fn verify_checksums_only(&self) -> Result<u64, LogError> {
+        let mut ticker = self.ticker(self.segments.iter().map(|segment| segment.len()).sum());
+        let mut frames = 0;
+        for i in 0..self.segments.len() {
+            let verified = self
+                .segment(i)?
+                .verify_checksums_only(&mut || ticker.tick());
+            frames += ticker.check(verified)?;
+        }
+        Ok(frames)
+    }
+
+    /// Reads and decodes every record of the segment starting at `base_offset`, see `verify`
+    pub fn verify_segment(&self, AbsOffset(base_offset): AbsOffset) -> Result<u64, LogError> {
+        let i = self.segment_at(base_offset)?;
+        Ok(self.segment(i)?.verify(&mut || true)?)
+    }
+
+    /// Returns the number of records in the log, without opening segments that aren't.
+    /// Records removed from the log (e.g. by a truncate) are not counted
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.len()).sum::<u64>() + self.batch.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> LogStats {
+        LogStats {
+            segments: self.segments.len(),
+            records: self.len(),
+            store_bytes: self
+                .segments
+                .iter()
+                .map(|segment| segment.meta().records_len)
+                .sum(),
+            lowest_offset: self.lowest_offset().map(|offset| offset.0),
+            highest_offset: self.highest_offset().map(|offset| offset.0),
+        }
+    }
+
+    /// Bytes the log's files take up on disk: every segment's store plus its index, which is
+    /// preallocated to its max size while open. Files that can't be read count as empty and
+    /// in memory logs take up none
+    pub fn size_on_disk(&self) -> u64 {
+        if self.in_memory {
+            return 0;
+        }
+        self.segment_paths()
+            .iter()
+            .flat_map(|(_, store, index)| [store, index])
+            .map(|path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+            .sum()
+    }
+
+    /// Bytes of records written to the log's stores, including each record's framing.
+    /// Records still buffered by batching aren't counted
+    pub fn logical_size(&self) -> u64 {
+        self.stats().store_bytes
+    }
+
+    /// Buckets the size of every record in the log by the power of two it's at most, for
+    /// capacity planning. Reads each record's length from its store without decoding it.
+    /// Records still buffered by batching aren't counted
+    pub fn size_histogram(&self) -> Result<SizeHistogram, LogError> {
+        let mut histogram = SizeHistogram::default();
+        for i in 0..self.segments.len() {
+            for len in self.segment(i)?.record_lens()? {
+                histogram.add(len);
+            }
+        }
+        Ok(histogram)
+    }
+
+    /// Opens the offsets consumer groups have committed against this log, kept in its
+    /// directory, or in memory for an in memory log
+    pub fn offset_store(&self) -> Result<OffsetStore, LogError> {
+        if self.in_memory {
+            return Ok(OffsetStore::in_memory());
+        }
+        OffsetStore::open(&self.dir).context("Cannot read consumer offsets", &self.dir)
+    }
+
+    /// Checks the log can be served from without scanning it: every segment's files still exist,
+    /// and the newest record and one other record picked at random can be read
+    pub fn health_check(&self) -> HealthReport {
+        let stats = self.stats();
+        let mut problems = vec![];
+
+        if !self.in_memory {
+            for (base_offset, store, index) in self.segment_paths() {
+                if !store.exists() || !index.exists() {
+                    problems.push(format!("Files of segment {} are missing", base_offset.0));
+                }
+            }
+        }
+
+        if let (Some(lowest), Some(highest)) = (stats.lowest_offset, stats.highest_offset) {
+            let random = RandomState::new().build_hasher().finish();
+            let sample = lowest + random % (highest - lowest + 1);
+            // offsets can have gaps, so read the first record at or after the sample
+            let sample = self
+                .segment_index(sample)
+                .and_then(|i| self.segment(i).ok()?.seek(AbsOffset(sample)))
+                .map_or(highest, |sample| sample.0);
+
+            for offset in [highest, sample] {
+                match self.read(AbsOffset(offset)) {
+                    // an expired record is still there, it's just hidden
+                    Ok(_) | Err(LogError::RecordExpired(_)) => {}
+                    Err(e) => problems.push(format!("Cannot read offset {}: {}", offset, e)),
+                }
+            }
+        }
+
+        let active_segment = self.segments[self.active_segment].meta();
+        let active_segment_remaining_bytes = self
+            .config
+            .get_max_store_bytes()
+            .saturating_sub(active_segment.store_size);
+
+        HealthReport {
+            status: if problems.is_empty() {
+                HealthStatus::Ok
+            } else {
+                HealthStatus::Degraded(problems)
+            },
+            stats,
+            active_segment_remaining_bytes,
+        }
+    }
+
+    /// Returns an iterator over records starting at `start`.
+    /// If `start` is below the lowest retained offset (e.g. it was removed by a truncate),
+    /// iteration begins at the lowest retained offset instead of erroring
+    pub fn iter_from(&self, AbsOffset(start): AbsOffset) -> LogIterator<'_> {
+        let lowest = self
+            .segments
+            .first()
+            .map(|segment| segment.base_offset)
+            .unwrap_or(start);
+        LogIterator {
+            log: self,
+            offset: start.max(lowest),
+            prefetch: 0,
+            buffer: VecDeque::new(),
+            failed: false,
+        }
+    }
+}
+
+pub struct LogIterator<'a> {
+    log: &'a Log,
+    offset: u64,
+    prefetch: usize,
+    buffer: VecDeque<Record>, // records read ahead of offset
+    failed: bool,             // stopped at an error, see `SkipOrFail::Fail`
+}
+
+impl<'a> LogIterator<'a> {
+    /// Reads `window` records at a time with a single store read instead of one read per record,
+    /// which suits sequential scans. A window of 0 (the default) disables read-ahead
+    pub fn with_prefetch(mut self, window: usize) -> Self {
+        self.prefetch = window;
+        self
+    }
+}
+
+impl<'a> Iterator for LogIterator<'a> {
+    type Item = Result<Record, LogError>;
+
+    // expired records are skipped
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            match self.next_record()? {
+                Err(LogError::RecordExpired(_)) => continue,
+                Ok(record) if self.log.is_expired(&record) => continue,
+                Err(e) => {
+                    return Some(match self.log.config.scan_on_error {
+                        // the record that failed is the one before offset
+                        SkipOrFail::Skip if is_corruption(&e) => {
+                            Err(LogError::Corrupt(self.offset - 1))
+                        }
+                        SkipOrFail::Skip => Err(e),
+                        SkipOrFail::Fail => {
+                            self.failed = true;
+                            Err(e)
+                        }
+                    })
+                }
+                item => return Some(item),
+            }
+        }
+    }
+}
+
+// whether a read failed because the record's bytes are damaged
+fn is_corruption(e: &LogError) -> bool {
+    matches!(
+        e,
+        LogError::SegmentErrors(
+            SegmentError::DecodeError(_)
+                | SegmentError::StoreErrors(
+                    StoreError::ChecksumMismatch(_) | StoreError::DecryptionFailed(_)
+                )
+        )
+    )
+}
+
+impl<'a> LogIterator<'a> {
+    fn next_record(&mut self) -> Option<Result<Record, LogError>> {
+        if let Some(record) = self.buffer.pop_front() {
+            self.offset = record.offset.unwrap_or(self.offset) + 1;
+            return Some(Ok(record));
+        }
+        self.skip_gap();
+
+        let next_offset = self.log.segments.last()?.next_offset().0;
+        if self.offset >= next_offset {
+            // past what's been written, so serve any batched records
+            let record = self
+                .log
+                .batch
+                .iter()
+                .find(|record| record.offset.unwrap_or_default() >= self.offset)?;
+            self.offset = record.offset.unwrap_or_default() + 1;
+            return Some(Ok(record.clone()));
+        }
+
+        if self.prefetch == 0 {
+            let record = self.log.read(AbsOffset(self.offset));
+            self.offset += 1;
+            return Some(record);
+        }
+
+        let segment = self
+            .log
+            .segment(self.log.segment_index(self.offset).unwrap_or(0));
+        let records = segment
+            .and_then(|segment| Ok(segment.read_many(AbsOffset(self.offset), self.prefetch)?));
+        match records {
+            Ok(records) => self.buffer.extend(records),
+            Err(_) => {
+                // read the batch a record at a time, so only the record that failed errors
+                let record = self.log.read(AbsOffset(self.offset));
+                self.offset += 1;
+                return Some(record);
+            }
+        }
+        let record = self.buffer.pop_front()?;
+        self.offset = record.offset.unwrap_or(self.offset) + 1;
+        Some(Ok(record))
+    }
+
+    // moves offset past any gap in the offsets (from sparse offsets or compaction),
+    // which can run to the end of a segment
+    fn skip_gap(&mut self) {
+        while let Some(i) = self.log.segment_index(self.offset) {
+            // reading the offset reports why the segment can't be opened
+            let Ok(segment) = self.log.segment(i) else {
+                return;
+            };
+            match segment.seek(AbsOffset(self.offset)) {
+                Some(offset) => {
+                    self.offset = offset.0;
+                    return;
+                }
+                None => self.offset = segment.next_offset,
+            }
+        }
+    }
+}
+
+/// Which bytes of each record a `LogReader` reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadBytes {
+    /// The whole record, encoded with a length prefix as by `Message::encode_length_delimited`,
+    /// so the records can be told apart again
+    Encoded,
+    /// Only the record's value, with nothing between one value and the next
+    Value,
+}
+
+/// Reads the bytes of a range of records one after another, see `Log::reader`
+pub struct LogReader<'a> {
+    records: LogIterator<'a>,
+    end: u64,
+    bytes: ReadBytes,
+    pending: Vec<u8>, // the current record's bytes
+    position: usize,  // how many of them have been read
+    done: bool,       // reached a record at or past end
+}
+
+impl<'a> io::Read for LogReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // a record is read across as many calls as it takes to fit in buf
+        while self.position == self.pending.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let Some(record) = self.records.next() else {
+                self.done = true;
+                return Ok(0);
+            };
+            let record = record.map_err(io::Error::other)?;
+            if record.offset.unwrap_or_default() >= self.end {
+                self.done = true;
+                return Ok(0);
+            }
+            self.pending = match self.bytes {
+                ReadBytes::Encoded => record.encode_length_delimited_to_vec(),
+                ReadBytes::Value => record.value,
+            };
+            self.position = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.position);
+        buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        self.discard_precreated();
+        if self.config.flush_on_drop {
+            self.close()
+        }
+        if self.snapshot && !self.in_memory {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::{
+        fs::{File, OpenOptions},
+        io::{Cursor, Read},
+    };
+
+    use crate::proto::record::Record;
+
+    use super::{Config, Index, Store};
+
+    #[test]
+    fn log_test_append_read() {
+        // test append and read a record
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_append_read");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 1024,
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+
+        // let config  = Arc::new(config);
+
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+
+        let record = Record::builder().value("hello world").build();
+
+        let offset = log.append(record.clone()).unwrap();
+
+        let read_record = log.read(offset).unwrap();
+        assert_eq!(record.value, read_record.value);
+
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_out_of_range() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_test_out_of_range");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 1024,
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        let res = log.read(AbsOffset(1));
+        assert!(matches!(res, Err(LogError::OffsetPastEnd(1))));
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+    #[test]
+    fn log_test_init_existing() {
+        use super::IndexError::{self, *};
+        use super::LogError::{IndexErrors, SegmentErrors};
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_init_existing");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 100,
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        let record: Record = Record {
+            value: "hello world".as_bytes().to_vec(),
+            offset: None,
+            ..Default::default()
+        };
+
+        for i in 0..3 {
+            log.append(record.clone()).unwrap();
+        }
+        assert_eq!(log.lowest_offset().unwrap(), AbsOffset(0));
+        assert_eq!(log.highest_offset().unwrap(), AbsOffset(2));
+
+        log.close(); // apparently, shadowed variables are not dropped, so explicitly close
+
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert_eq!(log.lowest_offset().unwrap(), AbsOffset(0));
+        assert_eq!(log.highest_offset().unwrap(), AbsOffset(2));
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_read_write_plenty() {
+        use super::IndexError::{self, *};
+        use super::LogError::{IndexErrors, SegmentErrors};
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_write_plenty");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 1024, // use a small store size
+                initial_offset: 0,
+                max_record_size_kb: 400
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+
+        for i in 0..30{
+            let record: Record = Record {
+                value: format!("hello world{}", i).into_bytes(),
+                offset: None,
+                ..Default::default()
+            };
+            log.append(record).unwrap();
+        }
+
+        let mut c = ConfigBuilder::new(0, 0, 0);
+        let d = c.with_max_record_size_kb(78);
+        let e = d.build();
+
+        let record = log.read(AbsOffset(29)).unwrap();
+
+        assert_eq!(record.offset, Some(29));
+        assert_eq!(String::from_utf8(record.value).unwrap().as_str(), "hello world29");
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn test_create_new_segment() {
+        use super::IndexError::{self, *};
+        use super::LogError::{IndexErrors, SegmentErrors};
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_create_new_segment");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 76, // use a small store size of 76 bytes
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        
+        // this record "hello world 1" is serialized into 23 bytes (when the offset and timestamp are added)
+        // plus the len of the record (8 bytes) totalling 31 bytes
+        let record: Record = Record::builder().value("hello world1").build();
+        log.append(record).unwrap(); // this should succeed
+
+         // there should be one segment, alongside the manifest
+         assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 2); 
+
+        
+        // 16 + 8 = 24
+        // active segment store should be 31 + 24 = 55 bytes, space for 20 bytes left (record of size 1 + 8 bytes for len of record)
+        let record_2 = Record::builder().value("hello").build(); 
+
+        log.append(record_2).unwrap(); // this should succeed
+
+         // there should still be one segment
+         assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 2); 
+
+        
+
+        // now if we add something more than 1 bytes, it should result in the creation of a new segment as the old one should not be able to carry it
+        // despite there being space
+
+       
+        // 13 + 8 = 21 (greater than the 20 bytes left in segment, should result in creation of a new segment)
+        let record_3 = Record::builder().value("he").build(); 
+
+        log.append(record_3).unwrap(); // this should succeed, but result in the creation of a new segment
+
+        // there should be 2 segments
+        assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 3); 
+
+        std::fs::remove_dir_all(log_dir);
+
+
+    }
+
+    #[test]
+    fn log_test_append_read_in_memory() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 50,
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+
+        for i in 0..10 {
+            let record = Record::builder().value(format!("hello world{}", i)).build();
+            assert_eq!(log.append(record).unwrap(), AbsOffset(i));
+        }
+
+        // small store, so the log should have rolled over a few times
+        assert!(log.segments.len() > 1);
+
+        for i in 0..10 {
+            let record = log.read(AbsOffset(i)).unwrap();
+            assert_eq!(record.offset, Some(i));
+            assert_eq!(record.value, format!("hello world{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn log_test_iter_from() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 130, // fits 4 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+
+        for i in 0..10 {
+            let record = Record::builder().value(format!("hello world{}", i)).build();
+            log.append(record).unwrap();
+        }
+        assert_eq!(log.segments[1].base_offset, 4);
+
+        // start in the middle of the second segment
+        let offsets: Vec<u64> = log
+            .iter_from(AbsOffset(6))
+            .map(|record| record.unwrap().offset.unwrap())
+            .collect();
+        assert_eq!(offsets, (6..10).collect::<Vec<u64>>());
+
+        // drop the first segment, iterating from a removed offset starts at the lowest retained one
+        log.truncate(3);
+        let offsets: Vec<u64> = log
+            .iter_from(AbsOffset(0))
+            .map(|record| record.unwrap().offset.unwrap())
+            .collect();
+        assert_eq!(offsets, (4..10).collect::<Vec<u64>>());
+
+        assert_eq!(log.iter_from(AbsOffset(10)).count(), 0);
+    }
+
+    #[test]
+    fn log_test_record_larger_than_store() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 30,
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+
+        // "hello world" plus its offset and length prefix is more than 30 bytes
+        let record = Record::builder().value("hello world").build();
+        for _ in 0..3 {
+            let res = log.append(record.clone());
+            assert!(matches!(res, Err(LogError::RecordTooLarge)));
+        }
+        assert_eq!(log.segments.len(), 1);
+
+        // a record that fits is still stored after the rejected ones
+        let record = Record::builder().value("a").build();
+        assert_eq!(log.append(record).unwrap(), AbsOffset(0));
+        assert_eq!(log.read(AbsOffset(0)).unwrap().value, "a".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn log_test_subscription_lagged() {
+        use super::*;
+        use crate::log::subscription::SubscriptionError;
+
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_subscription_buffer(2)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        let mut subscription = log.subscribe();
+
+        // the subscriber doesn't keep up with these
+        for i in 0..5 {
+            let record = Record::builder().value(format!("hello world{}", i)).build();
+            log.append(record).unwrap();
+        }
+
+        // only the last 2 records are buffered
+        assert_eq!(subscription.recv().await, Err(SubscriptionError::Lagged(3)));
+
+        // catch up on the missed records from the log, then carry on with the subscription
+        let mut received: Vec<u64> = log
+            .iter_from(AbsOffset(0))
+            .take(3)
+            .map(|record| record.unwrap().offset.unwrap())
+            .collect();
+        while let Ok(record) = subscription.try_recv() {
+            received.push(record.offset.unwrap());
+        }
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+
+        let record = Record::builder().value("live").build();
+        log.append(record).unwrap();
+        let record = subscription.recv().await.unwrap();
+        assert_eq!(record.offset, Some(5));
+        assert_eq!(record.value, "live".as_bytes());
+    }
+
+    #[test]
+    fn log_test_append_built_record() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+
+        let record = Record::builder()
+            .key("user-1")
+            .value("hello world")
+            .header("content-type", "text/plain")
+            .header("source", "test")
+            .build();
+        assert_eq!(record.offset, None);
+
+        let offset = log.append(record).unwrap();
+        let read_record = log.read(offset).unwrap();
+
+        assert_eq!(read_record.offset, Some(offset.0));
+        assert_eq!(read_record.key, Some("user-1".as_bytes().to_vec()));
+        assert_eq!(read_record.value, "hello world".as_bytes());
+        assert_eq!(read_record.headers.len(), 2);
+        assert_eq!(read_record.headers[0].key, "content-type");
+        assert_eq!(read_record.headers[0].value, "text/plain".as_bytes());
+        assert_eq!(read_record.headers[1].key, "source");
+
+        let record = Record::builder().value("hello").with_offset(42).build();
+        assert_eq!(record.offset, Some(42));
+    }
+
+    #[test]
+    fn log_test_len() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 130, // fits 4 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        assert!(log.is_empty());
+        assert_eq!(log.len(), 0);
+
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        assert!(!log.is_empty());
+        assert_eq!(log.len(), 10);
+
+        // drops the first segment holding offsets 0 to 3
+        log.truncate(3);
+        assert_eq!(log.len(), 6);
+    }
+
+    #[test]
+    fn log_test_validator() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        log.set_validator(Box::new(|record: &Record| {
+            if record.value.is_empty() {
+                Err("value is empty".to_string())
+            } else {
+                Ok(())
+            }
+        }));
+
+        let res = log.append(Record::builder().build());
+        assert!(matches!(res, Err(LogError::ValidationFailed(reason)) if reason == "value is empty"));
+        assert!(log.is_empty());
+
+        assert_eq!(
+            log.append(Record::builder().value("hello").build())
+                .unwrap(),
+            AbsOffset(0)
+        );
+        assert_eq!(log.len(), 1);
+    }
+
+    #[test]
+    fn log_test_iter_prefetch() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 260, // fits 8 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..20 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        let store_reads = |log: &Log| -> u64 {
+            log.segments
+                .iter()
+                .map(|s| s.get().unwrap().store.read_count())
+                .sum()
+        };
+
+        let before = store_reads(&log);
+        let records: Vec<Record> = log.iter_from(AbsOffset(1)).map(|r| r.unwrap()).collect();
+        let without_prefetch = store_reads(&log) - before;
+
+        let before = store_reads(&log);
+        let prefetched: Vec<Record> = log
+            .iter_from(AbsOffset(1))
+            .with_prefetch(4)
+            .map(|r| r.unwrap())
+            .collect();
+        let with_prefetch = store_reads(&log) - before;
+
+        assert_eq!(records.len(), 19);
+        assert_eq!(records, prefetched);
+        // 2 reads for the first record of each segment and 1 for each after it, whose length
+        // was read along with the record before, against one read per window of at most 4.
+        // The windows stop at the segments holding offsets 1-7, 8-15 and 16-19
+        assert_eq!(without_prefetch, 22);
+        assert_eq!(with_prefetch, 5);
+    }
+
+    #[test]
+    fn log_test_append_encoded() {
+        use super::*;
+        let mut leader = Log::in_memory(Config::default()).expect("cannot create log");
+        let mut follower = Log::in_memory(Config::default()).expect("cannot create log");
+
+        for i in 0..3 {
+            leader
+                .append(Record::builder().key("k").value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        for offset in 0..3 {
+            let encoded = leader.read_encoded(AbsOffset(offset)).unwrap();
+            assert_eq!(
+                follower
+                    .append_encoded(AbsOffset(offset), &encoded)
+                    .unwrap(),
+                AbsOffset(offset)
+            );
+        }
+
+        for offset in 0..3 {
+            assert_eq!(
+                follower.read(AbsOffset(offset)).unwrap(),
+                leader.read(AbsOffset(offset)).unwrap()
+            );
+        }
+
+        // offsets must follow on from the follower's next offset
+        let encoded = leader.read_encoded(AbsOffset(2)).unwrap();
+        let res = follower.append_encoded(AbsOffset(5), &encoded);
+        assert!(matches!(
+            res,
+            Err(LogError::SegmentErrors(SegmentError::OffsetMismatch { expected: 3, got: 5 }))
+        ));
+    }
+
+    #[test]
+    fn log_test_watermarks() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 130, // fits 4 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        assert_eq!(log.lowest_offset(), None);
+        assert_eq!(log.highest_offset(), None);
+
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(0)));
+        assert_eq!(log.highest_offset(), Some(AbsOffset(9)));
+
+        // keep only the active segment, holding offsets 8 and 9
+        log.truncate(7);
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(8)));
+        assert_eq!(log.highest_offset(), Some(AbsOffset(9)));
+    }
+
+    #[test]
+    fn log_test_mock_clock_timestamps() {
+        use super::*;
+        use crate::log::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        log.set_clock(clock.clone());
+
+        let first = log.append(Record::builder().value("first").build()).unwrap();
+        clock.advance(250);
+        let second = log.append(Record::builder().value("second").build()).unwrap();
+
+        assert_eq!(log.read(first).unwrap().timestamp, Some(1_000));
+        assert_eq!(log.read(second).unwrap().timestamp, Some(1_250));
+    }
+
+    #[test]
+    fn log_test_read_with_position() {
+        use super::*;
+        use crate::log::store::VERSION_LEN;
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 130, // fits 4 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..6 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        // offset 5 is the second record of the second segment
+        let (record, position) = log.read_with_position(AbsOffset(5)).unwrap();
+        assert_eq!(record, log.read(AbsOffset(5)).unwrap());
+        assert_eq!(
+            position,
+            StorePos(VERSION_LEN + log.segments[1].meta().records_len / 2)
+        );
+        assert_eq!(
+            log.segments[1].get().unwrap().store.read(position).unwrap(),
+            log.read_encoded(AbsOffset(5)).unwrap()
+        );
+    }
+
+    #[test]
+    fn log_test_index_full_rolls() {
+        use super::*;
+        let config = Config {
+            segment: SegmentConfig {
+                // room for 2 index entries, the index fills up before the segment is maxed
+                max_index_bytes: (INDEX_ENTRY_LENGTH as u64 * 2) + 6,
+                max_store_bytes: 1024,
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..5 {
+            let offset = log
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(i));
+        }
+        assert_eq!(log.segments.len(), 3);
+        for i in 0..5 {
+            assert_eq!(
+                log.read(AbsOffset(i)).unwrap().value,
+                format!("hello world{}", i).into_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn log_test_large_initial_offset() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_large_initial_offset");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 140, // fits 4 records per segment
+                initial_offset: 1_000_000,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        assert_eq!(log.segments[0].base_offset, 1_000_000);
+        assert_eq!(log.segments[0].next_offset(), AbsOffset(1_000_000));
+
+        for i in 0..3 {
+            let offset = log
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(1_000_000 + i));
+        }
+        log.close();
+
+        // reopening picks up where the log left off
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert_eq!(log.segments[0].next_offset(), AbsOffset(1_000_003));
+        for i in 3..6 {
+            let offset = log
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(1_000_000 + i));
+        }
+        assert_eq!(log.segments[1].base_offset, 1_000_004);
+        assert!(log_dir.join("1000004").is_dir());
+
+        for i in 0..6 {
+            let record = log.read(AbsOffset(1_000_000 + i)).unwrap();
+            assert_eq!(record.offset, Some(1_000_000 + i));
+            assert_eq!(record.value, format!("hello world{}", i).into_bytes());
+        }
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(1_000_000)));
+        assert_eq!(log.highest_offset(), Some(AbsOffset(1_000_005)));
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_segment_paths() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_segment_paths");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 130, // fits 4 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        for i in 0..6 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        let paths = log.segment_paths();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].0, AbsOffset(0));
+        assert_eq!(paths[1].0, AbsOffset(4));
+        for (base_offset, store, index) in paths {
+            assert!(store.is_file());
+            assert!(index.is_file());
+            assert_eq!(
+                store.parent(),
+                Some(log_dir.join(base_offset.0.to_string()).as_path())
+            );
+        }
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_encryption() {
+        use super::*;
+        use crate::log::encryption::EncryptionConfig;
+        use crate::log::store::StoreError;
+
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_encryption");
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_encryption(EncryptionConfig::new([7; 32]))
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        for i in 0..3 {
+            log.append(Record::builder().value(format!("secret{}", i)).build())
+                .unwrap();
+        }
+        for i in 0..3 {
+            assert_eq!(
+                log.read(AbsOffset(i)).unwrap().value,
+                format!("secret{}", i).into_bytes()
+            );
+        }
+        let prefetched: Vec<Record> = log
+            .iter_from(AbsOffset(0))
+            .with_prefetch(2)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(prefetched.len(), 3);
+        assert_eq!(prefetched[2].value, "secret2".as_bytes());
+
+        // nothing readable ends up on disk
+        log.flush().unwrap();
+        let (_, store_path, _) = log.segment_paths().remove(0);
+        let stored = std::fs::read(store_path).unwrap();
+        assert!(!stored.windows(6).any(|w| w == "secret".as_bytes()));
+        log.close();
+
+        // the wrong key errors rather than panicking or returning garbage
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_encryption(EncryptionConfig::new([8; 32]))
+            .build();
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        let res = log.read(AbsOffset(1));
+        assert!(matches!(
+            res,
+            Err(LogError::SegmentErrors(SegmentError::StoreErrors(
+                StoreError::DecryptionFailed(_)
+            )))
+        ));
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_reindex_all() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_reindex_all");
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                max_store_bytes: 130, // fits 4 records per segment
+                initial_offset: 0,
+                max_record_size_kb: 400,
+            },
+            ..Default::default()
+        };
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        let positions: Vec<StorePos> = (0..10)
+            .map(|i| log.read_with_position(AbsOffset(i)).unwrap().1)
+            .collect();
+        let index_paths: Vec<PathBuf> = log
+            .segment_paths()
+            .into_iter()
+            .map(|(_, _, index)| index)
+            .collect();
+        log.close();
+
+        for path in index_paths {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert!(log.read(AbsOffset(0)).is_err());
+
+        assert_eq!(
+            log.reindex_all().unwrap(),
+            vec![(AbsOffset(0), 4), (AbsOffset(4), 4), (AbsOffset(8), 2)]
+        );
+        for i in 0..10 {
+            let (record, position) = log.read_with_position(AbsOffset(i)).unwrap();
+            assert_eq!(record.value, format!("hello world{}", i).into_bytes());
+            assert_eq!(position, positions[i as usize]);
+        }
+
+        // running it again on healthy indexes changes nothing
+        assert_eq!(
+            log.reindex_all().unwrap(),
+            vec![(AbsOffset(0), 4), (AbsOffset(4), 4), (AbsOffset(8), 2)]
+        );
+        for i in 0..10 {
+            assert_eq!(
+                log.read_with_position(AbsOffset(i)).unwrap().1,
+                positions[i as usize]
+            );
+        }
+        assert_eq!(
+            log.append(Record::builder().value("next").build()).unwrap(),
+            AbsOffset(10)
+        );
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_small_config() {
+        use super::*;
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        for i in 0..20 {
+            let offset = log
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(i));
+        }
+        assert!(log.segments.len() > 2);
+        for i in 0..20 {
+            assert_eq!(
+                log.read(AbsOffset(i)).unwrap().value,
+                format!("hello world{}", i).into_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn log_test_lazy_open() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_lazy_open");
+        let config = ConfigBuilder::new(1024, 40, 0) // a single record per segment
+            .with_lazy_open(true)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..20 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        assert_eq!(log.segments.len(), 20);
+        log.close();
+
+        let log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        let loaded = |log: &Log| log.segments.iter().filter(|s| s.is_loaded()).count();
+
+        // only the active segment is opened, the others are described by the manifest
+        assert_eq!(log.segments.len(), 20);
+        assert_eq!(loaded(&log), 1);
+        assert_eq!(log.segment_paths().len(), 20);
+        assert_eq!(log.len(), 20);
+        assert_eq!(log.segment_index(7), Some(7));
+        assert_eq!(loaded(&log), 1);
+        // the lowest offset is read from the oldest segment's index
+        let stats = log.stats();
+        assert_eq!(stats.store_bytes, 10 * 31 + 10 * 32);
+        assert_eq!(stats.lowest_offset, Some(0));
+        assert_eq!(loaded(&log), 2);
+
+        assert_eq!(
+            log.read(AbsOffset(5)).unwrap().value,
+            "hello world5".as_bytes()
+        );
+        assert_eq!(loaded(&log), 3);
+        assert!(log.segments[5].is_loaded());
+
+        let values: Vec<Record> = log.iter_from(AbsOffset(18)).map(|r| r.unwrap()).collect();
+        assert_eq!(values.len(), 2);
+        assert_eq!(loaded(&log), 4);
+
+        // a segment that can't be opened fails the read instead of panicking
+        drop(log);
+        let index = log_dir.join("9").join(".index");
+        std::fs::remove_file(&index).unwrap();
+        std::fs::create_dir(&index).unwrap();
+        let log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        assert_eq!(log.len(), 20);
+        assert!(matches!(log.read(AbsOffset(9)), Err(LogError::Io { .. })));
+        assert_eq!(
+            log.read(AbsOffset(10)).unwrap().value,
+            "hello world10".as_bytes()
+        );
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_max_open_segments() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_max_open_segments");
+        let config = ConfigBuilder::new(1024, 40, 0) // a single record per segment
+            .with_lazy_open(true)
+            .with_max_open_segments(3)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        let loaded = |log: &Log| log.segments.iter().filter(|s| s.is_loaded()).count();
+        for i in 0..20 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        // the sealed segments are closed as new ones are rolled to, the active one stays open
+        assert_eq!(log.segments.len(), 20);
+        assert_eq!(loaded(&log), 4);
+        drop(log);
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert_eq!(loaded(&log), 1);
+        for (i, record) in log.iter_from(AbsOffset(0)).enumerate() {
+            assert_eq!(
+                record.unwrap().value,
+                format!("hello world{}", i).into_bytes()
+            );
+            assert!(loaded(&log) <= 4);
+        }
+        // the least recently read segments were closed, and are opened again to be read
+        assert!(!log.segments[0].is_loaded());
+        assert!(log.segments[17].is_loaded());
+        assert_eq!(
+            log.read(AbsOffset(0)).unwrap().value,
+            "hello world0".as_bytes()
+        );
+        assert!(log.segments[0].is_loaded());
+        assert_eq!(loaded(&log), 4);
+        assert_eq!(log.len(), 20);
+        drop(log);
+
+        // segments opened up front count as open too
+        let config = ConfigBuilder::new(1024, 40, 0)
+            .with_max_open_segments(3)
+            .build();
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert_eq!(loaded(&log), 4);
+        assert_eq!(
+            log.read(AbsOffset(2)).unwrap().value,
+            "hello world2".as_bytes()
+        );
+        assert_eq!(loaded(&log), 4);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_append_if() {
+        use super::*;
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        assert_eq!(log.next_offset(), AbsOffset(0));
+
+        let expected = log.next_offset();
+        let offset = log
+            .append_if(expected, Record::builder().value("first").build())
+            .unwrap();
+        assert_eq!(offset, AbsOffset(0));
+        assert_eq!(log.next_offset(), AbsOffset(1));
+
+        // a second writer that read the log at the same time now has a stale offset
+        let err = log
+            .append_if(expected, Record::builder().value("second").build())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            LogError::OffsetConflict {
+                expected: 0,
+                actual: 1
+            }
+        ));
+        assert_eq!(log.len(), 1);
+
+        let offset = log
+            .append_if(log.next_offset(), Record::builder().value("second").build())
+            .unwrap();
+        assert_eq!(offset, AbsOffset(1));
+        assert_eq!(log.read(AbsOffset(1)).unwrap().value, "second".as_bytes());
+    }
+
+    #[test]
+    fn log_test_dump() {
+        use super::*;
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        log.append(Record::builder().value("two\nlines").build())
+            .unwrap();
+        log.append(Record::builder().value(vec![0xff, 0x00, 0x01]).build())
+            .unwrap();
+        assert!(log.segments.len() > 1);
+
+        let mut out = vec![];
+        log.dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 12);
+        assert!(lines[0].starts_with("0\t"));
+        assert!(lines[0].ends_with("\thello world0"));
+        assert!(lines[9].starts_with("9\t"));
+        assert!(lines[9].ends_with("\thello world9"));
+        assert!(lines[10].ends_with("\ttwo\\nlines"));
+        assert!(lines[11].starts_with("11\t"));
+        assert!(lines[11].ends_with("\t0xff0001"));
+    }
+
+    #[test]
+    fn log_test_failed_roll_keeps_active_segment() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_failed_roll");
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        for i in 0..4 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        // a file where the next segment's directory should go makes rolling fail
+        let blocker = log_dir.join("4");
+        File::create(&blocker).unwrap();
+        let err = log.append(Record::builder().value("hello world4").build());
+        assert!(err.is_err());
+
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.active_segment, 0);
+        assert_eq!(log.next_offset(), AbsOffset(4));
+        for i in 0..4 {
+            assert_eq!(
+                log.read(AbsOffset(i)).unwrap().value,
+                format!("hello world{}", i).as_bytes()
+            );
+        }
+
+        std::fs::remove_file(&blocker).unwrap();
+        let offset = log
+            .append(Record::builder().value("hello world4").build())
+            .unwrap();
+        assert_eq!(offset, AbsOffset(4));
+        assert_eq!(log.segments.len(), 2);
+        assert_eq!(
+            log.read(AbsOffset(4)).unwrap().value,
+            "hello world4".as_bytes()
+        );
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_test_sync_dir_on_segment_create() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_sync_dir");
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .with_sync_policy(SyncPolicy::EveryAppend)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        assert_eq!(log.segments.len(), 3);
+        sync_dir(&log_dir).unwrap();
+        log.close();
+        drop(log);
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert_eq!(log.len(), 10);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn sync_policy_should_sync() {
+        use super::*;
+        assert!(!SyncPolicy::Never.should_sync(1));
+        assert!(SyncPolicy::EveryAppend.should_sync(1));
+        assert!(!SyncPolicy::EveryN(3).should_sync(2));
+        assert!(SyncPolicy::EveryN(3).should_sync(3));
+        assert!(!SyncPolicy::EveryN(0).should_sync(3));
+    }
+
+    #[test]
+    fn log_test_offset_allocator() {
+        use super::*;
+        use crate::log::offset::OffsetAllocator;
+
+        struct SkipBy10;
+        impl OffsetAllocator for SkipBy10 {
+            fn next(&self, prev: u64, _record: &Record) -> u64 {
+                prev + 10
+            }
+        }
+
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .with_offset_allocator(Arc::new(SkipBy10))
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..10 {
+            let offset = log
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(i * 10));
+        }
+        assert!(log.segments.len() > 1);
+
+        for i in 0..10 {
+            let record = log.read(AbsOffset(i * 10)).unwrap();
+            assert_eq!(record.offset, Some(i * 10));
+            assert_eq!(record.value, format!("hello world{}", i).as_bytes());
+        }
+        assert!(log.read(AbsOffset(5)).is_err());
+        assert!(log.read(AbsOffset(45)).is_err());
+        assert_eq!(log.len(), 10);
+        assert_eq!(log.highest_offset(), Some(AbsOffset(90)));
+
+        // iterating skips the gaps
+        let offsets: Vec<u64> = log
+            .iter_from(AbsOffset(0))
+            .map(|r| r.unwrap().offset.unwrap())
+            .collect();
+        assert_eq!(offsets, (0..10).map(|i| i * 10).collect::<Vec<u64>>());
+        let offsets: Vec<u64> = log
+            .iter_from(AbsOffset(15))
+            .with_prefetch(3)
+            .map(|r| r.unwrap().offset.unwrap())
+            .collect();
+        assert_eq!(offsets, (2..10).map(|i| i * 10).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn log_test_health_check() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_health_check");
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        let report = log.health_check();
+        assert!(report.is_ok());
+        assert_eq!(report.stats.records, 0);
+        // less the store's version
+        assert_eq!(report.active_segment_remaining_bytes, 129);
+
+        for i in 0..10 {
+            log.append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        let report = log.health_check();
+        assert!(report.is_ok());
+        assert_eq!(
+            report.stats,
+            LogStats {
+                segments: 3,
+                records: 10,
+                store_bytes: 10 * 31,
+                lowest_offset: Some(0),
+                highest_offset: Some(9),
+            }
+        );
+        assert_eq!(report.active_segment_remaining_bytes, 129 - 2 * 31);
+
+        let (_, store, _) = log.segment_paths().remove(0);
+        std::fs::remove_file(store).unwrap();
+        let report = log.health_check();
+        assert!(!report.is_ok());
+        match report.status {
+            HealthStatus::Degraded(problems) => {
+                assert_eq!(problems, vec!["Files of segment 0 are missing".to_string()])
+            }
+            HealthStatus::Ok => unreachable!(),
+        }
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_record_ttl() {
+        use super::*;
+        use crate::log::clock::MockClock;
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        log.set_clock(clock.clone());
+
+        log.append(Record::builder().value("forever").build()).unwrap();
+        let offset = log
+            .append(Record::builder().value("short lived").expire_at_millis(1_500).build())
+            .unwrap();
+        log.append(Record::builder().value("forever too").build()).unwrap();
+
+        assert_eq!(log.read(offset).unwrap().value, "short lived".as_bytes());
+        assert_eq!(log.iter_from(AbsOffset(0)).count(), 3);
+
+        clock.advance(500);
+        assert!(matches!(log.read(offset), Err(LogError::RecordExpired(1))));
+        let values: Vec<Vec<u8>> = log
+            .iter_from(AbsOffset(0))
+            .map(|r| r.unwrap().value)
+            .collect();
+        assert_eq!(values, vec!["forever".as_bytes(), "forever too".as_bytes()]);
+        let values: Vec<Vec<u8>> = log
+            .iter_from(AbsOffset(0))
+            .with_prefetch(2)
+            .map(|r| r.unwrap().value)
+            .collect();
+        assert_eq!(values, vec!["forever".as_bytes(), "forever too".as_bytes()]);
+
+        // still in the log, it's only hidden from reads
+        assert_eq!(log.len(), 3);
+        assert!(log.health_check().is_ok());
+    }
+
+    #[test]
+    fn log_test_batching() {
+        use super::*;
+        use crate::log::clock::MockClock;
+        let clock = Arc::new(MockClock::new(1_000));
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .with_batching(8, Duration::from_millis(100))
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        log.set_clock(clock.clone());
+
+        for i in 0..20 {
+            let offset = log
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(i));
+        }
+        // two full batches have been written, the last 4 records are still buffered
+        assert_eq!(log.batch.len(), 4);
+        assert_eq!(log.segments.iter().map(|s| s.len()).sum::<u64>(), 16);
+
+        let check = |log: &Log| {
+            assert_eq!(log.len(), 20);
+            assert_eq!(log.next_offset(), AbsOffset(20));
+            assert_eq!(log.highest_offset(), Some(AbsOffset(19)));
+            for i in 0..20 {
+                let record = log.read(AbsOffset(i)).unwrap();
+                assert_eq!(record.offset, Some(i));
+                assert_eq!(record.timestamp, Some(1_000));
+                assert_eq!(record.value, format!("hello world{}", i).as_bytes());
+            }
+            let values: Vec<Vec<u8>> = log
+                .iter_from(AbsOffset(14))
+                .map(|r| r.unwrap().value)
+                .collect();
+            assert_eq!(values.len(), 6);
+            assert_eq!(values[5], "hello world19".as_bytes());
+        };
+        check(&log);
+
+        log.flush().unwrap();
+        assert!(log.batch.is_empty());
+        check(&log);
+
+        // a batch older than the interval is written on the next append
+        log.append(Record::builder().value("hello world20").build())
+            .unwrap();
+        assert_eq!(log.batch.len(), 1);
+        clock.advance(100);
+        log.append(Record::builder().value("hello world21").build())
+            .unwrap();
+        assert!(log.batch.is_empty());
+        assert_eq!(
+            log.read(AbsOffset(21)).unwrap().value,
+            "hello world21".as_bytes()
+        );
+    }
+
+    #[test]
+    fn log_test_compact_segment() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_compact_segment");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 3 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+
+        let keys = ["a", "b", "c", "a", "d", "b", "e", "a", "f", "g", "h", "i"];
+        for (i, key) in keys.iter().enumerate() {
+            log.append(Record::builder().key(*key).value(format!("value{}", i)).build())
+                .unwrap();
+        }
+        log.append(Record::builder().value("no key").build()).unwrap();
+        assert!(log.segments.len() > 2);
+
+        let first = log.segments[0].next_offset().0;
+        let active_len = log.segments[log.active_segment].len();
+        let active_base = log.segments[log.active_segment].base_offset;
+        assert!(matches!(
+            log.compact_segment(AbsOffset(active_base)),
+            Err(LogError::ActiveSegment(_))
+        ));
+        assert!(matches!(
+            log.compact_segment(AbsOffset(1_000)),
+            Err(LogError::SegmentNotFound(1_000))
+        ));
+
+        // "a" and "b" are written again later, "c" isn't
+        let superseded = |offset: usize| keys[offset + 1..].contains(&keys[offset]);
+        let removed = log.compact_segment(AbsOffset(0)).unwrap();
+        assert_eq!(
+            removed,
+            (0..first as usize).filter(|o| superseded(*o)).count() as u64
+        );
+        assert!(removed > 0);
+
+        let check = |log: &Log| {
+            for offset in 0..first {
+                let read = log.read(AbsOffset(offset));
+                if superseded(offset as usize) {
+                    assert!(read.is_err());
+                } else {
+                    assert_eq!(read.unwrap().value, format!("value{}", offset).as_bytes());
+                }
+            }
+            // the rest of the log is untouched
+            for offset in first..keys.len() as u64 {
+                assert_eq!(
+                    log.read(AbsOffset(offset)).unwrap().value,
+                    format!("value{}", offset).as_bytes()
+                );
+            }
+            assert_eq!(log.segments[log.active_segment].len(), active_len);
+        };
+        check(&log);
+        assert_eq!(log.segments[0].next_offset(), AbsOffset(first));
+        let values: Vec<Vec<u8>> = log
+            .iter_from(AbsOffset(0))
+            .map(|r| r.unwrap().value)
+            .collect();
+        assert_eq!(values.len(), keys.len() + 1 - removed as usize);
+
+        // survives reopening
+        log.close();
+        drop(log);
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        check(&log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_io_error_context() {
+        use super::*;
+        use crate::log::store::VERSION_LEN;
+        let log_dir = PathBuf::from("log_dir_missing_parent").join("log");
+        let err = match Log::new(log_dir.clone(), None) {
+            Err(e) => e,
+            Ok(_) => panic!("created a log under a missing directory"),
+        };
+        match &err {
+            LogError::Io {
+                source,
+                context,
+                path,
+            } => {
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+                assert_eq!(context, "Cannot create log directory");
+                assert_eq!(path.as_deref(), Some(log_dir.as_path()));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(err
+            .to_string()
+            .starts_with("Cannot create log directory log_dir_missing_parent/log: "));
+
+        // a record cut short on disk fails to read with the segment it's in
+        let log_dir = PathBuf::from("log_dir_io_error_context");
+        let mut log = Log::new(log_dir.clone(), None).expect("cannot create log");
+        log.append_str("hello world0").unwrap();
+        let (_, store, _) = log.segment_paths().remove(0);
+        let file = std::fs::OpenOptions::new().write(true).open(&store).unwrap();
+        file.set_len(VERSION_LEN).unwrap();
+        match log.read(AbsOffset(0)) {
+            Err(LogError::Io { source, context, path }) => {
+                assert_eq!(source.kind(), io::ErrorKind::UnexpectedEof);
+                assert_eq!(context, "Cannot read segment");
+                assert_eq!(path, Some(log_dir.join("0")));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        drop(log);
+        std::fs::remove_dir_all(log_dir).unwrap();
+    }
+
+    #[test]
+    fn log_test_compaction_with_concurrent_readers() {
+        use super::*;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::RwLock;
+
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_compaction_concurrent");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 3 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        // the first segment only holds keys that are written again later
+        let keys = ["a", "b", "c", "d", "a", "b", "c", "d", "e"];
+        for (i, key) in keys.iter().enumerate() {
+            log.append(Record::builder().key(*key).value(format!("value{}", i)).build())
+                .unwrap();
+        }
+        let first = log.segments[0].next_offset().0;
+        let log = Arc::new(RwLock::new(log));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let log = log.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let mut compacted_seen = false;
+                    while !done.load(Ordering::SeqCst) || !compacted_seen {
+                        let log = log.read().unwrap();
+                        // records outside the compacted segment always read back intact
+                        for offset in first..keys.len() as u64 {
+                            assert_eq!(
+                                log.read(AbsOffset(offset)).unwrap().value,
+                                format!("value{}", offset).as_bytes()
+                            );
+                        }
+                        // records in it are either intact or gone, never corrupt
+                        match log.read(AbsOffset(0)) {
+                            Ok(record) => assert_eq!(record.value, "value0".as_bytes()),
+                            Err(_) => compacted_seen = true,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let compaction = log
+            .read()
+            .unwrap()
+            .prepare_compaction(AbsOffset(0))
+            .unwrap();
+        // a copy written from an older generation is refused
+        let stale = log
+            .read()
+            .unwrap()
+            .prepare_compaction(AbsOffset(0))
+            .unwrap();
+        let removed = log.write().unwrap().commit_compaction(compaction).unwrap();
+        assert_eq!(removed, first);
+        assert!(matches!(
+            log.write().unwrap().commit_compaction(stale),
+            Err(LogError::StaleCompaction(0))
+        ));
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let log = log.read().unwrap();
+        for offset in 0..first {
+            assert!(log.read(AbsOffset(offset)).is_err());
+        }
+        assert_eq!(log.len(), keys.len() as u64 - first);
+        // only the segment's own files are left, the stale copy was cleaned up
+        assert_eq!(std::fs::read_dir(log_dir.join("0")).unwrap().count(), 2);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_compaction_leftovers_removed_on_open() {
+        use super::*;
+        let log_dir = PathBuf::from("log_dir_compaction_leftovers");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 5 keyed records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for key in ["a", "b", "c", "d", "e", "a"] {
+            log.append(Record::builder().key(key).value(key).build())
+                .unwrap();
+        }
+        // as if the process died before the copy was committed
+        let leftover = log.prepare_compaction(AbsOffset(0)).unwrap();
+        drop(leftover);
+        drop(log);
+        assert_eq!(std::fs::read_dir(log_dir.join("0")).unwrap().count(), 4);
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        assert_eq!(log.read(AbsOffset(0)).unwrap().value, "a".as_bytes());
+        assert_eq!(std::fs::read_dir(log_dir.join("0")).unwrap().count(), 2);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_append_read_str() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        let offset = log.append_str("hello wörld").unwrap();
+        assert_eq!(log.read_str(offset).unwrap(), "hello wörld");
+
+        let offset = log
+            .append(Record::builder().value(vec![0xff, 0xfe]).build())
+            .unwrap();
+        assert!(matches!(log.read_str(offset), Err(LogError::InvalidUtf8(1))));
+    }
+
+    #[test]
+    fn log_test_max_segments() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_max_segments");
+        let config = ConfigBuilder::new(1024, 40, 0) // a single record per segment
+            .with_max_segments(3)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+
+        // each append rolls to a new segment
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(log.segments.len(), 3);
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(0)));
+        assert!(log_dir.join("0").exists());
+
+        // the 4th roll removes the oldest segment
+        log.append_str("hello world3").unwrap();
+        assert_eq!(log.segments.len(), 3);
+        assert_eq!(log.active_segment, 2);
+        assert!(!log_dir.join("0").exists());
+        // 3 segments and the manifest
+        assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 4);
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(1)));
+        assert_eq!(log.highest_offset(), Some(AbsOffset(3)));
+        assert!(log.read(AbsOffset(0)).is_err());
+        assert_eq!(log.read_str(AbsOffset(3)).unwrap(), "hello world3");
+
+        log.append_str("hello world4").unwrap();
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(2)));
+        assert_eq!(log.read_str(AbsOffset(4)).unwrap(), "hello world4");
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_seal() {
+        use super::*;
+        use crate::log::store::VERSION_LEN;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_seal");
+        let mut log = Log::new(log_dir.clone(), None).expect("cannot create log");
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+
+        let base_offset = log.seal().unwrap();
+        assert_eq!(base_offset, AbsOffset(3));
+        assert_eq!(log.segments.len(), 2);
+        // sealing an empty segment doesn't roll again
+        assert_eq!(log.seal().unwrap(), AbsOffset(3));
+        assert_eq!(log.segments.len(), 2);
+
+        let (_, store, index) = log.segment_paths().remove(0);
+        let sizes = || {
+            (
+                std::fs::metadata(&store).unwrap().len(),
+                std::fs::metadata(&index).unwrap().len(),
+            )
+        };
+        let sealed = sizes();
+        assert_eq!(sealed, (VERSION_LEN + 3 * 31, 3 * INDEX_ENTRY_LENGTH as u64));
+
+        for i in 3..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(sizes(), sealed);
+        for i in 0..6 {
+            assert_eq!(
+                log.read_str(AbsOffset(i)).unwrap(),
+                format!("hello world{}", i)
+            );
+        }
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_read_compacted_record() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 3 records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for key in ["a", "b", "c", "b", "d", "e", "f"] {
+            log.append(Record::builder().key(key).value(key).build())
+                .unwrap();
+        }
+        assert_eq!(log.compact_segment(AbsOffset(0)).unwrap(), 1);
+
+        assert!(matches!(
+            log.read(AbsOffset(1)),
+            Err(LogError::RecordCompacted(1))
+        ));
+        assert_eq!(log.read(AbsOffset(0)).unwrap().value, "a".as_bytes());
+        assert_eq!(log.read(AbsOffset(2)).unwrap().value, "c".as_bytes());
+        assert!(matches!(
+            log.read(AbsOffset(100)),
+            Err(LogError::OffsetPastEnd(100))
+        ));
+    }
+
+    #[test]
+    fn log_test_read_has_offset() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        log.append_str("hello world0").unwrap();
+
+        // a record encoded without its offset, as a producer would send it
+        let encoded = Record::builder().value("hello world1").build().encode_to_vec();
+        log.append_encoded(AbsOffset(1), &encoded).unwrap();
+
+        for offset in 0..2 {
+            assert_eq!(log.read(AbsOffset(offset)).unwrap().offset, Some(offset));
+            let record = log.read_appended(AbsOffset(offset)).unwrap();
+            assert_eq!(record.offset(), AbsOffset(offset));
+            assert_eq!(record.value, format!("hello world{}", offset).as_bytes());
+            assert_eq!(record.into_record().offset, Some(offset));
+        }
+    }
+
+    #[test]
+    fn log_test_scan_on_error() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_scan_on_error");
+        let mut log = Log::new(log_dir.clone(), None).expect("cannot create log");
+        for i in 0..5 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let (_, position) = log.read_with_position(AbsOffset(2)).unwrap();
+        let (_, store, _) = log.segment_paths().remove(0);
+        drop(log);
+
+        // overwrite the middle record's payload with bytes that don't decode
+        let mut bytes = std::fs::read(&store).unwrap();
+        let start = position.0 as usize + LEN_WIDTH as usize;
+        bytes[start..start + 23].fill(0xff);
+        std::fs::write(&store, bytes).unwrap();
+
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_scan_on_error(SkipOrFail::Skip)
+            .build();
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        for prefetch in [0, 2] {
+            let items: Vec<Result<Record, LogError>> = log
+                .iter_from(AbsOffset(0))
+                .with_prefetch(prefetch)
+                .collect();
+            assert_eq!(items.len(), 5);
+            assert!(matches!(items[2], Err(LogError::Corrupt(2))));
+            for i in [0, 1, 3, 4] {
+                let record = items[i].as_ref().unwrap();
+                assert_eq!(record.value, format!("hello world{}", i).as_bytes());
+            }
+        }
+        drop(log);
+
+        // failing fast stops at the corrupt record
+        let log = Log::new(log_dir.clone(), None).expect("cannot create log");
+        let items: Vec<Result<Record, LogError>> = log.iter_from(AbsOffset(0)).collect();
+        assert_eq!(items.len(), 3);
+        assert!(items[2].is_err());
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_merge() {
+        use super::*;
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        for i in 0..5 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let mut other = Log::in_memory(Config::small()).expect("cannot create log");
+        for i in 0..10 {
+            other
+                .append(
+                    Record::builder()
+                        .key(format!("key{}", i))
+                        .value(format!("other{}", i))
+                        .build(),
+                )
+                .unwrap();
+        }
+
+        let next = log.merge(&other).unwrap();
+        assert_eq!(next, AbsOffset(10));
+        assert_eq!(log.len(), 15);
+        for i in 0..10 {
+            let merged = log.read(AbsOffset(5 + i)).unwrap();
+            let original = other.read(AbsOffset(i)).unwrap();
+            assert_eq!(merged.offset, Some(5 + i));
+            assert_eq!(merged.value, original.value);
+            assert_eq!(merged.key, original.key);
+            assert_eq!(merged.timestamp, original.timestamp);
+        }
+
+        // resuming only picks up what's new
+        other.append_str("other10").unwrap();
+        assert_eq!(log.merge_from(&other, next).unwrap(), AbsOffset(11));
+        assert_eq!(log.len(), 16);
+        assert_eq!(log.read_str(AbsOffset(15)).unwrap(), "other10");
+        assert_eq!(
+            log.merge_from(&other, AbsOffset(11)).unwrap(),
+            AbsOffset(11)
+        );
+        assert_eq!(log.len(), 16);
+    }
+
+    #[test]
+    fn log_test_strict_offsets() {
+        use super::*;
+        use crate::log::offset::OffsetAllocator;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let config = ConfigBuilder::new(1024, 130, 0)
+            .with_strict_offsets(true)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        // across segment rolls too
+        for i in 0..10 {
+            assert_eq!(
+                log.append_str(&format!("hello world{}", i)).unwrap(),
+                AbsOffset(i)
+            );
+        }
+
+        // skips an offset after the 3rd record
+        struct Skipping;
+        impl OffsetAllocator for Skipping {
+            fn next(&self, prev: u64, _record: &Record) -> u64 {
+                if prev == 2 { prev + 2 } else { prev + 1 }
+            }
+        }
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_strict_offsets(true)
+            .with_offset_allocator(Arc::new(Skipping))
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert!(matches!(
+            log.append_str("hello world3"),
+            Err(LogError::OffsetGap { expected: 3, got: 4 })
+        ));
+        // nothing was written
+        assert_eq!(log.len(), 3);
+
+        // the allocator is asked once per record, including when the check passes
+        struct Counting(AtomicU64);
+        impl OffsetAllocator for Counting {
+            fn next(&self, prev: u64, _record: &Record) -> u64 {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                prev + 1
+            }
+        }
+        let allocator = Arc::new(Counting(AtomicU64::new(0)));
+        let config = ConfigBuilder::new(1024, 130, 0)
+            .with_strict_offsets(true)
+            .with_offset_allocator(allocator.clone())
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        // the very first record doesn't need one
+        assert_eq!(allocator.0.load(Ordering::SeqCst), 9);
+    }
+
+    #[test]
+    fn log_test_size_on_disk() {
+        use super::*;
+        use crate::log::store::VERSION_LEN;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_size_on_disk");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        // an empty segment is just its store's version and its preallocated index
+        assert_eq!(log.logical_size(), 0);
+        assert_eq!(log.size_on_disk(), VERSION_LEN + 1024);
+
+        for i in 0..4 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(log.logical_size(), 4 * 31);
+        assert_eq!(log.size_on_disk(), 4 * 31 + VERSION_LEN + 1024);
+
+        for i in 4..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(log.logical_size(), 10 * 31);
+        assert_eq!(log.size_on_disk(), 10 * 31 + 3 * (VERSION_LEN + 1024));
+        assert!(log.size_on_disk() >= log.logical_size());
+
+        assert_eq!(Log::in_memory(Config::small()).unwrap().size_on_disk(), 0);
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_config_mismatch() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_config_mismatch");
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        log.append_str("hello world0").unwrap();
+        log.close();
+
+        // a smaller index would truncate existing entries
+        let config = ConfigBuilder::new(512, 1024, 0).build();
+        match Log::new(log_dir.clone(), Some(config)) {
+            Err(LogError::ConfigMismatch {
+                field,
+                stored,
+                supplied,
+            }) => {
+                assert_eq!(field, "max_index_bytes");
+                assert_eq!(stored, "1024");
+                assert_eq!(supplied, "512");
+            }
+            _ => panic!("expected a config mismatch"),
+        }
+        let config = ConfigBuilder::new(1024, 1024, 0).with_checksums(true).build();
+        assert!(matches!(
+            Log::new(log_dir.clone(), Some(config)),
+            Err(LogError::ConfigMismatch { .. })
+        ));
+
+        // retention isn't part of the format
+        let config = ConfigBuilder::new(1024, 1024, 0).with_max_segments(2).build();
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        assert_eq!(log.read_str(AbsOffset(0)).unwrap(), "hello world0");
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_position_of() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_position_of");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+
+        log.flush().unwrap();
+        let paths = log.segment_paths();
+        for offset in 0..10 {
+            let (segment, position) = log.position_of(AbsOffset(offset)).unwrap();
+            assert_eq!(segment, offset as usize / 4);
+
+            // read the record straight from the store file
+            let bytes = std::fs::read(&paths[segment].1).unwrap();
+            let start = position.0 as usize;
+            let len = BigEndian::read_u64(&bytes[start..start + LEN_WIDTH as usize]) as usize;
+            let payload = &bytes[start + LEN_WIDTH as usize..start + LEN_WIDTH as usize + len];
+            let record: Record = prost::Message::decode(payload).unwrap();
+            assert_eq!(record, log.read(AbsOffset(offset)).unwrap());
+        }
+        assert!(log.position_of(AbsOffset(10)).is_err());
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_rewind_to() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        log.rewind_to(AbsOffset(5)).unwrap();
+        assert_eq!(log.next_offset(), AbsOffset(5));
+        assert!(log.read(AbsOffset(5)).is_err());
+        for i in 5..10 {
+            assert_eq!(
+                log.append_str(&format!("replayed{}", i)).unwrap(),
+                AbsOffset(i)
+            );
+        }
+        assert_eq!(log.len(), 10);
+        for i in 0..10 {
+            let expected = if i < 5 { "hello world" } else { "replayed" };
+            assert_eq!(
+                log.read_str(AbsOffset(i)).unwrap(),
+                format!("{}{}", expected, i)
+            );
+        }
+        assert!(matches!(
+            log.rewind_to(AbsOffset(11)),
+            Err(LogError::InvalidRewind(11))
+        ));
+
+        // rewinding into an older segment drops the ones after it
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_rewind_to");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(log.segments.len(), 3);
+        log.rewind_to(AbsOffset(2)).unwrap();
+        assert_eq!(log.segments.len(), 1);
+        assert!(!log_dir.join("4").exists());
+        assert_eq!(log.append_str("replayed2").unwrap(), AbsOffset(2));
+        drop(log);
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        assert_eq!(log.next_offset(), AbsOffset(3));
+        assert_eq!(log.read_str(AbsOffset(1)).unwrap(), "hello world1");
+        assert_eq!(log.read_str(AbsOffset(2)).unwrap(), "replayed2");
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_index_interval() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_index_interval");
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_index_interval(4)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..20 {
+            assert_eq!(
+                log.append_str(&format!("hello world{}", i)).unwrap(),
+                AbsOffset(i)
+            );
+        }
+        assert_eq!(log.segments.len(), 1);
+
+        let check = |log: &Log| {
+            // a quarter of the records are indexed
+            assert_eq!(
+                log.segments[0].get().unwrap().index.size,
+                5 * INDEX_ENTRY_LENGTH as u64
+            );
+            assert_eq!(log.len(), 20);
+            assert_eq!(log.highest_offset(), Some(AbsOffset(19)));
+            for i in 0..20 {
+                assert_eq!(
+                    log.read_str(AbsOffset(i)).unwrap(),
+                    format!("hello world{}", i)
+                );
+            }
+            assert!(log.read(AbsOffset(20)).is_err());
+            for prefetch in [0, 3] {
+                let values: Vec<String> = log
+                    .iter_from(AbsOffset(6))
+                    .with_prefetch(prefetch)
+                    .map(|r| String::from_utf8(r.unwrap().value).unwrap())
+                    .collect();
+                assert_eq!(values.len(), 14);
+                assert_eq!(values[0], "hello world6");
+            }
+        };
+        check(&log);
+        drop(log);
+
+        // the offsets after the last indexed record are found on reopen
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        check(&log);
+        assert_eq!(log.append_str("hello world20").unwrap(), AbsOffset(20));
+        assert_eq!(
+            log.segments[0].get().unwrap().index.size,
+            6 * INDEX_ENTRY_LENGTH as u64
+        );
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_flush_on_drop() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_flush_on_drop");
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_flush_on_drop(false)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        // writes out the store, the index is only written out when it's closed
+        log.flush().unwrap();
+        let (_, store, index) = log.segment_paths().remove(0);
+        let lengths = || {
+            (
+                std::fs::metadata(&store).unwrap().len(),
+                std::fs::metadata(&index).unwrap().len(),
+            )
+        };
+        let before = lengths();
+        assert_eq!(before.1, 1024);
+        drop(log);
+
+        // the index is left at its preallocated size
+        assert_eq!(lengths(), before);
+
+        // and its zeroed entries aren't mistaken for records on reopen
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        assert_eq!(log.next_offset(), AbsOffset(3));
+        assert_eq!(log.read_str(AbsOffset(2)).unwrap(), "hello world2");
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn config_diff() {
+        use super::*;
+        let running = ConfigBuilder::new(1024, 1024, 0).build();
+        let file = ConfigBuilder::new(1024, 2048, 0)
+            .with_checksums(true)
+            .build();
+        assert!(running.diff(&running.clone()).is_empty());
+        assert_eq!(
+            running.diff(&file),
+            vec![
+                ConfigDiff {
+                    field: "max_store_bytes",
+                    old: "1024".to_string(),
+                    new: "2048".to_string(),
+                },
+                ConfigDiff {
+                    field: "checksums",
+                    old: "false".to_string(),
+                    new: "true".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn log_test_read_tail() {
+        use super::*;
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        assert!(log.read_tail(5).unwrap().is_empty());
+        for i in 0..100 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert!(log.segments.len() > 2);
+
+        let offsets = |tail: Vec<(AbsOffset, Record)>| {
+            tail.into_iter()
+                .map(|(AbsOffset(offset), record)| {
+                    assert_eq!(record.value, format!("hello world{}", offset).as_bytes());
+                    offset
+                })
+                .collect::<Vec<u64>>()
+        };
+        assert_eq!(offsets(log.read_tail(5).unwrap()), (95..100).collect::<Vec<u64>>());
+        // across segment boundaries
+        assert_eq!(offsets(log.read_tail(30).unwrap()), (70..100).collect::<Vec<u64>>());
+        assert_eq!(offsets(log.read_tail(1000).unwrap()), (0..100).collect::<Vec<u64>>());
+        assert!(log.read_tail(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn log_test_verify_on_open() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_verify_on_open");
+        let mut log = Log::new(log_dir.clone(), None).expect("cannot create log");
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let (_, store, _) = log.segment_paths().remove(0);
+        drop(log);
+
+        // a record that made it to the store but not the index, then half of another
+        let record = Record::builder().value("hello world3").with_offset(3).build();
+        let payload = record.encode_to_vec();
+        let mut frame = vec![];
+        frame.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+        frame.extend_from_slice(&payload);
+        let mut file = OpenOptions::new().append(true).open(&store).unwrap();
+        file.write_all(&frame).unwrap();
+        file.write_all(&frame[..frame.len() / 2]).unwrap();
+        drop(file);
+
+        let log = Log::new(log_dir.clone(), None).expect("cannot reopen log");
+        assert!(log.read(AbsOffset(3)).is_err());
+        drop(log);
+
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_verify_on_open(true)
+            .with_strict_recovery(true);
+        assert!(matches!(
+            Log::new(log_dir.clone(), Some(config.build())),
+            Err(LogError::SegmentErrors(SegmentError::Inconsistent(0)))
+        ));
+
+        let config = ConfigBuilder::new(1024, 1024, 0).with_verify_on_open(true).build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot recover log");
+        assert_eq!(log.next_offset(), AbsOffset(4));
+        assert_eq!(log.read_str(AbsOffset(3)).unwrap(), "hello world3");
+        assert_eq!(log.append_str("hello world4").unwrap(), AbsOffset(4));
+        assert_eq!(log.read_str(AbsOffset(4)).unwrap(), "hello world4");
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_iter_keys() {
+        use super::*;
+        let mut log = Log::in_memory(Config::small()).expect("cannot create log");
+        let writes = [("a", "1"), ("b", "1"), ("a", "2"), ("c", "1"), ("b", "2"), ("a", "3")];
+        for (key, value) in writes {
+            log.append(Record::builder().key(key).value(value).build()).unwrap();
+            log.append_str("no key").unwrap();
+        }
+        assert!(log.segments.len() > 1);
+
+        let keys: Vec<(Vec<u8>, AbsOffset)> = log.iter_keys().unwrap().collect();
+        assert_eq!(
+            keys,
+            vec![
+                (b"c".to_vec(), AbsOffset(6)),
+                (b"b".to_vec(), AbsOffset(8)),
+                (b"a".to_vec(), AbsOffset(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn log_test_manifest() {
+        use super::*;
+        let mut log_dir = PathBuf::new();
+        log_dir.push("log_dir_manifest");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        drop(log);
+        let manifest = Manifest::read(&log_dir).unwrap().unwrap();
+        assert_eq!(manifest.segments, vec![0, 4, 8]);
+
+        // flip a digit, so the manifest no longer matches its checksum
+        let manifest_path = log_dir.join(MANIFEST_FILE);
+        let contents = std::fs::read_to_string(&manifest_path).unwrap();
+        std::fs::write(&manifest_path, contents.replace("segments=0,4,8", "segments=0,4,9")).unwrap();
+
+        let strict = ConfigBuilder::new(1024, 130, 0).with_strict_recovery(true).build();
+        assert!(matches!(
+            Log::new(log_dir.clone(), Some(strict)),
+            Err(LogError::CorruptManifest(_))
+        ));
+
+        // the segments are found by scanning the directory instead, and the manifest rewritten
+        let log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot reopen log");
+        assert_eq!(log.segments.len(), 3);
+        assert_eq!(log.next_offset(), AbsOffset(10));
+        for i in 0..10 {
+            assert_eq!(
+                log.read_str(AbsOffset(i)).unwrap(),
+                format!("hello world{}", i)
+            );
+        }
+        drop(log);
+        assert_eq!(Manifest::read(&log_dir).unwrap().unwrap(), manifest);
+
+        // a listed segment that's gone is caught too
+        std::fs::remove_dir_all(log_dir.join("8")).unwrap();
+        let log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot reopen log");
+        assert_eq!(log.next_offset(), AbsOffset(8));
+        drop(log);
+        assert_eq!(Manifest::read(&log_dir).unwrap().unwrap().segments, vec![0, 4]);
+
+        // as are unlisted segments, when verifying
+        let contents = std::fs::read_to_string(&manifest_path).unwrap();
+        let mut manifest = Manifest::read(&log_dir).unwrap().unwrap();
+        manifest.segments = vec![0];
+        manifest.write(&log_dir, false).unwrap();
+        let verify = ConfigBuilder::new(1024, 130, 0).with_verify_on_open(true).build();
+        let log = Log::new(log_dir.clone(), Some(verify)).expect("cannot reopen log");
+        assert_eq!(log.segments.len(), 2);
+        drop(log);
+        assert_eq!(std::fs::read_to_string(&manifest_path).unwrap(), contents);
+
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_progress_fn_cancels() {
+        use super::*;
+        use std::sync::Mutex;
+        let config = ConfigBuilder::new(1024, 130, 0) // a few records per segment
+            .with_progress_interval(3)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..10 {
+            log.append(Record::builder().key("k").value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        let calls = Arc::new(Mutex::new(vec![]));
+        let progress_fn = |calls: Arc<Mutex<Vec<(u64, u64)>>>, stop_at: u64| -> ProgressFn {
+            Box::new(move |done, total| {
+                calls.lock().unwrap().push((done, total));
+                done < stop_at
+            })
+        };
+
+        log.set_progress_fn(progress_fn(calls.clone(), u64::MAX));
+        assert_eq!(log.verify().unwrap(), 10);
+        assert_eq!(*calls.lock().unwrap(), vec![(3, 10), (6, 10), (9, 10), (10, 10)]);
+
+        calls.lock().unwrap().clear();
+        log.set_progress_fn(progress_fn(calls.clone(), 6));
+        assert!(matches!(log.verify(), Err(LogError::Cancelled(6))));
+        assert_eq!(*calls.lock().unwrap(), vec![(3, 10), (6, 10)]);
+
+        // the segment being reindexed when it's cancelled is left as it was
+        assert!(matches!(log.reindex_all(), Err(LogError::Cancelled(6))));
+        // and a cancelled compaction doesn't replace the segment
+        log.set_progress_fn(progress_fn(calls.clone(), 3));
+        assert!(matches!(
+            log.compact_segment(AbsOffset(0)),
+            Err(LogError::Cancelled(3))
+        ));
+
+        assert_eq!(log.len(), 10);
+        for i in 0..10 {
+            assert_eq!(
+                log.read(AbsOffset(i)).unwrap().value,
+                format!("hello world{}", i).into_bytes()
+            );
+        }
+        assert_eq!(
+            log.append(Record::builder().value("next").build()).unwrap(),
+            AbsOffset(10)
+        );
+    }
+
+    #[test]
+    fn log_test_append_ref() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        let template = Record::builder().value("hello world").build();
+
+        let offsets: Vec<u64> = (0..10)
+            .map(|_| log.append_ref(&template).unwrap().0)
+            .collect();
+        assert_eq!(offsets, (0..10).collect::<Vec<u64>>());
+        assert!(log.segments.len() > 1);
+
+        // the offsets and timestamps went into the log, not the template
+        assert_eq!(template.offset, None);
+        assert_eq!(template.timestamp, None);
+        for offset in offsets {
+            let record = log.read(AbsOffset(offset)).unwrap();
+            assert_eq!(record.offset, Some(offset));
+            assert!(record.timestamp.is_some());
+            assert_eq!(record.value, template.value);
+        }
+    }
+
+    #[test]
+    fn log_test_open_empty_dir() {
+        use super::*;
+        let log_dir = PathBuf::from("log_dir_open_empty_dir");
+        std::fs::create_dir_all(&log_dir).unwrap();
+        let config = ConfigBuilder::new(1024, 1024, 5).build();
+
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        assert!(log.is_empty());
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.lowest_offset(), None);
+        assert_eq!(log.highest_offset(), None);
+        assert_eq!(log.next_offset(), AbsOffset(5));
+        // offsets before, at and after the initial offset are all just missing
+        for offset in [0, 5, 6] {
+            assert!(log.read(AbsOffset(offset)).is_err());
+        }
+        assert!(log.read_tail(3).unwrap().is_empty());
+        assert!(log.iter_from(AbsOffset(0)).next().is_none());
+        assert!(log.health_check().is_ok());
+
+        assert_eq!(
+            log.append(Record::builder().value("first").build())
+                .unwrap(),
+            AbsOffset(5)
+        );
+        assert_eq!(log.highest_offset(), Some(AbsOffset(5)));
+        drop(log);
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        assert_eq!(log.read_str(AbsOffset(5)).unwrap(), "first");
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_size_histogram() {
+        use super::*;
+        use crate::log::clock::MockClock;
+        use std::collections::BTreeMap;
+        let config = ConfigBuilder::new(1024, 4096, 0).build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        assert_eq!(log.size_histogram().unwrap(), SizeHistogram::default());
+
+        // a 1 byte offset and timestamp, each with a 1 byte tag, on top of the value
+        log.set_clock(Arc::new(MockClock::new(1)));
+        for len in [10, 100, 200, 300] {
+            log.append(Record::builder().value(vec![b'a'; len]).build()).unwrap();
+        }
+
+        let histogram = log.size_histogram().unwrap();
+        // the value's tag and length take 2 bytes, or 3 once it's over 127 bytes
+        let expected: BTreeMap<u64, u64> = [(16, 1), (128, 1), (256, 1), (512, 1)].into();
+        assert_eq!(histogram.buckets, expected);
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.min, Some(16));
+        assert_eq!(histogram.max, Some(307));
+        assert_eq!(histogram.total, 16 + 106 + 207 + 307);
+        assert_eq!(histogram.mean(), Some(159.0));
+    }
+
+    #[test]
+    fn log_test_append_idempotent() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        log.append_str("before").unwrap();
+
+        let first = log
+            .append_idempotent(7, 0, Record::builder().value("first").build())
+            .unwrap();
+        assert_eq!(
+            first,
+            AppendOutcome {
+                offset: AbsOffset(1),
+                deduplicated: false
+            }
+        );
+
+        // a retry gets the offset it was appended at, without appending it again
+        let retried = log
+            .append_idempotent(7, 0, Record::builder().value("first").build())
+            .unwrap();
+        assert_eq!(
+            retried,
+            AppendOutcome {
+                offset: AbsOffset(1),
+                deduplicated: true
+            }
+        );
+        assert_eq!(log.len(), 2);
+
+        // producers are tracked separately
+        let other = log
+            .append_idempotent(8, 0, Record::builder().value("other").build())
+            .unwrap();
+        assert_eq!(other.offset, AbsOffset(2));
+        assert!(!other.deduplicated);
+
+        let second = log
+            .append_idempotent(7, 1, Record::builder().value("second").build())
+            .unwrap();
+        assert_eq!(second.offset, AbsOffset(3));
+        assert!(!second.deduplicated);
+        assert!(matches!(
+            log.append_idempotent(7, 0, Record::builder().value("first").build()),
+            Err(LogError::StaleSequence {
+                producer: 7,
+                sequence: 0,
+                last: 1
+            })
+        ));
+        assert_eq!(log.read_str(AbsOffset(3)).unwrap(), "second");
+    }
+
+    #[test]
+    fn log_test_scan_ignores_junk() {
+        use super::*;
+        let log_dir = PathBuf::from("log_dir_scan_ignores_junk");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        drop(log);
+
+        // files and directories that aren't segments, some named like they could be
+        std::fs::write(log_dir.join("4.store"), b"junk").unwrap();
+        std::fs::write(log_dir.join("12"), b"junk").unwrap();
+        std::fs::write(log_dir.join("notes.txt"), b"junk").unwrap();
+        std::fs::create_dir(log_dir.join("backup")).unwrap();
+        std::fs::create_dir(log_dir.join("004")).unwrap();
+        std::fs::remove_file(log_dir.join(MANIFEST_FILE)).unwrap();
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        let base_offsets: Vec<u64> = log
+            .segment_paths()
+            .iter()
+            .map(|(base, _, _)| base.0)
+            .collect();
+        assert_eq!(base_offsets, vec![0, 4, 8]);
+        for i in 0..10 {
+            assert_eq!(
+                log.read_str(AbsOffset(i)).unwrap(),
+                format!("hello world{}", i)
+            );
+        }
+        assert!(log_dir.join("backup").is_dir());
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_max_total_records() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .with_max_total_records(6, OnFull::Reject)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert!(matches!(log.append_str("hello world6"), Err(LogError::LogFull)));
+        assert_eq!(log.len(), 6);
+        assert_eq!(log.next_offset(), AbsOffset(6));
+
+        let config = ConfigBuilder::new(1024, 130, 0)
+            .with_max_total_records(6, OnFull::EvictOldest)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        // the first segment, holding 0 to 3, makes way
+        assert_eq!(log.append_str("hello world6").unwrap(), AbsOffset(6));
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(4)));
+        assert_eq!(log.highest_offset(), Some(AbsOffset(6)));
+        assert!(log.read(AbsOffset(3)).is_err());
+        assert_eq!(log.read_str(AbsOffset(4)).unwrap(), "hello world4");
+    }
+
+    #[test]
+    fn log_test_max_total_records_evicts_active_segment() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_max_total_records(2, OnFull::EvictOldest)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        log.append_str("hello world0").unwrap();
+        log.append_str("hello world1").unwrap();
+
+        // both are in the active segment, which is rolled and then evicted
+        assert_eq!(log.append_str("hello world2").unwrap(), AbsOffset(2));
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(2)));
+        assert_eq!(log.highest_offset(), Some(AbsOffset(2)));
+        assert_eq!(log.append_str("hello world3").unwrap(), AbsOffset(3));
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.read_str(AbsOffset(2)).unwrap(), "hello world2");
+    }
+
+    #[test]
+    fn log_test_try_append_batch() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_max_record_size_kb(20)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+
+        let results = log.try_append_batch(vec![
+            Record::builder().value("hello world0").build(),
+            Record::builder().value("far too large for this log").build(),
+            Record::builder().value("hello world1").build(),
+        ]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(*results[0].as_ref().unwrap(), AbsOffset(0));
+        assert!(matches!(results[1], Err(LogError::RecordTooLarge)));
+        assert_eq!(*results[2].as_ref().unwrap(), AbsOffset(1));
+
+        // the records either side of the oversized one were kept
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.read_str(AbsOffset(0)).unwrap(), "hello world0");
+        assert_eq!(log.read_str(AbsOffset(1)).unwrap(), "hello world1");
+    }
+
+    #[test]
+    fn log_test_presets() {
+        use super::*;
+        for (workload, dir) in [
+            (Workload::HighThroughput, "log_dir_preset_high_throughput"),
+            (Workload::LowLatency, "log_dir_preset_low_latency"),
+            (Workload::SpaceEfficient, "log_dir_preset_space_efficient"),
+        ] {
+            let log_dir = PathBuf::from(dir);
+            let config = Config::preset(workload);
+            let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+            for i in 0..50 {
+                assert_eq!(
+                    log.append_str(&format!("hello world{}", i)).unwrap(),
+                    AbsOffset(i)
+                );
+            }
+            log.flush().unwrap();
+            for i in 0..50 {
+                assert_eq!(
+                    log.read_str(AbsOffset(i)).unwrap(),
+                    format!("hello world{}", i)
+                );
+            }
+            drop(log);
+
+            let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+            assert_eq!(log.next_offset(), AbsOffset(50), "{:?}", workload);
+            assert_eq!(log.read_str(AbsOffset(37)).unwrap(), "hello world37");
+            assert!(log.health_check().is_ok());
+            std::fs::remove_dir_all(log_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn log_test_read_into() {
+        use super::*;
+        // the last record stays batched
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_batching(3, Duration::from_secs(60))
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..4 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(log.batch.len(), 1);
+
+        let mut buf = b"left over from an earlier read".to_vec();
+        for offset in 0..4 {
+            assert_eq!(
+                log.read_into(AbsOffset(offset), &mut buf).unwrap(),
+                AbsOffset(offset)
+            );
+            assert_eq!(buf, log.read(AbsOffset(offset)).unwrap().value);
+        }
+        assert!(log.read_into(AbsOffset(4), &mut buf).is_err());
+    }
+
+    #[test]
+    fn log_test_batch_written_out_at_once() {
+        use super::*;
+        let log_dir = PathBuf::from("log_dir_batch_written_out_at_once");
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_batching(3, Duration::from_secs(60))
+            .with_sync_policy(SyncPolicy::EveryAppend)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        let store_len = || std::fs::metadata(log_dir.join("0").join(".store")).unwrap().len();
+        let empty = store_len();
+
+        // nothing reaches the file until the batch is full, then all of it does
+        for i in 0..2 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        assert_eq!(store_len(), empty);
+        log.append_str("hello world2").unwrap();
+        let written = store_len();
+        assert!(written > empty);
+
+        log.append_str("hello world3").unwrap();
+        assert_eq!(store_len(), written);
+        log.flush().unwrap();
+        assert!(store_len() > written);
+        assert_eq!(log.read_str(AbsOffset(3)).unwrap(), "hello world3");
+        drop(log);
+        std::fs::remove_dir_all(log_dir).unwrap();
+    }
+
+    #[test]
+    fn log_test_append_set() {
+        use super::*;
+        let log_dir = PathBuf::from("log_dir_append_set");
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        log.append_str("before").unwrap();
+        let set: Vec<Record> = (0..10)
+            .map(|i| Record::builder().value(format!("hello world{}", i)).build())
+            .collect();
+        assert_eq!(
+            log.append_set(set).unwrap(),
+            (1..11).map(AbsOffset).collect::<Vec<_>>()
+        );
+        assert_eq!(log.append_str("after").unwrap(), AbsOffset(11));
+
+        let check = |log: &Log| {
+            assert_eq!(log.len(), 12);
+            for i in 0..10 {
+                let record = log.read(AbsOffset(1 + i)).unwrap();
+                assert_eq!(record.offset, Some(1 + i));
+                assert_eq!(record.value, format!("hello world{}", i).as_bytes());
+            }
+            assert_eq!(log.read_str(AbsOffset(0)).unwrap(), "before");
+            assert_eq!(log.read_str(AbsOffset(11)).unwrap(), "after");
+            let offsets: Vec<u64> = log
+                .iter_from(AbsOffset(0))
+                .map(|r| r.unwrap().offset.unwrap())
+                .collect();
+            assert_eq!(offsets, (0..12).collect::<Vec<u64>>());
+        };
+        check(&log);
+        // the set is a single entry in the store
+        assert_eq!(log.size_histogram().unwrap().count, 3);
+        drop(log);
+
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot reopen log");
+        check(&log);
+        assert_eq!(log.next_offset(), AbsOffset(12));
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+
+    #[test]
+    fn log_test_drain() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+
+        // the first half, ending part way through the second segment
+        let drained = log.drain(AbsOffset(4)).unwrap();
+        let offsets: Vec<u64> = drained.iter().map(|(offset, _)| offset.0).collect();
+        assert_eq!(offsets, vec![0, 1, 2, 3, 4]);
+        assert_eq!(drained[4].1.value, "hello world4".as_bytes());
+        // only the first segment is removed
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(4)));
+        assert_eq!(log.len(), 6);
+
+        // the rest start after up_to
+        let drained = log.drain(AbsOffset(100)).unwrap();
+        let offsets: Vec<u64> = drained.iter().map(|(offset, _)| offset.0).collect();
+        assert_eq!(offsets, (5..10).collect::<Vec<u64>>());
+        assert!(log.is_empty());
+        assert!(log.drain(AbsOffset(100)).unwrap().is_empty());
+
+        // appending carries on after the drained records
+        assert_eq!(log.append_str("hello world10").unwrap(), AbsOffset(10));
+        assert_eq!(log.drain(AbsOffset(10)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn log_test_compaction_estimate() {
+        use super::*;
+        use crate::log::clock::MockClock;
+        let clock = Arc::new(MockClock::new(1_000));
+        let config = ConfigBuilder::new(1024, 130, 0).build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        log.set_clock(clock.clone());
+
+        log.append(Record::builder().value("short lived").expire_at_millis(1_500).build())
+            .unwrap();
+        let keys = ["a", "b", "c", "a", "d", "b", "e", "a", "f", "g"];
+        for (i, key) in keys.iter().enumerate() {
+            log.append(Record::builder().key(*key).value(format!("value{}", i)).build())
+                .unwrap();
+        }
+        for i in 0..3 {
+            log.append_str(&format!("no key{}", i)).unwrap();
+        }
+        clock.advance(500);
+        // the superseded records and the expired one are all in sealed segments
+        assert!(log.segments[log.active_segment].base_offset > 7);
 
-    pub fn read(&self, offset: u64) -> Result<Record, LogError> {
-        let mut active_segment: usize = 0;
-        // we iterate over the segments until we find the
-        //first segment whose base offset is less than or equal to the offset we’re looking
+        let before = log.stats().store_bytes;
+        let estimate = log.compaction_estimate().unwrap();
+        // the first two writes of "a" and the first of "b", expiry is left to retention
+        assert_eq!(estimate.records, 3);
 
-        for (i, segment) in self.segments.iter().enumerate() {
-            if self.segments[i].base_offset <= offset && offset < self.segments[i].next_offset {
-                active_segment = i;
-                break;
-            }
+        // nothing was rewritten
+        assert_eq!(log.stats().store_bytes, before);
+        assert_eq!(log.len(), 14);
+
+        let sealed: Vec<u64> = log.segments[..log.active_segment]
+            .iter()
+            .map(|segment| segment.base_offset)
+            .collect();
+        let mut removed = 0;
+        for base_offset in sealed {
+            removed += log.compact_segment(AbsOffset(base_offset)).unwrap();
         }
-        let record = self.segments[active_segment].read(offset)?;
-        Ok(record)
+        assert_eq!(removed, estimate.records);
+        assert_eq!(before - log.stats().store_bytes, estimate.bytes);
+        assert_eq!(log.compaction_estimate().unwrap(), CompactionEstimate::default());
     }
 
-    fn close(&mut self) {
-        for segment in &mut self.segments {
-            segment.close();
+    #[test]
+    fn log_test_snapshot() {
+        use super::*;
+        let log_dir = PathBuf::from("log_dir_snapshot");
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        for i in 0..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
         }
-    }
 
-    fn remove(&mut self) -> Result<(), LogError> {
-        self.close();
+        // the active segment's last appends are still buffered by its store
+        let mut snapshot = log.snapshot().unwrap();
+        let snapshot_dir = snapshot.dir.clone();
+        assert!(snapshot_dir.is_dir());
+        for i in 6..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
 
-        let _ = std::fs::remove_dir(self.dir.clone())?;
-        Ok(())
-    }
+        assert_eq!(log.len(), 10);
+        assert_eq!(snapshot.len(), 6);
+        assert_eq!(snapshot.next_offset(), AbsOffset(6));
+        let values: Vec<String> = snapshot
+            .iter_from(AbsOffset(0))
+            .map(|record| String::from_utf8(record.unwrap().value).unwrap())
+            .collect();
+        let expected: Vec<String> = (0..6).map(|i| format!("hello world{}", i)).collect();
+        assert_eq!(values, expected);
+        assert!(snapshot.read(AbsOffset(6)).is_err());
+        assert!(matches!(
+            snapshot.append_str("hello"),
+            Err(LogError::ReadOnly)
+        ));
+        assert!(matches!(
+            snapshot.drain(AbsOffset(3)),
+            Err(LogError::ReadOnly)
+        ));
 
-    fn reset(&mut self) -> Result<(), LogError> {
-        self.remove()?;
-        self.setup()
+        drop(snapshot);
+        assert!(!snapshot_dir.exists());
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
     }
 
-    fn lowest_offset(&self) -> Result<u64, LogError> {
-        Ok(self.segments[0].base_offset)
-    }
+    #[test]
+    fn log_test_snapshot_in_memory() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0)
+            .with_batching(10, Duration::from_secs(60))
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let snapshot = log.snapshot().unwrap();
+        log.append_str("hello world6").unwrap();
+        log.flush().unwrap();
 
-    fn highest_offset(&self) -> Result<u64, LogError> {
-        let offset = self
-            .segments
-            .last()
-            .map(|last_segment| last_segment.next_offset - 1)
-            .unwrap_or(0);
-        Ok(offset)
+        assert_eq!(log.len(), 7);
+        // batched records are in the snapshot too
+        assert_eq!(snapshot.read_str(AbsOffset(5)).unwrap(), "hello world5");
+        assert!(snapshot.read(AbsOffset(6)).is_err());
     }
 
-    fn truncate(&mut self, lowest: u64) {
-        let mut segments: Vec<Segment> = vec![];
+    #[test]
+    fn log_test_validate_offset() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 5 keyed records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for key in ["a", "b", "c", "d", "e", "f", "g", "f", "h", "i", "j"] {
+            log.append(Record::builder().key(key).value(key).build())
+                .unwrap();
+        }
+        // drops the first segment, and the first "f" from the second
+        log.truncate(4);
+        assert_eq!(log.segments[0].base_offset, 5);
+        assert_eq!(log.compact_segment(AbsOffset(5)).unwrap(), 1);
 
-        let mut segment_index_to_remove: Vec<usize> = vec![];
+        assert!(matches!(
+            log.validate_offset(AbsOffset(4)),
+            Err(LogError::OffsetGone(4))
+        ));
+        assert!(matches!(
+            log.read(AbsOffset(0)),
+            Err(LogError::OffsetGone(0))
+        ));
+        assert!(matches!(
+            log.validate_offset(AbsOffset(5)),
+            Err(LogError::RecordCompacted(5))
+        ));
+        assert!(matches!(
+            log.read_encoded(AbsOffset(5)),
+            Err(LogError::RecordCompacted(5))
+        ));
+        assert!(log.validate_offset(AbsOffset(6)).is_ok());
+        assert!(log.validate_offset(AbsOffset(10)).is_ok());
+        assert!(matches!(
+            log.validate_offset(AbsOffset(11)),
+            Err(LogError::OffsetPastEnd(11))
+        ));
+        assert!(matches!(
+            log.position_of(AbsOffset(u64::MAX)),
+            Err(LogError::OffsetPastEnd(_))
+        ));
+    }
 
-        for (i, mut segment) in &mut self.segments.iter_mut().enumerate() {
-            if segment.next_offset <= lowest + 1 {
-                segment.remove();
-                segment_index_to_remove.push(i)
-            }
+    #[test]
+    fn log_test_append_located() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        let mut bases = vec![];
+        for i in 0..10 {
+            let segments = log.segments.len();
+            let (offset, base) = log
+                .append_located(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+            assert_eq!(offset, AbsOffset(i));
+            // the base only changes on the append that created a new segment
+            let rolled = log.segments.len() > segments;
+            assert_eq!(rolled, bases.last().is_some_and(|last| *last != base.0));
+            bases.push(base.0);
         }
+        assert_eq!(bases, vec![0, 0, 0, 0, 4, 4, 4, 4, 8, 8]);
+    }
 
-        for index in segment_index_to_remove {
-            self.segments.remove(index);
+    #[test]
+    fn log_test_verify_checksums_only() {
+        use super::*;
+        use crate::log::store::StoreBackend;
+        let config = ConfigBuilder::new(1024, 1024, 0).with_checksums(true).build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
         }
-    }
-}
+        // bytes that aren't a record, appended as is
+        log.append_encoded(AbsOffset(3), &[0xff; 4]).unwrap();
 
-impl Drop for Log {
-    fn drop(&mut self) {
-        self.close()
+        // the checksums hold, so only a full verify decodes the bad record
+        assert!(matches!(
+            log.verify(),
+            Err(LogError::SegmentErrors(SegmentError::DecodeError(_)))
+        ));
+        assert_eq!(log.verify_checksums_only().unwrap(), 4);
+
+        // flip a bit in the second record's payload
+        let (_, position) = log.position_of(AbsOffset(1)).unwrap();
+        let segment = log.segments[0].get_mut().unwrap();
+        let payload_at = position.0 as usize + segment.store.header_len() as usize;
+        match &mut segment.store.backend {
+            StoreBackend::Memory(bytes) => bytes[payload_at + 2] ^= 0x01,
+            StoreBackend::File(_) => unreachable!("the log is in memory"),
+        }
+        assert!(matches!(
+            log.verify_checksums_only(),
+            Err(LogError::SegmentErrors(SegmentError::StoreErrors(
+                StoreError::ChecksumMismatch(p)
+            ))) if p == position.0
+        ));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::path::{Path, PathBuf};
-    use std::sync::Arc;
-    use std::{
-        fs::{File, OpenOptions},
-        io::{Cursor, Read},
-    };
+    #[test]
+    fn log_test_append_latencies() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        log.append_str("hello world").unwrap();
+        assert_eq!(log.append_latencies(), LatencyStats::default());
 
-    use crate::proto::record::Record;
+        let config = ConfigBuilder::new(1024, 1024, 0).with_track_latency(8).build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let latencies = log.append_latencies();
+        // only the latest 8 are kept
+        assert_eq!(latencies.count, 8);
+        assert!(latencies.p50 <= latencies.p99);
+        assert!(latencies.p99 <= latencies.max);
 
-    use super::{Config, Index, Store};
+        let stats = LatencyStats::from_latencies((1..=100).map(Duration::from_millis));
+        assert_eq!(stats.p50, Duration::from_millis(50));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+        assert_eq!(stats.max, Duration::from_millis(100));
+    }
 
     #[test]
-    fn log_test_append_read() {
-        // test append and read a record
+    fn log_test_open_and_migrate() {
         use super::*;
-        let mut log_dir = PathBuf::new();
-        log_dir.push("log_dir_append_read");
-        let config = Config {
-            segment: SegmentConfig {
-                max_index_bytes: 1024,
-                max_store_bytes: 1024,
-                initial_offset: 0,
-                max_record_size_kb: 400,
-            },
-        };
+        let log_dir = PathBuf::from("log_dir_open_and_migrate");
+        let old = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::new(log_dir.clone(), Some(old)).expect("cannot create log");
+        for i in 0..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let set = (6..8)
+            .map(|i| Record::builder().value(format!("hello world{}", i)).build())
+            .collect();
+        log.append_set(set).unwrap();
+        drop(log);
 
-        // let config  = Arc::new(config);
+        let new = ConfigBuilder::new(1024, 130, 0)
+            .with_checksums(true)
+            .with_index_interval(2)
+            .build();
+        assert!(matches!(
+            Log::new(log_dir.clone(), Some(new.clone())),
+            Err(LogError::ConfigMismatch { .. })
+        ));
 
-        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        // interrupted before the first segment's store was replaced, with its rewrite half done
+        let migration = Log::plan_migration(&log_dir, &new).unwrap().unwrap();
+        assert!(migration.segments.len() > 1);
+        migration.write(&log_dir).unwrap();
+        std::fs::write(log_dir.join("0").join(".store.migrate"), "half a store").unwrap();
+        assert!(matches!(
+            Log::new(log_dir.clone(), Some(new.clone())),
+            Err(LogError::MigrationPending(_))
+        ));
 
-        let record = crate::proto::record::Record {
-            value: "hello world".as_bytes().to_vec(),
-            offset: None,
-        };
+        let log = Log::open_and_migrate(log_dir.clone(), new.clone()).expect("cannot migrate log");
+        assert!(!log_dir.join(MIGRATION_FILE).exists());
+        assert_eq!(log.len(), 8);
+        for i in 0..8 {
+            assert_eq!(
+                log.read_str(AbsOffset(i)).unwrap(),
+                format!("hello world{}", i)
+            );
+        }
+        // every frame carries a checksum now
+        assert_eq!(log.verify().unwrap(), 8);
+        let manifest = Manifest::read(&log_dir).unwrap().unwrap();
+        assert!(manifest.checksums);
+        assert_eq!(manifest.index_interval, 2);
+        drop(log);
 
-        let offset = log.append(record.clone()).unwrap();
+        // opens like any other log in the new format from then on
+        let log = Log::new(log_dir.clone(), Some(new)).expect("cannot reopen log");
+        assert_eq!(log.read_str(AbsOffset(7)).unwrap(), "hello world7");
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
 
-        let read_record = log.read(offset).unwrap();
-        assert_eq!(record.value, read_record.value);
+    #[test]
+    fn log_test_append_dedup_by_content() {
+        use super::*;
+        let mut log = Log::in_memory(Config::default()).expect("cannot create log");
+        let record = || Record::builder().key("a").value("hello world").build();
 
-        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+        let first = log.append_dedup_by_content(record()).unwrap();
+        assert!(!first.deduplicated);
+        let second = log.append_dedup_by_content(record()).unwrap();
+        assert!(second.deduplicated);
+        assert_eq!(second.offset, first.offset);
+        assert_eq!(log.len(), 1);
+
+        // the assigned timestamp doesn't count, a different key does
+        let mut stamped = record();
+        stamped.timestamp = Some(1);
+        assert_eq!(stamped.content_hash(), record().content_hash());
+        let other = Record::builder().key("b").value("hello world").build();
+        assert_ne!(other.content_hash(), record().content_hash());
+        assert!(!log.append_dedup_by_content(other).unwrap().deduplicated);
+        // the key and value aren't run together
+        let split = |key: &str, value: &str| Record::builder().key(key).value(value).build();
+        assert_ne!(split("ab", "c").content_hash(), split("a", "bc").content_hash());
+        assert_eq!(log.len(), 2);
     }
 
     #[test]
-    fn log_test_out_of_range() {
-        use super::IndexError::{self, *};
-        use super::LogError::{IndexErrors, SegmentErrors};
+    fn log_test_read_range_across_gap() {
         use super::*;
-        let mut log_dir = PathBuf::new();
-        log_dir.push("log_dir_test_out_of_range");
-        let config = Config {
-            segment: SegmentConfig {
-                max_index_bytes: 1024,
-                max_store_bytes: 1024,
-                initial_offset: 0,
-                max_record_size_kb: 400,
-            },
-        };
-        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
-        let res = log.read(1);
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 5 keyed records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        // the second segment's keys are all written again in the third
+        for key in keys.iter().chain(&keys[5..]).chain(&["k"]) {
+            log.append(Record::builder().key(*key).value(*key).build())
+                .unwrap();
+        }
+        assert_eq!(log.segments[1].base_offset, 5);
+        assert_eq!(log.compact_segment(AbsOffset(5)).unwrap(), 5);
         assert!(matches!(
-            res,
-            Err(SegmentErrors(SegmentError::IndexErrors(
-                IndexError::IndexEntryNotFound(1)
-            )))
+            log.read(AbsOffset(7)),
+            Err(LogError::RecordCompacted(7))
         ));
-        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+
+        let offsets = |records: Vec<Record>| -> Vec<u64> {
+            records.iter().map(|record| record.offset.unwrap()).collect()
+        };
+        assert_eq!(
+            offsets(log.read_range(AbsOffset(3), AbsOffset(12)).unwrap()),
+            vec![3, 4, 10, 11]
+        );
+        // ranges starting or ending in the gap
+        assert_eq!(
+            offsets(log.read_range(AbsOffset(6), AbsOffset(11)).unwrap()),
+            vec![10]
+        );
+        assert_eq!(
+            offsets(log.read_range(AbsOffset(3), AbsOffset(8)).unwrap()),
+            vec![3, 4]
+        );
+        assert!(log
+            .read_range(AbsOffset(6), AbsOffset(9))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            offsets(log.read_range(AbsOffset(14), AbsOffset(100)).unwrap()),
+            vec![14, 15]
+        );
     }
+
     #[test]
-    fn log_test_init_existing() {
-        use super::IndexError::{self, *};
-        use super::LogError::{IndexErrors, SegmentErrors};
+    fn log_test_no_auto_roll() {
         use super::*;
-        let mut log_dir = PathBuf::new();
-        log_dir.push("log_dir_init_existing");
-        let config = Config {
-            segment: SegmentConfig {
-                max_index_bytes: 1024,
-                max_store_bytes: 100,
-                initial_offset: 0,
-                max_record_size_kb: 400,
-            },
-        };
-        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
-        let record: Record = Record {
-            value: "hello world".as_bytes().to_vec(),
-            offset: None,
-        };
-
-        for i in 0..3 {
-            log.append(record.clone()).unwrap();
+        // fits 4 records per segment
+        let config = ConfigBuilder::new(1024, 130, 0).with_auto_roll(false).build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..4 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
         }
-        assert_eq!(log.lowest_offset().unwrap(), 0);
-        assert_eq!(log.highest_offset().unwrap(), 2);
-
-        log.close(); // apparently, shadowed variables are not dropped, so explicitly close
+        assert!(matches!(log.append_str("hello world4"), Err(LogError::SegmentFull(0))));
+        let set = vec![Record::builder().value("hello world4").build()];
+        assert!(matches!(log.append_set(set), Err(LogError::SegmentFull(0))));
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.len(), 4);
 
-        let mut log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
-        assert_eq!(log.lowest_offset().unwrap(), 0);
-        assert_eq!(log.highest_offset().unwrap(), 2);
-        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+        // sealing still rolls
+        assert_eq!(log.seal().unwrap(), AbsOffset(4));
+        assert_eq!(log.append_str("hello world4").unwrap(), AbsOffset(4));
+        assert_eq!(log.segments.len(), 2);
     }
 
     #[test]
-    fn log_test_read_write_plenty() {
-        use super::IndexError::{self, *};
-        use super::LogError::{IndexErrors, SegmentErrors};
+    fn log_test_first_offset_at_or_after() {
         use super::*;
-        let mut log_dir = PathBuf::new();
-        log_dir.push("log_dir_write_plenty");
-        let config = Config {
-            segment: SegmentConfig {
-                max_index_bytes: 1024,
-                max_store_bytes: 1024, // use a small store size
-                initial_offset: 0,
-                max_record_size_kb: 400
-            },
-        };
-        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 5 keyed records per segment
+            .with_max_segments(3)
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        // the second segment's keys are all written again in the third
+        for key in keys.iter().chain(&keys[5..]).chain(&["k"]) {
+            log.append(Record::builder().key(*key).value(*key).build())
+                .unwrap();
+        }
+        // the first segment was removed by the fourth
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(5)));
+        assert_eq!(
+            log.first_offset_at_or_after(AbsOffset(2)),
+            Some(AbsOffset(5))
+        );
+        assert_eq!(
+            log.first_offset_at_or_after(AbsOffset(7)),
+            Some(AbsOffset(7))
+        );
 
-        for i in 0..30{
-            let record: Record = Record {
-                value: format!("hello world{}", i).into_bytes(),
-                offset: None,
-            };
-            log.append(record).unwrap();
+        // compaction leaves 5 to 9 without records
+        assert_eq!(log.compact_segment(AbsOffset(5)).unwrap(), 5);
+        assert_eq!(log.lowest_offset(), Some(AbsOffset(10)));
+        assert_eq!(
+            log.first_offset_at_or_after(AbsOffset(2)),
+            Some(AbsOffset(10))
+        );
+        assert_eq!(
+            log.first_offset_at_or_after(AbsOffset(7)),
+            Some(AbsOffset(10))
+        );
+        assert_eq!(
+            log.first_offset_at_or_after(AbsOffset(12)),
+            Some(AbsOffset(12))
+        );
+        assert_eq!(
+            log.first_offset_at_or_after(AbsOffset(15)),
+            Some(AbsOffset(15))
+        );
+        assert_eq!(log.first_offset_at_or_after(AbsOffset(16)), None);
+    }
+
+    #[test]
+    fn log_test_reader() {
+        use super::*;
+        use std::io::Read;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        for i in 0..10 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
         }
+        let values: Vec<u8> = (2..8)
+            .flat_map(|i| format!("hello world{}", i).into_bytes())
+            .collect();
 
-        let mut c = ConfigBuilder::new(0, 0, 0);
-        let d = c.with_max_record_size_kb(78);
-        let e = d.build();
+        let mut copied = vec![];
+        std::io::copy(
+            &mut log.reader(AbsOffset(2), AbsOffset(8), ReadBytes::Value),
+            &mut copied,
+        )
+        .unwrap();
+        assert_eq!(copied, values);
 
-        let record = log.read(29).unwrap();
+        // a buffer smaller than a record splits it across reads
+        let mut reader = log.reader(AbsOffset(2), AbsOffset(8), ReadBytes::Value);
+        let (mut read, mut buf) = (vec![], [0; 5]);
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, values);
 
-        assert_eq!(record.offset, Some(29));
-        assert_eq!(String::from_utf8(record.value).unwrap().as_str(), "hello world29");
-        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+        let mut encoded = vec![];
+        std::io::copy(
+            &mut log.reader(AbsOffset(2), AbsOffset(8), ReadBytes::Encoded),
+            &mut encoded,
+        )
+        .unwrap();
+        let mut encoded = encoded.as_slice();
+        for i in 2..8 {
+            let record = Record::decode_length_delimited(&mut encoded).unwrap();
+            assert_eq!(record.offset, Some(i));
+            assert_eq!(record.value, format!("hello world{}", i).into_bytes());
+        }
+        assert!(encoded.is_empty());
+        assert_eq!(
+            log.reader(AbsOffset(10), AbsOffset(20), ReadBytes::Value)
+                .read(&mut buf)
+                .unwrap(),
+            0
+        );
     }
 
     #[test]
-    fn test_create_new_segment() {
-        use super::IndexError::{self, *};
-        use super::LogError::{IndexErrors, SegmentErrors};
+    fn log_test_offset_exhausted() {
         use super::*;
-        let mut log_dir = PathBuf::new();
-        log_dir.push("log_dir_create_new_segment");
-        let config = Config {
-            segment: SegmentConfig {
-                max_index_bytes: 1024,
-                max_store_bytes: 50, // use a small store size of 40 bytes
-                initial_offset: 0,
-                max_record_size_kb: 400,
-            },
-        };
-        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
-        
-        // this record "hello world 1" is serialized into 16 bytes (when the offset is added)
-        // plus the len of the record (8 bytes) totalling 24 bytes
-        let record: Record = Record {
-            value: "hello world1".as_bytes().to_vec(),
-            offset: None,
-        };
-        log.append(record).unwrap(); // this should succeed
-
-         // there should be one segment
-         assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 1); 
-
-        
-        // 9 + 8 = 17
-        // active segment store should be 24 + 17 = 41 bytes, space for 9 bytes left (record of size 1 + 8 bytes for len of record)
-        let record_2 = Record {
-            value: "hello".as_bytes().to_vec(),
-            offset: None,
-        }; 
+        let config = ConfigBuilder::new(1024, 1024, u64::MAX - 1).build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        assert_eq!(
+            log.append_str("hello world0").unwrap(),
+            AbsOffset(u64::MAX - 1)
+        );
+        assert!(matches!(
+            log.append_str("hello world1"),
+            Err(LogError::OffsetExhausted(u64::MAX))
+        ));
+        assert_eq!(log.next_offset(), AbsOffset(u64::MAX));
+        assert_eq!(log.len(), 1);
 
-        log.append(record_2).unwrap(); // this should succeed
+        let config = ConfigBuilder::new(1024, 1024, u64::MAX - 1)
+            .with_batching(10, Duration::from_secs(60))
+            .build();
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        assert_eq!(
+            log.append_str("hello world0").unwrap(),
+            AbsOffset(u64::MAX - 1)
+        );
+        assert!(matches!(
+            log.append_str("hello world1"),
+            Err(LogError::OffsetExhausted(u64::MAX))
+        ));
+        log.flush().unwrap();
+        assert_eq!(
+            log.read_str(AbsOffset(u64::MAX - 1)).unwrap(),
+            "hello world0"
+        );
+    }
 
-         // there should still be one segment
-         assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 1); 
+    #[test]
+    fn log_test_precreate_next_segment() {
+        use super::*;
+        use std::os::unix::fs::MetadataExt;
+        let log_dir = PathBuf::from("log_dir_precreate_next_segment");
+        let config = ConfigBuilder::new(1024, 130, 0) // fits 4 records per segment
+            .with_precreate_next_segment(true)
+            .build();
+        let mut log = Log::new(log_dir.clone(), Some(config.clone())).expect("cannot create log");
 
-        
+        let mut appended = 0;
+        while log.precreated.is_none() {
+            log.append_str(&format!("hello world{}", appended)).unwrap();
+            appended += 1;
+        }
+        assert_eq!(log.segments.len(), 1);
+        while !log.precreated.as_ref().unwrap().is_finished() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let inode = |path: PathBuf| std::fs::metadata(path).unwrap().ino();
+        let precreated = inode(log_dir.join(PRECREATED_DIR).join(".store"));
 
-        // now if we add something more than 1 bytes, it should result in the creation of a new segment as the old one should not be able to carry it
-        // despite there being space
+        // the roll takes over the precreated files instead of creating its own
+        while log.segments.len() == 1 {
+            log.append_str(&format!("hello world{}", appended)).unwrap();
+            appended += 1;
+        }
+        let base_offset = log.segments[1].base_offset;
+        let segment_dir = log_dir.join(base_offset.to_string());
+        assert_eq!(inode(segment_dir.join(".store")), precreated);
+        assert!(!log_dir.join(PRECREATED_DIR).exists());
+        assert_eq!(
+            log.read_str(AbsOffset(base_offset)).unwrap(),
+            format!("hello world{}", base_offset)
+        );
 
-       
-        // 2 + 8 = 10 (greater than the 9 bytes left in segment, should result in creation of a new segment)
-        let record_3 = Record {
-            value: "he".as_bytes().to_vec(),
-            offset: None,
-        }; 
+        drop(log);
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot open log");
+        assert_eq!(log.segments.len(), 2);
+        assert_eq!(log.next_offset(), AbsOffset(appended));
+        assert_eq!(
+            log.read_str(AbsOffset(base_offset)).unwrap(),
+            format!("hello world{}", base_offset)
+        );
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
 
-        log.append(record_3).unwrap(); // this should succeed, but result in the creation of a new segment
+    #[test]
+    fn log_test_checkpoint() {
+        use super::*;
+        let config = ConfigBuilder::new(1024, 130, 0).build(); // fits 4 records per segment
+        let mut log = Log::in_memory(config).expect("cannot create log");
+        let empty = log.checkpoint().unwrap();
+        for i in 0..3 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let checkpoint = log.checkpoint().unwrap();
+        assert_eq!(checkpoint.next_offset, 3);
+        assert_eq!(log.since(&checkpoint).unwrap().count(), 0);
+        for i in 3..6 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
 
-        // there should be 2 segments
-        assert_eq!(std::fs::read_dir(&log_dir).unwrap().count(), 2); 
+        // it survives being kept outside the log
+        let json = serde_json::to_string(&checkpoint).unwrap();
+        let checkpoint: Checkpoint = serde_json::from_str(&json).unwrap();
+        let since: Vec<(AbsOffset, Record)> = log
+            .since(&checkpoint)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(since.len(), 3);
+        for (i, (AbsOffset(offset), record)) in since.into_iter().enumerate() {
+            assert_eq!(offset, 3 + i as u64);
+            assert_eq!(record.value, format!("hello world{}", offset).into_bytes());
+        }
+        assert_eq!(log.since(&empty).unwrap().count(), 6);
 
-        std::fs::remove_dir_all(log_dir);
+        // rewinding past the checkpoint and appending again isn't the same log
+        log.rewind_to(AbsOffset(2)).unwrap();
+        assert!(matches!(log.since(&checkpoint), Err(LogError::CheckpointMismatch(3))));
+        log.append_str("hello again2").unwrap();
+        log.append_str("hello again3").unwrap();
+        assert!(matches!(log.since(&checkpoint), Err(LogError::CheckpointMismatch(3))));
+    }
 
+    #[test]
+    fn log_test_concurrent_appends_get_every_offset_once() {
+        use super::*;
+        use std::sync::Mutex;
+        // small segments, so appends from different threads race across rolls too
+        let log = Arc::new(Mutex::new(Log::in_memory(Config::small()).expect("cannot create log")));
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    (0..100)
+                        .map(|i| {
+                            let value = format!("thread{} record{}", thread, i);
+                            let offset = log.lock().unwrap().append_str(&value).unwrap();
+                            (offset.0, value)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let appended: Vec<(u64, String)> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
 
+        // no offset handed out twice and none skipped
+        let mut offsets: Vec<u64> = appended.iter().map(|(offset, _)| *offset).collect();
+        offsets.sort();
+        assert_eq!(offsets, (0..800).collect::<Vec<u64>>());
+        let log = log.lock().unwrap();
+        assert!(log.segments.len() > 1);
+        for (offset, value) in appended {
+            assert_eq!(log.read_str(AbsOffset(offset)).unwrap(), value);
+        }
     }
 }
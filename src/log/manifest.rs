@@ -0,0 +1,285 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::log::{diff_fields, Config, ConfigDiff};
+use super::segment::SegmentMeta;
+use super::store::STORE_VERSION;
+
+/// Name of the file in a log's directory listing its segments and the config it was created with
+pub const MANIFEST_FILE: &str = "manifest";
+// written first, then renamed over the manifest so it's never seen half written
+const MANIFEST_TMP_FILE: &str = "manifest.tmp";
+/// Name of the file in a log's directory tracking a migration between formats, only there
+/// while one is running or after it was interrupted, see `Log::open_and_migrate`
+pub const MIGRATION_FILE: &str = "migration";
+const MIGRATION_TMP_FILE: &str = "migration.tmp";
+
+/// What's needed to open a log without scanning its directory: the base offset of each of its
+/// segments, along with the parts of its config that decide the format of its files, so
+/// reopening it with an incompatible config can be refused. Everything else (retention,
+/// batching, sync policy...) can change between opens. Sealed segments are described too, so
+/// a lazily opened log doesn't have to open them to count its records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u8,
+    pub max_index_bytes: u64,
+    pub max_store_bytes: u64,
+    pub checksums: bool,
+    pub encrypted: bool,
+    pub index_interval: u32,
+    pub segments: Vec<u64>,
+    // base offset and what's known about each sealed segment as of the last write
+    pub sealed: Vec<(u64, SegmentMeta)>,
+}
+
+impl Manifest {
+    pub fn new(config: &Config, segments: Vec<u64>) -> Self {
+        Self {
+            version: STORE_VERSION,
+            max_index_bytes: config.get_max_index_bytes(),
+            max_store_bytes: config.get_max_store_bytes(),
+            checksums: config.has_checksums(),
+            encrypted: config.get_encryption().is_some(),
+            index_interval: config.get_index_interval(),
+            segments,
+            sealed: vec![],
+        }
+    }
+
+    // the format fields, one `field=value` line each
+    fn fields(&self) -> [(&'static str, String); 6] {
+        [
+            ("version", self.version.to_string()),
+            ("max_index_bytes", self.max_index_bytes.to_string()),
+            ("max_store_bytes", self.max_store_bytes.to_string()),
+            ("checksums", self.checksums.to_string()),
+            ("encrypted", self.encrypted.to_string()),
+            ("index_interval", self.index_interval.to_string()),
+        ]
+    }
+
+    /// Reads the manifest in `dir`, `None` if there's none. A manifest that doesn't match its
+    /// checksum or can't be parsed fails with `io::ErrorKind::InvalidData`
+    pub fn read(dir: &Path) -> io::Result<Option<Self>> {
+        let Some(body) = read_checked(&dir.join(MANIFEST_FILE), "manifest")? else {
+            return Ok(None);
+        };
+
+        let mut manifest = Self {
+            version: 0,
+            max_index_bytes: 0,
+            max_store_bytes: 0,
+            checksums: false,
+            encrypted: false,
+            index_interval: 1,
+            segments: vec![],
+            sealed: vec![],
+        };
+        for (field, value) in lines(&body)? {
+            match field {
+                "version" => manifest.version = parse(value)?,
+                "max_index_bytes" => manifest.max_index_bytes = parse(value)?,
+                "max_store_bytes" => manifest.max_store_bytes = parse(value)?,
+                "checksums" => manifest.checksums = parse(value)?,
+                "encrypted" => manifest.encrypted = parse(value)?,
+                "index_interval" => manifest.index_interval = parse(value)?,
+                "segments" => manifest.segments = split(value)?,
+                "sealed" => manifest.sealed = split_sealed(value)?,
+                // written by a newer version, nothing to check it against
+                _ => {}
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Replaces the manifest in `dir` in one go, syncing it to disk first if `sync`
+    pub fn write(&self, dir: &Path, sync: bool) -> io::Result<()> {
+        let mut body: String = self
+            .fields()
+            .iter()
+            .map(|(field, value)| format!("{}={}\n", field, value))
+            .collect();
+        body.push_str(&format!("segments={}\n", join(&self.segments)));
+        body.push_str(&format!("sealed={}", join_sealed(&self.sealed)));
+        write_checked(dir, MANIFEST_TMP_FILE, MANIFEST_FILE, &body, sync)
+    }
+
+    /// The first format field that differs from `supplied`, with the stored value as the old one
+    pub fn mismatch(&self, supplied: &Manifest) -> Option<ConfigDiff> {
+        diff_fields(&self.fields(), &supplied.fields()).into_iter().next()
+    }
+}
+
+/// How far rewriting a log's segments from one format into another got, so an interrupted
+/// migration carries on where it stopped. Only checksums and the index interval can be
+/// migrated, the rest of the format stays as it was
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    // the format the segments were written in
+    pub from_checksums: bool,
+    pub from_index_interval: u32,
+    // the format they're rewritten in
+    pub checksums: bool,
+    pub index_interval: u32,
+    pub segments: Vec<u64>,
+    // segments whose store has been replaced by a rewritten one
+    pub done: Vec<u64>,
+    // the segment whose rewritten store is complete and about to replace its old one
+    pub current: Option<u64>,
+}
+
+impl Migration {
+    /// Reads the migration in `dir`, `None` if there's none running
+    pub fn read(dir: &Path) -> io::Result<Option<Self>> {
+        let Some(body) = read_checked(&dir.join(MIGRATION_FILE), "migration")? else {
+            return Ok(None);
+        };
+        let mut migration = Self {
+            from_checksums: false,
+            from_index_interval: 1,
+            checksums: false,
+            index_interval: 1,
+            segments: vec![],
+            done: vec![],
+            current: None,
+        };
+        for (field, value) in lines(&body)? {
+            match field {
+                "from_checksums" => migration.from_checksums = parse(value)?,
+                "from_index_interval" => migration.from_index_interval = parse(value)?,
+                "checksums" => migration.checksums = parse(value)?,
+                "index_interval" => migration.index_interval = parse(value)?,
+                "segments" => migration.segments = split(value)?,
+                "done" => migration.done = split(value)?,
+                "current" if !value.is_empty() => migration.current = Some(parse(value)?),
+                _ => {}
+            }
+        }
+        Ok(Some(migration))
+    }
+
+    /// Replaces the migration in `dir` in one go, syncing it first so it's never behind the
+    /// segments it describes
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        let body = format!(
+            "from_checksums={}\nfrom_index_interval={}\nchecksums={}\nindex_interval={}\n\
+             segments={}\ndone={}\ncurrent={}",
+            self.from_checksums,
+            self.from_index_interval,
+            self.checksums,
+            self.index_interval,
+            join(&self.segments),
+            join(&self.done),
+            self.current.map_or(String::new(), |base| base.to_string()),
+        );
+        write_checked(dir, MIGRATION_TMP_FILE, MIGRATION_FILE, &body, true)
+    }
+}
+
+// the body of a file whose last line is a checksum of everything before it,
+// `None` if there's no such file
+fn read_checked(path: &Path, what: &str) -> io::Result<Option<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return Err(invalid(&format!("{} isn't utf-8", what)))
+        }
+        Err(e) => return Err(e),
+    };
+
+    // the checksum line comes last and covers everything before it
+    let body = contents
+        .trim_end_matches('\n')
+        .rsplit_once('\n')
+        .map_or("", |(body, _)| body);
+    let checksum = contents[body.len()..]
+        .trim()
+        .strip_prefix("checksum=")
+        .and_then(|checksum| u32::from_str_radix(checksum, 16).ok())
+        .ok_or_else(|| invalid(&format!("{} has no checksum", what)))?;
+    if checksum != crc32fast::hash(body.as_bytes()) {
+        return Err(invalid(&format!("{} doesn't match its checksum", what)));
+    }
+    Ok(Some(body.to_string()))
+}
+
+// writes body and its checksum to `tmp`, then renames it over `name`
+fn write_checked(dir: &Path, tmp: &str, name: &str, body: &str, sync: bool) -> io::Result<()> {
+    let contents = format!("{}\nchecksum={:08x}\n", body, crc32fast::hash(body.as_bytes()));
+    let tmp = dir.join(tmp);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    if sync {
+        file.sync_all()?;
+    }
+    fs::rename(tmp, dir.join(name))
+}
+
+// the `field=value` lines of a body
+fn lines(body: &str) -> io::Result<Vec<(&str, &str)>> {
+    body.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.split_once('=')
+                .ok_or_else(|| invalid("invalid line"))
+        })
+        .collect()
+}
+
+// a comma separated list of base offsets
+fn split(value: &str) -> io::Result<Vec<u64>> {
+    value.split(',').filter(|base| !base.is_empty()).map(parse).collect()
+}
+
+fn join(bases: &[u64]) -> String {
+    let bases: Vec<String> = bases.iter().map(|base| base.to_string()).collect();
+    bases.join(",")
+}
+
+// a comma separated list of sealed segments, each as
+// `base_offset:next_offset:len:store_size:records_len`
+fn split_sealed(value: &str) -> io::Result<Vec<(u64, SegmentMeta)>> {
+    value
+        .split(',')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            let fields: Vec<u64> = segment.split(':').map(parse).collect::<io::Result<_>>()?;
+            match fields[..] {
+                [base_offset, next_offset, len, store_size, records_len] => Ok((
+                    base_offset,
+                    SegmentMeta {
+                        next_offset,
+                        len,
+                        store_size,
+                        records_len,
+                    },
+                )),
+                _ => Err(invalid("invalid sealed segment")),
+            }
+        })
+        .collect()
+}
+
+fn join_sealed(sealed: &[(u64, SegmentMeta)]) -> String {
+    let sealed: Vec<String> = sealed
+        .iter()
+        .map(|(base, meta)| {
+            format!(
+                "{}:{}:{}:{}:{}",
+                base, meta.next_offset, meta.len, meta.store_size, meta.records_len
+            )
+        })
+        .collect();
+    sealed.join(",")
+}
+
+fn parse<T: FromStr>(value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| invalid("invalid value"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -1,4 +1,13 @@
+pub mod async_log;
+pub mod clock;
+pub mod encryption;
+pub mod health;
 mod index;
 pub mod log;
+mod manifest;
+pub mod offset;
+pub mod offset_store;
+pub mod partitioned;
 mod segment;
 mod store;
+pub mod subscription;
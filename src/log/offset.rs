@@ -0,0 +1,146 @@
+//! Offsets and store positions. The index keeps `RelOffset`s and `StorePos`s, the store reads
+//! and appends at `StorePos`s, and a segment converts the absolute offsets it's given with
+//! `to_relative`/`to_absolute` before they reach its index. `Log` and `Segment` take and
+//! return absolute offsets as `AbsOffset`.
+//!
+//! None of them convert into each other implicitly, so mixing them up doesn't compile:
+//!
+//! ```compile_fail
+//! // an absolute offset where a relative one is expected
+//! to_absolute(AbsOffset(100), AbsOffset(142));
+//! ```
+//!
+//! ```compile_fail
+//! // a raw position, or an offset, where a store position is expected
+//! index.write(RelOffset(0), 10u64);
+//! index.write(RelOffset(0), AbsOffset(10));
+//! ```
+//!
+//! ```compile_fail
+//! // a raw offset where a log offset is expected
+//! log.read(10u64);
+//! ```
+//!
+//! The crate is a binary, so rustdoc doesn't run these; `test::no_implicit_conversions`
+//! checks the same at build time.
+
+use std::ops::{Add, AddAssign};
+
+use crate::proto::record::Record;
+
+/// Picks the offset of each appended record from the offset of the record before it.
+/// Offsets must strictly increase, so `next` has to return more than `prev`
+pub trait OffsetAllocator: Send + Sync {
+    fn next(&self, prev: u64, record: &Record) -> u64;
+}
+
+/// Consecutive offsets, the default
+pub struct SequentialAllocator;
+
+impl OffsetAllocator for SequentialAllocator {
+    fn next(&self, prev: u64, _record: &Record) -> u64 {
+        prev + 1
+    }
+}
+
+/// An offset in the log, as records are appended and read at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct AbsOffset(pub u64);
+
+/// An offset relative to the base offset of the segment holding it, as kept in its index.
+/// Only `to_relative` and `to_absolute` convert between the two, so one can't be passed
+/// where the other is expected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct RelOffset(pub u32);
+
+/// Where a record's frame starts in its segment's store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StorePos(pub u64);
+
+// a position plus a length in bytes, e.g. a frame's size to get to the frame after it.
+// Positions aren't added to each other, nor to offsets
+impl Add<u64> for StorePos {
+    type Output = StorePos;
+
+    fn add(self, len: u64) -> StorePos {
+        StorePos(self.0 + len)
+    }
+}
+
+impl AddAssign<u64> for StorePos {
+    fn add_assign(&mut self, len: u64) {
+        self.0 += len;
+    }
+}
+
+/// `offset` relative to the segment starting at `base_offset`, or None if it's before the
+/// segment or too far past its start for an index entry
+pub fn to_relative(base_offset: AbsOffset, offset: AbsOffset) -> Option<RelOffset> {
+    let relative = offset.0.checked_sub(base_offset.0)?;
+    u32::try_from(relative).ok().map(RelOffset)
+}
+
+/// The offset in the log of `offset`, relative to the segment starting at `base_offset`
+pub fn to_absolute(base_offset: AbsOffset, offset: RelOffset) -> AbsOffset {
+    AbsOffset(base_offset.0 + offset.0 as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offset_conversions() {
+        let base = AbsOffset(100);
+        assert_eq!(to_relative(base, AbsOffset(100)), Some(RelOffset(0)));
+        assert_eq!(to_relative(base, AbsOffset(142)), Some(RelOffset(42)));
+        assert_eq!(to_absolute(base, RelOffset(42)), AbsOffset(142));
+
+        // before the segment, or past what an index entry can hold
+        assert_eq!(to_relative(base, AbsOffset(99)), None);
+        assert_eq!(to_relative(base, AbsOffset(100 + u32::MAX as u64 + 1)), None);
+
+        for offset in [100, 101, 5_000, 100 + u32::MAX as u64] {
+            let relative = to_relative(base, AbsOffset(offset)).unwrap();
+            assert_eq!(to_absolute(base, relative), AbsOffset(offset));
+        }
+    }
+
+    // `LacksFrom`/`LacksAdd` are implemented for every type through `()`, and also through
+    // `Present` for types with the conversion, so naming them without the second parameter is
+    // ambiguous, and doesn't compile, for types that have it
+    struct Present;
+
+    trait LacksFrom<U, A> {
+        fn check() {}
+    }
+    impl<T, U> LacksFrom<U, ()> for T {}
+    impl<T: From<U>, U> LacksFrom<U, Present> for T {}
+
+    trait LacksAdd<U, A> {
+        fn check() {}
+    }
+    impl<T, U> LacksAdd<U, ()> for T {}
+    impl<T: Add<U>, U> LacksAdd<U, Present> for T {}
+
+    #[test]
+    fn no_implicit_conversions() {
+        <AbsOffset as LacksFrom<u64, _>>::check();
+        <AbsOffset as LacksFrom<RelOffset, _>>::check();
+        <AbsOffset as LacksFrom<StorePos, _>>::check();
+        <RelOffset as LacksFrom<u32, _>>::check();
+        <RelOffset as LacksFrom<AbsOffset, _>>::check();
+        <StorePos as LacksFrom<u64, _>>::check();
+        <StorePos as LacksFrom<AbsOffset, _>>::check();
+        <u64 as LacksFrom<AbsOffset, _>>::check();
+        <u64 as LacksFrom<StorePos, _>>::check();
+
+        // positions only move forward by a length
+        <StorePos as LacksAdd<StorePos, _>>::check();
+        <StorePos as LacksAdd<AbsOffset, _>>::check();
+        assert_eq!(StorePos(10) + 5, StorePos(15));
+        let mut position = StorePos(10);
+        position += 5;
+        assert_eq!(position, StorePos(15));
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the file in a log's directory holding the offsets committed by consumer groups
+pub const OFFSETS_FILE: &str = "consumer_offsets";
+// written first, then renamed over the offsets file so it's never seen half written
+const OFFSETS_TMP_FILE: &str = "consumer_offsets.tmp";
+
+/// The offset each consumer group has read up to, so a restarted consumer resumes from its
+/// last commit rather than the start of the log. Every commit rewrites the whole file and
+/// syncs it before renaming it into place, so a crash leaves either the old or new offsets
+pub struct OffsetStore {
+    dir: Option<PathBuf>, // None when the log is in memory
+    committed: BTreeMap<String, u64>,
+}
+
+impl OffsetStore {
+    /// Opens the offsets committed in `dir`, usually the log's directory. A file that doesn't
+    /// match its checksum or can't be parsed fails with `io::ErrorKind::InvalidData`
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let committed = match fs::read_to_string(dir.join(OFFSETS_FILE)) {
+            Ok(contents) => parse(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(invalid("consumer offsets aren't utf-8"))
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            dir: Some(dir.to_path_buf()),
+            committed,
+        })
+    }
+
+    /// Offsets kept only in memory, for in memory logs
+    pub fn in_memory() -> Self {
+        Self {
+            dir: None,
+            committed: BTreeMap::new(),
+        }
+    }
+
+    /// Records that `group` has read up to `offset`, writing it out before returning.
+    /// Group names can't contain a newline
+    pub fn commit(&mut self, group: &str, offset: u64) -> io::Result<()> {
+        if group.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "consumer group names can't contain a newline",
+            ));
+        }
+        let previous = self.committed.insert(group.to_string(), offset);
+        if let Err(e) = self.write() {
+            // keep what's in memory in line with what's on disk
+            match previous {
+                Some(previous) => self.committed.insert(group.to_string(), previous),
+                None => self.committed.remove(group),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The offset `group` last committed, if it has
+    pub fn committed(&self, group: &str) -> Option<u64> {
+        self.committed.get(group).copied()
+    }
+
+    // replaces the offsets file in one go
+    fn write(&self) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let body: String = self
+            .committed
+            .iter()
+            .map(|(group, offset)| format!("{}\t{}\n", offset, group))
+            .collect();
+        let contents = format!("{}checksum={:08x}\n", body, crc32fast::hash(body.as_bytes()));
+
+        let tmp = dir.join(OFFSETS_TMP_FILE);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, dir.join(OFFSETS_FILE))?;
+        // persist the rename too
+        fs::File::open(dir)?.sync_all()
+    }
+}
+
+// one `offset\tgroup` line per group, then a checksum of them all
+fn parse(contents: &str) -> io::Result<BTreeMap<String, u64>> {
+    let (body, checksum) = contents
+        .trim_end_matches('\n')
+        .rsplit_once("checksum=")
+        .ok_or_else(|| invalid("consumer offsets have no checksum"))?;
+    let checksum = u32::from_str_radix(checksum, 16)
+        .map_err(|_| invalid("consumer offsets have no checksum"))?;
+    if checksum != crc32fast::hash(body.as_bytes()) {
+        return Err(invalid("consumer offsets don't match their checksum"));
+    }
+
+    body.lines()
+        .map(|line| {
+            let (offset, group) = line
+                .split_once('\t')
+                .ok_or_else(|| invalid("invalid consumer offsets line"))?;
+            let offset = offset
+                .parse()
+                .map_err(|_| invalid("invalid consumer offset"))?;
+            Ok((group.to_string(), offset))
+        })
+        .collect()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offset_store_commit_reopen() {
+        let dir = PathBuf::from("offset_store_commit_reopen");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut offsets = OffsetStore::open(&dir).unwrap();
+        assert_eq!(offsets.committed("billing"), None);
+        offsets.commit("billing", 3).unwrap();
+        offsets.commit("search", 10).unwrap();
+        offsets.commit("billing", 7).unwrap();
+        assert!(offsets.commit("two\nlines", 1).is_err());
+        drop(offsets);
+
+        let offsets = OffsetStore::open(&dir).unwrap();
+        assert_eq!(offsets.committed("billing"), Some(7));
+        assert_eq!(offsets.committed("search"), Some(10));
+        assert_eq!(offsets.committed("two\nlines"), None);
+
+        // a damaged file isn't mistaken for offsets
+        let path = dir.join(OFFSETS_FILE);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replace("7\tbilling", "8\tbilling")).unwrap();
+        let err = OffsetStore::open(&dir).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::log::{Config, Log, LogError, LogIterator};
+use super::offset::AbsOffset;
+use crate::proto::record::Record;
+
+/// Name of the file in a partitioned log's directory holding its number of partitions
+pub const PARTITIONS_FILE: &str = "partitions";
+// written first, then renamed over the partitions file so it's never seen half written
+const PARTITIONS_TMP_FILE: &str = "partitions.tmp";
+
+/// A set of logs, one per partition, each in its own directory with its own offsets.
+/// Records are routed to a partition by a hash of their key, so records with the same key
+/// always land in the same partition, in order
+pub struct PartitionedLog {
+    partitions: Vec<Log>,
+}
+
+impl PartitionedLog {
+    /// Opens the partitioned log in dir, creating it if it doesn't exist. The number of
+    /// partitions is kept in the directory, since keys are routed by it, and opening with
+    /// a different number fails with `LogError::ConfigMismatch`
+    pub fn new(dir: PathBuf, partitions: usize, config: Option<Config>) -> Result<Self, LogError> {
+        if !dir.exists() {
+            std::fs::create_dir(&dir)?;
+        }
+        let partitions = partitions.max(1);
+        check_partitions(&dir, partitions)?;
+        let partitions = (0..partitions)
+            .map(|i| Log::new(dir.join(i.to_string()), config.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { partitions })
+    }
+
+    pub fn in_memory(partitions: usize, config: Config) -> Result<Self, LogError> {
+        let partitions = (0..partitions.max(1))
+            .map(|_| Log::in_memory(config.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { partitions })
+    }
+
+    pub fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    // crc32 rather than std's hasher, which isn't guaranteed to be stable across releases
+    pub fn partition_for(&self, key: &[u8]) -> usize {
+        crc32fast::hash(key) as usize % self.partitions.len()
+    }
+
+    /// Appends value under key to the key's partition, returning the partition and the offset
+    /// of the record in it
+    pub fn append(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(usize, AbsOffset), LogError> {
+        let key = key.into();
+        let partition = self.partition_for(&key);
+        let record = Record::builder().key(key).value(value).build();
+        let offset = self.partitions[partition].append(record)?;
+        Ok((partition, offset))
+    }
+
+    pub fn read(&self, partition: usize, offset: AbsOffset) -> Result<Record, LogError> {
+        self.partition(partition)?.read(offset)
+    }
+
+    pub fn iter_from(
+        &self,
+        partition: usize,
+        start: AbsOffset,
+    ) -> Result<LogIterator<'_>, LogError> {
+        Ok(self.partition(partition)?.iter_from(start))
+    }
+
+    pub fn partition(&self, partition: usize) -> Result<&Log, LogError> {
+        self.partitions
+            .get(partition)
+            .ok_or(LogError::PartitionNotFound(partition))
+    }
+}
+
+// refuses a number of partitions other than the one the log in dir was created with,
+// writing it down if the log is new
+fn check_partitions(dir: &Path, partitions: usize) -> Result<(), LogError> {
+    match std::fs::read_to_string(dir.join(PARTITIONS_FILE)) {
+        Ok(stored) if stored.trim() == partitions.to_string() => Ok(()),
+        Ok(stored) => Err(LogError::ConfigMismatch {
+            field: "partitions".to_string(),
+            stored: stored.trim().to_string(),
+            supplied: partitions.to_string(),
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let tmp = dir.join(PARTITIONS_TMP_FILE);
+            std::fs::write(&tmp, partitions.to_string())?;
+            std::fs::File::open(&tmp)?.sync_all()?;
+            std::fs::rename(&tmp, dir.join(PARTITIONS_FILE))?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partitioned_log_routes_by_key() {
+        let dir = PathBuf::from("log_dir_partitioned");
+        let mut log = PartitionedLog::new(dir.clone(), 4, Some(Config::small())).unwrap();
+        assert_eq!(log.partitions(), 4);
+
+        let mut expected: Vec<Vec<(AbsOffset, String)>> = vec![vec![]; 4];
+        for i in 0..40 {
+            let key = format!("user-{}", i % 10);
+            let (partition, offset) = log.append(key.clone(), format!("value{}", i)).unwrap();
+            assert_eq!(partition, log.partition_for(key.as_bytes()));
+            // offsets are per partition
+            assert_eq!(offset, AbsOffset(expected[partition].len() as u64));
+            expected[partition].push((offset, format!("value{}", i)));
+        }
+        assert!(
+            expected
+                .iter()
+                .filter(|records| !records.is_empty())
+                .count()
+                > 1
+        );
+
+        for (partition, records) in expected.iter().enumerate() {
+            for (offset, value) in records {
+                assert_eq!(
+                    log.read(partition, *offset).unwrap().value,
+                    value.as_bytes()
+                );
+            }
+            let values: Vec<Vec<u8>> = log
+                .iter_from(partition, AbsOffset(0))
+                .unwrap()
+                .map(|r| r.unwrap().value)
+                .collect();
+            assert_eq!(values.len(), records.len());
+        }
+        assert!(matches!(
+            log.read(4, AbsOffset(0)),
+            Err(LogError::PartitionNotFound(4))
+        ));
+        drop(log);
+
+        // keys would be routed to other partitions
+        assert!(matches!(
+            PartitionedLog::new(dir.clone(), 8, Some(Config::small())),
+            Err(LogError::ConfigMismatch { field, .. }) if field == "partitions"
+        ));
+        let log = PartitionedLog::new(dir.clone(), 4, Some(Config::small())).unwrap();
+        assert_eq!(
+            log.read(0, AbsOffset(0)).unwrap().value,
+            expected[0][0].1.as_bytes()
+        );
+
+        drop(log);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
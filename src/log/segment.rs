@@ -12,12 +12,24 @@ use std::{
 };
 use thiserror::Error;
 
+use super::clock::{Clock, SystemClock};
 use super::index::{Index, IndexError};
-use super::log::Config;
+use super::log::{Config, INDEX_ENTRY_LENGTH};
+use super::offset::{to_absolute, to_relative, AbsOffset, RelOffset, StorePos};
 use super::store::{Store, StoreError};
 use crate::proto::{self, record::Record};
 use std::io;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// values at least this long are written to the store from the record as is, see `append_ref`
+const UNCOPIED_VALUE_LEN: usize = 64 * 1024;
+// compacted copies of a segment's files are written next to them with this extension,
+// followed by the generation they were written from and a count, see `compaction_paths`
+const COMPACTION_EXTENSION: &str = "compact";
 
 #[derive(Error, Debug)]
 pub enum SegmentError {
@@ -25,7 +37,25 @@ pub enum SegmentError {
     SegmentPathNotADirectory(PathBuf),
 
     #[error("store full")]
-    StoreFull(Record),
+    StoreFull(Box<Record>),
+
+    #[error("Expected offset {expected} but got {got}")]
+    OffsetMismatch { expected: u64, got: u64 },
+
+    #[error("Index and store of segment {0} don't agree on where it ends")]
+    Inconsistent(u64),
+
+    // the record would take the last offset there is, leaving none for the next one
+    #[error("No offsets left after {0}")]
+    OffsetExhausted(u64),
+
+    // no record at the absolute offset, e.g. it's before the segment or was compacted away
+    #[error("No record at offset {0} in the segment")]
+    OffsetNotInSegment(u64),
+
+    // a long scan was asked to stop partway through
+    #[error("Cancelled")]
+    Cancelled,
 
     #[error(transparent)]
     IndexErrors(#[from] IndexError),
@@ -49,97 +79,1035 @@ pub struct Segment {
     pub base_offset: u64,
     pub next_offset: u64,
     pub config: Arc<Config>,
+    pub clock: Arc<dyn Clock>, // timestamps appended records
+    pub generation: u64,       // bumped every time the segment's files are replaced
+    // records from the last indexed one on, only more than 1 with a sparse index
+    since_indexed: u64,
+    compactions: AtomicU64, // compacted copies started, each one's files are named after it
+}
+
+/// A compacted copy of a segment, written by `Segment::write_compacted`
+pub struct CompactedSegment {
+    pub base_offset: u64,
+    // generation of the segment it was written from
+    pub generation: u64,
+    store: Store,
+    index: Index,
+    removed: u64,
+}
+
+impl CompactedSegment {
+    /// Removes the copy's files, for a copy that won't be swapped in
+    pub fn discard(self) {
+        if !self.store.is_in_memory() {
+            let _ = std::fs::remove_file(&self.store.path);
+            let _ = std::fs::remove_file(&self.index.path);
+        }
+    }
 }
 
 impl Segment {
     pub fn new(
         dir: PathBuf,
-        base_offset: u64,
+        AbsOffset(base_offset): AbsOffset,
         config: Arc<Config>,
     ) -> Result<Segment, SegmentError> {
         if (!dir.is_dir()) {
             return Err(SegmentError::SegmentPathNotADirectory(dir));
         }
+        remove_compaction_leftovers(&dir);
 
-        let store = Store::new(dir.join(".store"), config.clone());
-        let index = Index::new(dir.join(".index"), config.clone());
+        let store = Store::new(dir.join(".store"), config.clone())?;
+        let index = Index::new(dir.join(".index"), config.clone())?;
+        // an unclosed index whose only entry is at position 0, in a store without a version,
+        // reads as empty, see `written_len`
+        let unindexed = index.size == 0 && store.records_start().0 == 0 && store.size > 0;
         let next_offset = index
             .read_last_entry()
-            .map(|e| e.record_offset as u64 + 1)
+            .map(|e| to_absolute(AbsOffset(base_offset), e.record_offset).0 + 1)
             .unwrap_or(base_offset);
 
-        Ok(Segment {
+        let mut segment = Segment {
             store,
             index,
             base_offset,
             next_offset,
             config,
-        })
+            clock: Arc::new(SystemClock),
+            generation: 0,
+            since_indexed: 0,
+            compactions: AtomicU64::new(0),
+        };
+        segment.count_since_indexed();
+        if unindexed {
+            segment.recover()?;
+        } else if segment.config.is_verify_on_open() && !segment.is_consistent() {
+            if segment.config.is_strict_recovery() {
+                return Err(SegmentError::Inconsistent(base_offset));
+            }
+            segment.recover()?;
+        }
+        Ok(segment)
         //todo!()
     }
 
-    pub fn append(&mut self, mut record: proto::record::Record) -> Result<u64, SegmentError> {
-        let record_offset = self.next_offset;
+    pub fn in_memory(AbsOffset(base_offset): AbsOffset, config: Arc<Config>) -> Segment {
+        Segment {
+            store: Store::in_memory(config.clone()),
+            index: Index::in_memory(config.clone()),
+            base_offset,
+            next_offset: base_offset,
+            config,
+            clock: Arc::new(SystemClock),
+            generation: 0,
+            since_indexed: 0,
+            compactions: AtomicU64::new(0),
+        }
+    }
+
+    // the offset record would get if it was appended next
+    pub fn next_offset_for(&self, record: &Record) -> AbsOffset {
+        if self.next_offset == self.base_offset
+            && self.base_offset == self.config.get_initial_offset()
+        {
+            // the very first record in the log
+            AbsOffset(self.base_offset)
+        } else {
+            // the previous record is the last one in this segment, or in the segment before it
+            // when this one is empty, since segments start right after the last offset
+            AbsOffset(
+                self.config
+                    .get_offset_allocator()
+                    .next(self.next_offset - 1, record),
+            )
+        }
+    }
+
+    pub fn append(&mut self, record: proto::record::Record) -> Result<AbsOffset, SegmentError> {
+        match self.append_ref(&record) {
+            // hand the record back as it came, so the segment it's retried on gives it an offset
+            Err(SegmentError::StoreErrors(StoreError::StoreFullError)) => {
+                Err(SegmentError::StoreFull(Box::new(record)))
+            }
+            result => result,
+        }
+    }
+
+    /// Appends a borrowed record, adding the offset and timestamp it's given to its encoding
+    /// rather than to the record. Fails with `StoreError::StoreFullError` if it doesn't fit
+    pub fn append_ref(&mut self, record: &Record) -> Result<AbsOffset, SegmentError> {
+        let record_offset = self.next_offset_for(record);
+        self.append_at(record, record_offset)
+    }
+
+    /// Appends a borrowed record like `append_ref`, at an offset the caller already got from
+    /// `next_offset_for`, so the offset allocator isn't asked twice for the same record
+    pub fn append_at(
+        &mut self,
+        record: &Record,
+        AbsOffset(record_offset): AbsOffset,
+    ) -> Result<AbsOffset, SegmentError> {
+        if record_offset < self.next_offset {
+            return Err(SegmentError::OffsetMismatch {
+                expected: self.next_offset,
+                got: record_offset,
+            });
+        }
+        let next_offset = offset_after(record_offset)?;
+
+        // fields encoded after a message are merged into it, so the ones the segment assigns
+        // are encoded on their own and tacked on the end
+        let assigned = Record {
+            offset: record.offset.is_none().then_some(record_offset),
+            timestamp: record.timestamp.is_none().then(|| self.clock.now_millis()),
+            ..Default::default()
+        };
+        // a large value is written to the store straight from the record, rather than copied
+        // into its encoding along with the rest of it
+        let (head, value) = if record.value.len() >= UNCOPIED_VALUE_LEN {
+            (record.encode_without_value(), record.value.as_slice())
+        } else {
+            (record.encode_to_vec(), &[][..])
+        };
+        let assigned = assigned.encode_to_vec();
+        let parts = [head.as_slice(), value, assigned.as_slice()];
+        let encoded_len = head.len() + value.len() + assigned.len();
+
+        // check before writing to the store so a full index doesn't leave an unindexed record behind
+        let indexed = self.indexes_next();
+        if indexed && self.index.is_full() {
+            return Err(SegmentError::IndexErrors(IndexError::IndexFullError));
+        }
+        // index offset is always relative to the base offset
+        let index_offset = self.index_offset(record_offset)?;
 
-        if record.offset.is_none() {
-            record.offset = Some(record_offset);
+        if !self.store.can_store_record(encoded_len) {
+            return Err(SegmentError::StoreErrors(StoreError::StoreFullError));
         }
 
-        let mut record_buf: Vec<u8> = vec![];
+        let written = self.store.append_parts(&parts)?;
+        self.write_index(indexed, index_offset, written.position)?;
+
+        self.next_offset = next_offset;
 
-        record.encode(&mut record_buf)?;
+        Ok(AbsOffset(record_offset))
+    }
 
+    /// Appends records as one record set: a single frame in the store, so they share its length
+    /// prefix and checksum. Each record still gets its own offset, timestamp and index entry,
+    /// the entries all pointing at the set. Returns their offsets. Either every record is
+    /// appended or none are, failing with `StoreError::StoreFullError` if the set doesn't fit
+    pub fn append_set(&mut self, records: &[Record]) -> Result<Vec<AbsOffset>, SegmentError> {
+        let Some(first) = records.first() else {
+            return Ok(vec![]);
+        };
+        let AbsOffset(first_offset) = self.next_offset_for(first);
+        if first_offset < self.next_offset {
+            return Err(SegmentError::OffsetMismatch {
+                expected: self.next_offset,
+                got: first_offset,
+            });
+        }
 
+        // each record keeps its offset relative to the first one
+        let now = self.clock.now_millis();
+        let mut offsets = Vec::with_capacity(records.len());
+        let mut set = Vec::with_capacity(records.len());
+        for record in records {
+            let offset = match offsets.last() {
+                Some(&prev) => self.config.get_offset_allocator().next(prev, record),
+                None => first_offset,
+            };
+            let mut record = record.clone();
+            record.offset = Some(offset - first_offset);
+            record.timestamp.get_or_insert(now);
+            offsets.push(offset);
+            set.push(record);
+        }
+        let next_offset = offset_after(offsets[offsets.len() - 1])?;
+        let frame = Record {
+            offset: Some(first_offset),
+            set,
+            ..Default::default()
+        };
 
-        if !self.store.can_store_record(record_buf.len()) {
-            return Err(SegmentError::StoreFull(record));
+        if !self.index.has_room_for(self.entries_for(records.len() as u64)) {
+            return Err(SegmentError::IndexErrors(IndexError::IndexFullError));
         }
+        let index_offsets = offsets
+            .iter()
+            .map(|&offset| self.index_offset(offset))
+            .collect::<Result<Vec<RelOffset>, IndexError>>()?;
 
-        let (total_written, position) = self.store.append(record_buf)?;
+        let encoded = frame.encode_to_vec();
+        if !self.store.can_store_record(encoded.len()) {
+            return Err(SegmentError::StoreErrors(StoreError::StoreFullError));
+        }
 
-        // index offset is always relative to the base offset
-        let index_offset = record_offset - self.base_offset;
-        self.index.write(index_offset as u32, position as u64)?;
+        let written = self.store.append_framed(encoded)?;
+        for index_offset in index_offsets {
+            self.write_index(self.indexes_next(), index_offset, written.position)?;
+        }
+
+        self.next_offset = next_offset;
+        Ok(offsets.into_iter().map(AbsOffset).collect())
+    }
+
+    /// Appends a record that has already been encoded (e.g. by a leader) as is.
+    /// `offset` must be the segment's next offset
+    pub fn append_encoded(
+        &mut self,
+        AbsOffset(offset): AbsOffset,
+        encoded: &[u8],
+    ) -> Result<AbsOffset, SegmentError> {
+        if offset != self.next_offset {
+            return Err(SegmentError::OffsetMismatch {
+                expected: self.next_offset,
+                got: offset,
+            });
+        }
+        let next_offset = offset_after(offset)?;
+
+        let indexed = self.indexes_next();
+        if indexed && self.index.is_full() {
+            return Err(SegmentError::IndexErrors(IndexError::IndexFullError));
+        }
+        let index_offset = self.index_offset(offset)?;
+
+        if !self.store.can_store_record(encoded.len()) {
+            return Err(SegmentError::StoreErrors(StoreError::StoreFullError));
+        }
+
+        let written = self.store.append_framed(encoded.to_vec())?;
+        self.write_index(indexed, index_offset, written.position)?;
+
+        self.next_offset = next_offset;
+
+        Ok(AbsOffset(offset))
+    }
+
+    pub fn read(&self, AbsOffset(offset): AbsOffset) -> Result<Record, SegmentError> {
+        let (frame, position) = self.read_frame(offset)?;
+        let frame: Record = prost::Message::decode(&frame[..])?;
+        unpack_one(frame, offset).ok_or(SegmentError::StoreErrors(
+            StoreError::StoreEntryNotFound(position.0),
+        ))
+    }
 
-        self.next_offset += 1;
+    // reads the record at offset without decoding it
+    pub fn read_encoded(&self, offset: AbsOffset) -> Result<Vec<u8>, SegmentError> {
+        let (record, _) = self.read_encoded_with_position(offset)?;
+        Ok(record)
+    }
+
+    // reads the record at offset without decoding it, along with its position in the store.
+    // A record in a record set is encoded on its own, with its offset made absolute
+    pub fn read_encoded_with_position(
+        &self,
+        AbsOffset(offset): AbsOffset,
+    ) -> Result<(Vec<u8>, StorePos), SegmentError> {
+        let (frame, position) = self.read_frame(offset)?;
+        let decoded: Record = prost::Message::decode(&frame[..])?;
+        if decoded.set.is_empty() {
+            return Ok((frame, position));
+        }
+        match unpack_one(decoded, offset) {
+            Some(record) => Ok((record.encode_to_vec(), position)),
+            None => Err(SegmentError::StoreErrors(StoreError::StoreEntryNotFound(
+                position.0,
+            ))),
+        }
+    }
 
-        Ok(record_offset)
+    // reads the frame holding the record at offset, along with its position in the store
+    fn read_frame(&self, offset: u64) -> Result<(Vec<u8>, StorePos), SegmentError> {
+        let position = self.position_of(AbsOffset(offset))?;
+        match self.store.read(position) {
+            Ok(record) => Ok((record, position)),
+            Err(e @ (StoreError::DecryptionFailed(_) | StoreError::IOError(_))) => {
+                Err(SegmentError::StoreErrors(e))
+            }
+            Err(_) => Err(SegmentError::StoreErrors(StoreError::StoreEntryNotFound(
+                position.0,
+            ))),
+        }
     }
 
-    pub fn read(&self, offset: u64) -> Result<Record, SegmentError> {
+    // the position of the record at offset in the store
+    pub fn position_of(&self, AbsOffset(offset): AbsOffset) -> Result<StorePos, SegmentError> {
         // _, pos, err := s.index.Read(int64(off - s.baseOffset))
 
-        let pos: u64 = offset - self.base_offset;
-        if let Some(entry) = self.index.read(pos) {
-            if let Ok(record) = self.store.read(entry.position) {
-                let record: Record = prost::Message::decode(&record[..])?;
-                return Ok(record);
+        // offsets before the segment aren't in it, e.g. after older segments were removed
+        if self.relative(offset).is_none() {
+            return Err(SegmentError::OffsetNotInSegment(offset));
+        }
+        match self.locate(offset)? {
+            Some((found, position)) if found == offset => Ok(position),
+            _ => Err(SegmentError::OffsetNotInSegment(offset)),
+        }
+    }
+
+    // the offset and store position of the first record that's offset or after it. Records
+    // a sparse index doesn't point at are found by walking the store from the one before them
+    fn locate(&self, offset: u64) -> Result<Option<(u64, StorePos)>, SegmentError> {
+        let rel = match self.relative(offset) {
+            Some(rel) => rel,
+            // before the segment, so its first record is the first one after offset
+            None if offset < self.base_offset => RelOffset(0),
+            // past anything an index entry can point at
+            None => return Ok(None),
+        };
+        if let Some((_, entry)) = self.index.find(rel) {
+            return Ok(Some((self.absolute(entry.record_offset), entry.position)));
+        }
+        if !self.is_sparse() {
+            return Ok(self
+                .index
+                .seek(rel)
+                .map(|(_, entry)| (self.absolute(entry.record_offset), entry.position)));
+        }
+
+        // the first record is always indexed, so there's nothing before it to walk from
+        let Some((_, from)) = self
+            .index
+            .floor(rel)
+            .or_else(|| self.index.find(RelOffset(0)))
+        else {
+            return Ok(None);
+        };
+        self.walk(self.absolute(from.record_offset), from.position)
+            .find(|record| record.as_ref().map_or(true, |(found, _, _)| *found >= offset))
+            .transpose()
+            .map(|found| found.map(|(found, position, _)| (found, position)))
+    }
+
+    // walks the store from position, where the record at offset starts, yielding every record
+    // from there on with its offset and position. Records in a record set share its position,
+    // and those before offset are skipped. Stops after the first error
+    fn walk(
+        &self,
+        mut offset: u64,
+        mut position: StorePos,
+    ) -> impl Iterator<Item = Result<(u64, StorePos, Record), SegmentError>> + '_ {
+        let start = offset;
+        let mut done = false;
+        let mut pending = VecDeque::new();
+        std::iter::from_fn(move || loop {
+            if let Some(item) = pending.pop_front() {
+                return Some(Ok(item));
+            }
+            if done || position.0 >= self.store.size as u64 {
+                return None;
+            }
+            let frame = self
+                .store
+                .read_framed(position)
+                .map_err(SegmentError::from)
+                .and_then(|(payload, framed_len)| {
+                    let frame: Record = prost::Message::decode(&payload[..])?;
+                    Ok((frame, framed_len))
+                });
+            match frame {
+                Ok((frame, framed_len)) => {
+                    for record in unpack(frame) {
+                        let found = record.offset.unwrap_or(offset);
+                        offset = found + 1;
+                        if found >= start {
+                            pending.push_back((found, position, record));
+                        }
+                    }
+                    position += framed_len;
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            }
+        })
+    }
+
+    // offset relative to the segment, as its index keeps it. None if it's before the segment
+    // or too far past its start
+    fn relative(&self, offset: u64) -> Option<RelOffset> {
+        to_relative(AbsOffset(self.base_offset), AbsOffset(offset))
+    }
+
+    // the offset in the log of an offset relative to the segment
+    fn absolute(&self, offset: RelOffset) -> u64 {
+        to_absolute(AbsOffset(self.base_offset), offset).0
+    }
+
+    // the index offset a record appended at offset gets, a segment whose offsets have run
+    // past what an index entry can hold is full
+    fn index_offset(&self, offset: u64) -> Result<RelOffset, IndexError> {
+        self.relative(offset).ok_or(IndexError::IndexFullError)
+    }
+
+    // whether only some records get an index entry
+    fn is_sparse(&self) -> bool {
+        self.config.get_index_interval() > 1
+    }
+
+    // whether the next record appended gets an index entry
+    fn indexes_next(&self) -> bool {
+        self.index.size == 0 || self.since_indexed >= self.config.get_index_interval() as u64
+    }
+
+    // how many index entries appending `records` more records adds
+    fn entries_for(&self, records: u64) -> u64 {
+        let interval = self.config.get_index_interval() as u64;
+        // an empty index always indexes the next record
+        let mut since_indexed = if self.index.size == 0 { interval } else { self.since_indexed };
+        let mut entries = 0;
+        for _ in 0..records {
+            if since_indexed >= interval {
+                entries += 1;
+                since_indexed = 1;
             } else {
-                return Err(SegmentError::StoreErrors(StoreError::StoreEntryNotFound(
-                    entry.position,
-                )));
+                since_indexed += 1;
             }
+        }
+        entries
+    }
+
+    // indexes an appended record if `indexed`, keeping count of the records since the last one
+    fn write_index(
+        &mut self,
+        indexed: bool,
+        offset: RelOffset,
+        position: StorePos,
+    ) -> Result<(), IndexError> {
+        if indexed {
+            self.index.write(offset, position)?;
+            self.since_indexed = 1;
         } else {
-            return Err(SegmentError::IndexErrors(IndexError::IndexEntryNotFound(
-                pos as u32,
-            )));
+            self.since_indexed += 1;
+        }
+        Ok(())
+    }
+
+    // recounts the records from the last indexed one on, e.g. after the store was replaced.
+    // A sparse index also doesn't know the last offset, so next_offset is found by walking
+    fn count_since_indexed(&mut self) {
+        let Some(last) = self.index.read_last_entry() else {
+            self.since_indexed = 0;
+            return;
+        };
+        if !self.is_sparse() {
+            self.since_indexed = 1;
+            return;
+        }
+        // a torn record at the end of the store isn't counted
+        let records: Vec<u64> = self
+            .walk(self.absolute(last.record_offset), last.position)
+            .map_while(Result::ok)
+            .map(|(offset, _, _)| offset)
+            .collect();
+        self.since_indexed = records.len() as u64;
+        if let Some(last) = records.last() {
+            self.next_offset = self.next_offset.max(last + 1);
+        }
+    }
+
+    /// Reads up to `count` consecutive records starting at `offset`, finding where they start
+    /// with one index lookup and reading them all with a single store read, instead of a lookup
+    /// and read per record. Stops at the end of the segment
+    pub fn read_many(
+        &self,
+        AbsOffset(offset): AbsOffset,
+        count: usize,
+    ) -> Result<Vec<Record>, SegmentError> {
+        let rel = self
+            .relative(offset)
+            .ok_or(SegmentError::OffsetNotInSegment(offset))?;
+        if self.is_sparse() {
+            // the index can't say where the batch ends, so walk it record by record
+            let (first, position) = self
+                .locate(offset)?
+                .ok_or(SegmentError::OffsetNotInSegment(offset))?;
+            return self
+                .walk(first, position)
+                .take(count)
+                .map(|record| record.map(|(_, _, record)| record))
+                .collect();
+        }
+        let (n, first) = self
+            .index
+            .seek(rel)
+            .ok_or(SegmentError::OffsetNotInSegment(offset))?;
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let first_offset = self.absolute(first.record_offset);
+        let first = first.position;
+
+        // the batch ends where the record after it starts, or at the end of the store. Records
+        // in a record set share its position, so that's the first one past the last's position
+        let end = match self.index.read(n + count as u64 - 1) {
+            Some(last) => (n + count as u64..)
+                .map_while(|i| self.index.read(i))
+                .map(|entry| entry.position.0)
+                .find(|&position| position > last.position.0)
+                .unwrap_or(self.store.size as u64),
+            None => self.store.size as u64,
+        };
+
+        let bytes = self.store.read_bytes(first.0, (end - first.0) as usize)?;
+
+        let mut records = vec![];
+        let mut start = 0;
+        while start < bytes.len() {
+            let (payload, framed_len) = self.store.unframe(&bytes[start..], first + start as u64)?;
+            let frame: Record = prost::Message::decode(&payload[..])?;
+            // the batch can start part way through a record set
+            records.extend(
+                unpack(frame)
+                    .into_iter()
+                    .filter(|record| record.offset.is_none_or(|offset| offset >= first_offset)),
+            );
+            start += framed_len as usize;
+        }
+        records.truncate(count);
+        Ok(records)
+    }
+
+    /// Rebuilds the index from the records in the store, e.g. when the index file was lost.
+    /// Returns the number of entries written
+    pub fn rebuild_index(&mut self) -> Result<u64, SegmentError> {
+        self.rebuild_index_with(&mut || true)
+    }
+
+    /// `rebuild_index`, calling `tick` after each record read. The whole store is read before
+    /// the index is touched, so `tick` returning false leaves the segment as it was and fails
+    /// with `SegmentError::Cancelled`
+    pub fn rebuild_index_with(
+        &mut self,
+        tick: &mut dyn FnMut() -> bool,
+    ) -> Result<u64, SegmentError> {
+        let interval = self.config.get_index_interval() as u64;
+        let mut entries = vec![];
+        let mut since_indexed = 0;
+        let mut next_offset = self.base_offset;
+
+        for entry in self.store.iter() {
+            let (position, frame) = entry?;
+            let frame: Record = prost::Message::decode(&frame[..])?;
+            for record in unpack(frame) {
+                let offset = record.offset.unwrap_or(next_offset);
+
+                if entries.is_empty() || since_indexed >= interval {
+                    entries.push((self.index_offset(offset)?, position));
+                    since_indexed = 1;
+                } else {
+                    since_indexed += 1;
+                }
+                next_offset = offset + 1;
+                if !tick() {
+                    return Err(SegmentError::Cancelled);
+                }
+            }
+        }
+
+        self.index.size = 0;
+        for (offset, position) in entries {
+            self.index.write(offset, position)?;
+        }
+        self.since_indexed = since_indexed;
+        self.next_offset = next_offset;
+        Ok(self.index.size / INDEX_ENTRY_LENGTH as u64)
+    }
+
+    /// Reads and decodes every record in the store, which checks its checksum if there is one.
+    /// Calls `tick` after each record, returning false stops with `SegmentError::Cancelled`.
+    /// Returns the number of records read
+    pub fn verify(&self, tick: &mut dyn FnMut() -> bool) -> Result<u64, SegmentError> {
+        let mut records = 0;
+        for entry in self.store.iter() {
+            let (_, payload) = entry?;
+            let frame: Record = prost::Message::decode(&payload[..])?;
+            records += frame.set.len().max(1) as u64;
+            if !tick() {
+                return Err(SegmentError::Cancelled);
+            }
+        }
+        Ok(records)
+    }
+
+    /// A quicker `verify` for integrity sweeps: only checks each frame's checksum, without
+    /// decrypting or decoding it, so a record that isn't valid protobuf still passes.
+    /// Corrupt frames fail the same way. Returns the number of frames checked, where a record
+    /// set is a single frame
+    pub fn verify_checksums_only(
+        &self,
+        tick: &mut dyn FnMut() -> bool,
+    ) -> Result<u64, SegmentError> {
+        let mut frames = 0;
+        let mut position = self.store.records_start();
+        while position.0 < self.store.size as u64 {
+            position += self.store.check_frame(position)?;
+            frames += 1;
+            if !tick() {
+                return Err(SegmentError::Cancelled);
+            }
         }
+        Ok(frames)
     }
 
-    pub fn close(&mut self) {
-        self.index.close();
+    // whether the index accounts for every record in the store: the records from its last
+    // entry on fill the rest of the store, and there are no more of them than the interval
+    fn is_consistent(&self) -> bool {
+        let Some(last) = self.index.read_last_entry() else {
+            return self.store.size as u64 == self.store.records_start().0;
+        };
+        let mut position = last.position;
+        let mut records = 0;
+        while position.0 < self.store.size as u64 {
+            match self.store.record_len_at(position) {
+                Ok(len) => position += self.store.header_len() + len,
+                Err(_) => return false,
+            }
+            records += 1;
+        }
+        (1..=self.config.get_index_interval() as u64).contains(&records)
+    }
+
+    /// Makes the index agree with the store, e.g. after a crash between writing a record and
+    /// indexing it. A torn record at the end of the store is dropped, then the index is
+    /// rebuilt from what's left. Returns the number of index entries
+    pub fn recover(&mut self) -> Result<u64, SegmentError> {
+        let mut position = self.store.records_start();
+        while position.0 < self.store.size as u64 {
+            match self.store.record_len_at(position) {
+                Ok(len) => position += self.store.header_len() + len,
+                Err(_) => break,
+            }
+        }
+        self.store.truncate_to(position.0 as usize)?;
+        self.rebuild_index()
+    }
+
+    /// The size of every record in the store as stored after its header, in offset order.
+    /// Only the length prefixes are read, the records aren't decoded
+    pub fn record_lens(&self) -> Result<Vec<u64>, SegmentError> {
+        let mut lens = vec![];
+        let mut position = self.store.records_start();
+        while position.0 < self.store.size as u64 {
+            let len = self.store.record_len_at(position)?;
+            lens.push(len);
+            position += self.store.header_len() + len;
+        }
+        Ok(lens)
+    }
+
+    /// Writes every record in the store as a `offset\tlen\tvalue` line, where len is the
+    /// length of the record in the store. Values that aren't utf-8 are written as hex
+    pub fn dump<W: Write>(&self, mut out: W) -> Result<(), SegmentError> {
+        let mut position = self.store.records_start();
+        while position.0 < self.store.size as u64 {
+            let (payload, framed_len) = self.store.read_framed(position)?;
+            let frame: Record = prost::Message::decode(&payload[..])?;
+            // records in a record set are each written with the length of the whole set
+            for record in unpack(frame) {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}",
+                    record.offset.unwrap_or_default(),
+                    framed_len - self.store.header_len(),
+                    escape_value(&record.value)
+                )?;
+            }
+            position += framed_len;
+        }
+        Ok(())
+    }
+
+    /// Every record in the segment along with its offset, in offset order
+    pub fn records(&self) -> Result<Vec<(AbsOffset, Record)>, SegmentError> {
+        if self.is_sparse() {
+            return match self.index.read(0) {
+                Some(first) => self
+                    .walk(self.absolute(first.record_offset), first.position)
+                    .map(|record| record.map(|(offset, _, record)| (AbsOffset(offset), record)))
+                    .collect(),
+                None => Ok(vec![]),
+            };
+        }
+        // the records of a record set share its entries' position, so it's only read once
+        let mut frame: Option<(StorePos, Vec<Record>)> = None;
+        let mut records = vec![];
+        for entry in (0..self.len()).filter_map(|n| self.index.read(n)) {
+            let offset = self.absolute(entry.record_offset);
+            if frame.as_ref().is_none_or(|(position, _)| *position != entry.position) {
+                let payload = self.store.read(entry.position)?;
+                let decoded: Record = prost::Message::decode(&payload[..])?;
+                frame = Some((entry.position, unpack(decoded)));
+            }
+            let (_, unpacked) = frame.as_ref().unwrap();
+            let record = unpacked
+                .iter()
+                .find(|record| record.offset.is_none_or(|found| found == offset))
+                .ok_or(StoreError::StoreEntryNotFound(entry.position.0))?;
+            records.push((AbsOffset(offset), record.clone()));
+        }
+        Ok(records)
+    }
+
+    /// The last `n` records in the segment along with their offsets, in offset order.
+    /// Only the store from the index entry before them on is read
+    pub fn tail(&self, n: u64) -> Result<Vec<(AbsOffset, Record)>, SegmentError> {
+        let skip = self.len().saturating_sub(n);
+        // entry i points at record i * interval
+        let interval = self.config.get_index_interval() as u64;
+        let Some(entry) = self.index.read(skip / interval) else {
+            return Ok(vec![]);
+        };
+        self.walk(self.absolute(entry.record_offset), entry.position)
+            .skip((skip % interval) as usize)
+            .take(n as usize)
+            .map(|record| record.map(|(offset, _, record)| (AbsOffset(offset), record)))
+            .collect()
+    }
+
+    /// Rewrites the segment with only the records `keep` returns true for, keeping their
+    /// offsets. Returns the number of records removed
+    pub fn compact(
+        &mut self,
+        keep: impl Fn(AbsOffset, &Record) -> bool,
+    ) -> Result<u64, SegmentError> {
+        let compacted = self.write_compacted(keep, &mut || true)?;
+        self.swap_compacted(compacted)
+    }
+
+    /// Writes a compacted copy of the segment next to it, without changing the segment,
+    /// so it can run while the segment is being read. See `swap_compacted`.
+    /// Calls `tick` after each record, returning false removes the copy written so far and
+    /// fails with `SegmentError::Cancelled`
+    pub fn write_compacted(
+        &self,
+        keep: impl Fn(AbsOffset, &Record) -> bool,
+        tick: &mut dyn FnMut() -> bool,
+    ) -> Result<CompactedSegment, SegmentError> {
+        let records = self.records()?;
+
+        let (mut store, mut index) = if self.store.is_in_memory() {
+            (
+                Store::in_memory(self.config.clone()),
+                Index::in_memory(self.config.clone()),
+            )
+        } else {
+            let (store_path, index_path) = self.compaction_paths();
+            (
+                Store::new(store_path, self.config.clone())?,
+                Index::new(index_path, self.config.clone())?,
+            )
+        };
+
+        let mut removed = 0;
+        let mut kept = 0;
+        let mut cancelled = false;
+        for (offset, record) in records {
+            if !keep(offset, &record) {
+                removed += 1;
+            } else {
+                let written = store.append_framed(record.encode_to_vec())?;
+                if kept % self.config.get_index_interval() as u64 == 0 {
+                    index.write(self.index_offset(offset.0)?, written.position)?;
+                }
+                kept += 1;
+            }
+            if !tick() {
+                cancelled = true;
+                break;
+            }
+        }
+        store.flush()?;
+        index.close()?;
+
+        let compacted = CompactedSegment {
+            base_offset: self.base_offset,
+            generation: self.generation,
+            store,
+            index,
+            removed,
+        };
+        if cancelled {
+            compacted.discard();
+            return Err(SegmentError::Cancelled);
+        }
+        Ok(compacted)
+    }
+
+    /// Replaces the segment's store and index with a compacted copy, renaming its files over
+    /// the segment's. Returns the number of records removed
+    pub fn swap_compacted(&mut self, compacted: CompactedSegment) -> Result<u64, SegmentError> {
+        let CompactedSegment {
+            mut store,
+            mut index,
+            removed,
+            ..
+        } = compacted;
+
+        if !self.store.is_in_memory() {
+            let (old_store, old_index) = self.paths();
+            std::fs::rename(&store.path, &old_store)?;
+            std::fs::rename(&index.path, &old_index)?;
+            drop((store, index));
+            store = Store::new(old_store, self.config.clone())?;
+            index = Index::new(old_index, self.config.clone())?;
+        }
+        self.index.close()?;
+        self.store = store;
+        self.index = index;
+        self.generation += 1;
+        self.count_since_indexed();
+        // next_offset stays as it was, the offsets after the removed records are still taken
+        Ok(removed)
+    }
+
+    // where a compacted store and index are written before replacing the segment's files,
+    // unique to each compaction so two running at once don't write over each other. Any left
+    // behind are removed the next time the segment is opened
+    fn compaction_paths(&self) -> (PathBuf, PathBuf) {
+        let n = self.compactions.fetch_add(1, Ordering::Relaxed);
+        let extension = format!("{}-{}-{}", COMPACTION_EXTENSION, self.generation, n);
+        let (store, index) = self.paths();
+        (
+            store.with_extension(&extension),
+            index.with_extension(&extension),
+        )
+    }
+
+    /// Copies the segment into `dir`, or into memory when there's no dir, for `Log::snapshot`.
+    /// The copy is opened as it is, without verifying it
+    pub fn copy_to(&self, dir: Option<&Path>) -> Result<Segment, SegmentError> {
+        let store = self.store.copy_to(dir.map(|dir| dir.join(".store")))?;
+        let index = self
+            .index
+            .copy_to(dir.map(|dir| dir.join(".index")), self.config.clone())?;
+        let mut segment = Segment {
+            store,
+            index,
+            base_offset: self.base_offset,
+            next_offset: self.next_offset,
+            config: self.config.clone(),
+            clock: self.clock.clone(),
+            generation: 0,
+            since_indexed: 0,
+            compactions: AtomicU64::new(0),
+        };
+        segment.count_since_indexed();
+        Ok(segment)
+    }
+
+    pub fn close(&mut self) -> Result<(), SegmentError> {
+        self.store.flush()?;
+        self.index.close()?;
+        Ok(())
+    }
+
+    /// Writes appends still buffered by the store out to its file
+    pub fn flush(&mut self) -> Result<(), SegmentError> {
+        self.store.flush()?;
+        Ok(())
+    }
+
+    /// Syncs the store's file and then the index's entries to disk, whatever the sync policy
+    pub fn sync(&mut self) -> Result<(), SegmentError> {
+        self.store.sync()?;
+        self.index.flush()?;
+        Ok(())
     }
 
     pub fn remove(&mut self) {
-        self.close();
+        // the files are removed anyway
+        let _ = self.close();
+
+        if self.store.is_in_memory() {
+            return;
+        }
 
         std::fs::remove_file(self.index.path.clone()).expect("Cannot delete index file");
         std::fs::remove_file(self.store.path.clone()).expect("Cannot delete store file");
     }
 
+    /// Removes the record at offset and every one after it, shrinking the store and index,
+    /// so the next append gets `offset` again
+    pub fn truncate_from(&mut self, AbsOffset(offset): AbsOffset) -> Result<(), SegmentError> {
+        if let Some((_, position)) = self.locate(offset)? {
+            // locate only finds offsets before the segment or that fit in an index entry
+            let rel = self.relative(offset).unwrap_or_default();
+            let entries = self
+                .index
+                .seek(rel)
+                .map_or(self.index.size / INDEX_ENTRY_LENGTH as u64, |(n, _)| n);
+            // records of a record set from before offset are written back as a smaller set,
+            // at the same position so their index entries still point at it
+            let kept: Vec<Record> = match self.store.read(position) {
+                Ok(payload) => unpack(prost::Message::decode(&payload[..])?)
+                    .into_iter()
+                    .filter(|record| record.offset.is_some_and(|found| found < offset))
+                    .collect(),
+                Err(_) => vec![],
+            };
+            self.store.truncate_to(position.0 as usize)?;
+            self.index.truncate_to(entries)?;
+            if let Some(first) = kept.first().and_then(|record| record.offset) {
+                let set = kept
+                    .into_iter()
+                    .map(|mut record| {
+                        record.offset = record.offset.map(|found| found - first);
+                        record
+                    })
+                    .collect();
+                let frame = Record {
+                    offset: Some(first),
+                    set,
+                    ..Default::default()
+                };
+                self.store.append_framed(frame.encode_to_vec())?;
+            }
+            self.count_since_indexed();
+        }
+        self.next_offset = offset.max(self.base_offset);
+        Ok(())
+    }
+
+    // paths of the store and index files
+    pub fn paths(&self) -> (PathBuf, PathBuf) {
+        (self.store.path.clone(), self.index.path.clone())
+    }
+
+    // the first offset in the segment that's offset or after it, offsets can have gaps
+    pub fn seek(&self, AbsOffset(offset): AbsOffset) -> Option<AbsOffset> {
+        self.locate(offset)
+            .ok()
+            .flatten()
+            .map(|(found, _)| AbsOffset(found))
+    }
+
+    // offset of the first record in the segment
+    pub fn first_offset(&self) -> Option<AbsOffset> {
+        self.index
+            .read(0)
+            .map(|entry| AbsOffset(self.absolute(entry.record_offset)))
+    }
+
+    // offset of the last record in the segment
+    pub fn last_offset(&self) -> Option<AbsOffset> {
+        let last = self.index.read_last_entry()?;
+        let last_offset = self.absolute(last.record_offset);
+        if !self.is_sparse() {
+            return Some(AbsOffset(last_offset));
+        }
+        self.walk(last_offset, last.position)
+            .map_while(Result::ok)
+            .last()
+            .map(|(offset, _, _)| AbsOffset(offset))
+            .or(Some(AbsOffset(last_offset)))
+    }
+
+    // number of records in the segment, every index entry but the last stands for
+    // index_interval records
+    pub fn len(&self) -> u64 {
+        let entries = self.index.size / INDEX_ENTRY_LENGTH as u64;
+        match entries {
+            0 => 0,
+            n => (n - 1) * self.config.get_index_interval() as u64 + self.since_indexed,
+        }
+    }
+
+    /// What's needed about the segment once it's sealed without opening it again
+    pub fn meta(&self) -> SegmentMeta {
+        SegmentMeta {
+            next_offset: self.next_offset,
+            len: self.len(),
+            store_size: self.store.size as u64,
+            records_len: self.store.records_len(),
+        }
+    }
+
     pub fn is_maxed(&self) -> bool {
         self.store.size >= self.config.get_max_store_bytes() as usize
-            || self.index.size >= self.config.get_max_index_bytes()
+            || (!self.config.is_index_growable()
+                && self.index.size >= self.config.get_max_index_bytes())
+    }
+
+    // 90% of its store or index is taken, so it's likely to fill up soon
+    pub fn is_nearly_full(&self) -> bool {
+        let nearly = |size: u64, max: u64| size * 10 >= max * 9;
+        nearly(self.store.size as u64, self.config.get_max_store_bytes())
+            || (!self.config.is_index_growable()
+                && nearly(self.index.size, self.config.get_max_index_bytes()))
+    }
+
+    /// Takes over an empty segment created ahead of time in another directory, once that
+    /// directory has been renamed to `dir`, giving it `base_offset`
+    pub fn rebase_empty(&mut self, dir: &Path, AbsOffset(base_offset): AbsOffset) {
+        debug_assert_eq!(self.next_offset, self.base_offset);
+        self.base_offset = base_offset;
+        self.next_offset = base_offset;
+        self.store.path = dir.join(".store");
+        self.index.path = dir.join(".index");
     }
 
     // nearestMultiple(j uint64, k uint64) returns the nearest and lesser multiple of k in j,
@@ -154,6 +1122,291 @@ impl Segment {
     }
 }
 
+// removes compacted copies left in a segment's directory by a compaction that never finished,
+// e.g. because the process died part way through it
+fn remove_compaction_leftovers(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let prefix = format!("{}-", COMPACTION_EXTENSION);
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let leftover = path
+            .extension()
+            .is_some_and(|extension| extension.to_string_lossy().starts_with(&prefix));
+        if leftover {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// utf-8 values are escaped so they stay on one line, anything else is written as hex
+fn escape_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(value) => value.escape_default().to_string(),
+        Err(_) => {
+            let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+// the records in a frame read from the store: the record itself, or the records of a
+// record set with their offsets made absolute
+fn unpack(frame: Record) -> Vec<Record> {
+    if frame.set.is_empty() {
+        return vec![frame];
+    }
+    let first = frame.offset.unwrap_or_default();
+    frame
+        .set
+        .into_iter()
+        .map(|mut record| {
+            record.offset = Some(first + record.offset.unwrap_or_default());
+            record
+        })
+        .collect()
+}
+
+// the record at offset in a frame, which is either that record or a record set holding it
+fn unpack_one(frame: Record, offset: u64) -> Option<Record> {
+    if frame.set.is_empty() {
+        return Some(frame);
+    }
+    unpack(frame)
+        .into_iter()
+        .find(|record| record.offset == Some(offset))
+}
+
+/// What's known about a sealed segment without opening it: kept in the log's manifest so a
+/// lazily opened log can count its records, and by a `LazySegment` that's been closed again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentMeta {
+    pub next_offset: u64,
+    pub len: u64,
+    // size of the store file, a store that's since changed size can't be described by the rest
+    pub store_size: u64,
+    pub records_len: u64,
+}
+
+/// A segment that's only opened the first time it's accessed, see `ConfigBuilder::with_lazy_open`.
+/// Its base offset, paths and `SegmentMeta` are known without opening it, while reading or
+/// writing it goes through `get`/`get_mut`, which open it and fail if it can't be.
+/// A sealed segment can be closed again with `unload`, see `ConfigBuilder::with_max_open_segments`
+pub struct LazySegment {
+    pub base_offset: u64,
+    dir: PathBuf,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    state: Mutex<LazyState>,
+}
+
+struct LazyState {
+    segment: Option<Arc<Segment>>,
+    // what was known about the segment when it was last closed, stale while it's open
+    meta: SegmentMeta,
+}
+
+/// A segment opened by `LazySegment::get`, which can't be closed while it's borrowed
+pub struct SegmentRef<'a> {
+    segment: Arc<Segment>,
+    lazy: PhantomData<&'a LazySegment>,
+}
+
+impl Deref for SegmentRef<'_> {
+    type Target = Segment;
+
+    fn deref(&self) -> &Segment {
+        &self.segment
+    }
+}
+
+impl LazySegment {
+    /// A segment that isn't opened until it's accessed, described by meta until then
+    pub fn new(
+        dir: PathBuf,
+        AbsOffset(base_offset): AbsOffset,
+        config: Arc<Config>,
+        clock: Arc<dyn Clock>,
+        meta: SegmentMeta,
+    ) -> Self {
+        Self {
+            base_offset,
+            dir,
+            config,
+            clock,
+            state: Mutex::new(LazyState {
+                segment: None,
+                meta,
+            }),
+        }
+    }
+
+    pub fn loaded(segment: Segment) -> Self {
+        Self {
+            base_offset: segment.base_offset,
+            // an in-memory segment's store has no path, so it has no directory either
+            dir: segment.store.path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            config: segment.config.clone(),
+            clock: segment.clock.clone(),
+            state: Mutex::new(LazyState {
+                meta: segment.meta(),
+                segment: Some(Arc::new(segment)),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, LazyState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open(&self) -> Result<Segment, SegmentError> {
+        let base_offset = AbsOffset(self.base_offset);
+        let mut segment = Segment::new(self.dir.clone(), base_offset, self.config.clone())?;
+        segment.clock = self.clock.clone();
+        Ok(segment)
+    }
+
+    /// The segment, opening it if it isn't already
+    pub fn get(&self) -> Result<SegmentRef<'_>, SegmentError> {
+        let mut state = self.state();
+        let segment = match &state.segment {
+            Some(segment) => segment.clone(),
+            None => state.segment.insert(Arc::new(self.open()?)).clone(),
+        };
+        Ok(SegmentRef {
+            segment,
+            lazy: PhantomData,
+        })
+    }
+
+    /// The segment to change, opening it if it isn't already
+    pub fn get_mut(&mut self) -> Result<&mut Segment, SegmentError> {
+        if self.state().segment.is_none() {
+            let segment = Arc::new(self.open()?);
+            self.state().segment = Some(segment);
+        }
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        let segment = state.segment.as_mut().and_then(Arc::get_mut);
+        // a `SegmentRef` borrows the segment, so none are left once it's borrowed mutably
+        Ok(segment.expect("segment is still referenced"))
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.state().segment.is_some()
+    }
+
+    /// The segment's next offset, length and store size, without opening it
+    pub fn meta(&self) -> SegmentMeta {
+        let state = self.state();
+        match &state.segment {
+            Some(segment) => segment.meta(),
+            None => state.meta,
+        }
+    }
+
+    pub fn next_offset(&self) -> AbsOffset {
+        AbsOffset(self.meta().next_offset)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.meta().len
+    }
+
+    /// Closes the segment if it's open, keeping what's needed to describe it until it's opened
+    /// again. Returns false, leaving it open, for an in memory segment, which couldn't be opened
+    /// again, or one still referenced by a `SegmentRef`. Fails if closing it does, in which
+    /// case it's unloaded anyway and opened again from its files when it's next used
+    pub fn unload(&self) -> Result<bool, SegmentError> {
+        if self.dir.as_os_str().is_empty() {
+            return Ok(false);
+        }
+        let mut state = self.state();
+        let Some(segment) = state.segment.take() else {
+            return Ok(true);
+        };
+        match Arc::try_unwrap(segment) {
+            Ok(mut segment) => {
+                state.meta = segment.meta();
+                segment.close()?;
+                Ok(true)
+            }
+            Err(segment) => {
+                state.segment = Some(segment);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Some(segment) = state.segment.as_mut().and_then(Arc::get_mut) {
+            segment.clock = clock.clone();
+        }
+        self.clock = clock;
+    }
+
+    /// The directory the segment's files are in, empty for an in memory segment
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn paths(&self) -> (PathBuf, PathBuf) {
+        match &self.state().segment {
+            Some(segment) => segment.paths(),
+            None => (self.dir.join(".store"), self.dir.join(".index")),
+        }
+    }
+
+    // the open segment, None if it isn't
+    fn opened(&mut self) -> Option<&mut Segment> {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        state.segment.as_mut().and_then(Arc::get_mut)
+    }
+
+    pub fn close(&mut self) -> Result<(), SegmentError> {
+        match self.opened() {
+            Some(segment) => segment.close(),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the segment's store if it's been opened, see `Segment::flush`
+    pub fn flush(&mut self) -> Result<(), SegmentError> {
+        match self.opened() {
+            Some(segment) => segment.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Syncs the segment if it's been opened, see `Segment::sync`
+    pub fn sync(&mut self) -> Result<(), SegmentError> {
+        match self.opened() {
+            Some(segment) => segment.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the segment's files, without opening it if it isn't
+    pub fn remove(&mut self) {
+        match self.opened() {
+            Some(segment) => segment.remove(),
+            None => {
+                let (store, index) = self.paths();
+                let _ = std::fs::remove_file(index);
+                let _ = std::fs::remove_file(store);
+            }
+        }
+    }
+}
+
+// the offset after offset, failing rather than wrapping around to 0 once offsets run out
+fn offset_after(offset: u64) -> Result<u64, SegmentError> {
+    offset
+        .checked_add(1)
+        .ok_or(SegmentError::OffsetExhausted(offset))
+}
+
 #[cfg(test)]
 mod test {
     use super::super::index::IndexError;
@@ -171,22 +1424,20 @@ mod test {
         let config = ConfigBuilder::new((INDEX_ENTRY_LENGTH * 3) as u64, 1024, 0).build();
         let config = Arc::new(config);
 
-        let record: Record = Record {
-            value: "hello world".as_bytes().to_vec(),
-            offset: None,
-        };
+        let record: Record = Record::builder().value("hello world").build();
 
         let mut path = PathBuf::new();
         path.push(dir);
 
-        let mut segment = Segment::new(path.clone(), 16, config).expect("Cannot create Segment");
+        let mut segment =
+            Segment::new(path.clone(), AbsOffset(16), config).expect("Cannot create Segment");
 
         assert_eq!(segment.next_offset, 16);
         assert_eq!(segment.is_maxed(), false);
 
         for i in 0..3 {
             let offset = segment.append(record.clone()).unwrap();
-            assert_eq!(16 + i, offset);
+            assert_eq!(AbsOffset(16 + i), offset);
 
             let record_1 = segment.read(offset).unwrap();
             assert_eq!(record_1.value, record.clone().value);
@@ -205,8 +1456,8 @@ mod test {
 
         let config = Arc::new(config);
 
-        let mut segment =
-            Segment::new(path.clone(), 16, config.clone()).expect("Cannot create Segment");
+        let mut segment = Segment::new(path.clone(), AbsOffset(16), config.clone())
+            .expect("Cannot create Segment");
 
         // store should be full
         assert!(segment.is_maxed());
@@ -214,7 +1465,8 @@ mod test {
         // clear segment
         segment.remove();
 
-        let mut segment = Segment::new(path.clone(), 16, config).expect("Cannot create Segment");
+        let mut segment =
+            Segment::new(path.clone(), AbsOffset(16), config).expect("Cannot create Segment");
 
         // store and index should NOT be full
         assert!(!segment.is_maxed());
@@ -223,4 +1475,311 @@ mod test {
 
         std::fs::remove_dir(dir).expect("Cannot delete")
     }
+
+    #[test]
+    fn segment_store_full_clears_offset() {
+        // fits 2 records
+        let config = Arc::new(ConfigBuilder::new(1024, 70, 0).build());
+        let mut segment = Segment::in_memory(AbsOffset(0), config.clone());
+        for i in 0..2 {
+            segment
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        let record = match segment.append(Record::builder().value("hello world2").build()) {
+            Err(SegmentError::StoreFull(record)) => *record,
+            _ => panic!("expected the store to be full"),
+        };
+        assert_eq!(record.offset, None);
+
+        // retried on the next segment, it follows on from the full one
+        let mut next = Segment::in_memory(AbsOffset(segment.next_offset), config);
+        assert_eq!(next.append(record).unwrap(), AbsOffset(2));
+        assert_eq!(next.read(AbsOffset(2)).unwrap().offset, Some(2));
+    }
+
+    #[test]
+    fn segment_read_many() {
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let mut segment = Segment::in_memory(AbsOffset(10), config.clone());
+        for i in 0..6 {
+            segment
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        let many = segment.read_many(AbsOffset(11), 3).unwrap();
+        let each: Vec<Record> = (11..14)
+            .map(|offset| segment.read(AbsOffset(offset)).unwrap())
+            .collect();
+        assert_eq!(many, each);
+
+        // stops at the end of the segment
+        let many = segment.read_many(AbsOffset(13), 10).unwrap();
+        let each: Vec<Record> = (13..16)
+            .map(|offset| segment.read(AbsOffset(offset)).unwrap())
+            .collect();
+        assert_eq!(many, each);
+
+        // a sparse index walks from the entry before the start
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).with_index_interval(4).build());
+        let mut segment = Segment::in_memory(AbsOffset(0), config);
+        for i in 0..6 {
+            segment
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+        let many = segment.read_many(AbsOffset(1), 4).unwrap();
+        let each: Vec<Record> = (1..5)
+            .map(|offset| segment.read(AbsOffset(offset)).unwrap())
+            .collect();
+        assert_eq!(many, each);
+    }
+
+    #[test]
+    fn segment_offset_not_in_segment() {
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let base = 1 << 33;
+        let mut segment = Segment::in_memory(AbsOffset(base), config);
+        for i in 0..2 {
+            segment
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        // the whole absolute offset is reported, before the segment, after it, or too far
+        // past its base for a relative offset
+        for offset in [5, base - 1, base + 2, base + (1 << 32) + 1] {
+            assert!(matches!(
+                segment.read(AbsOffset(offset)),
+                Err(SegmentError::OffsetNotInSegment(missing)) if missing == offset
+            ));
+            assert!(matches!(
+                segment.read_many(AbsOffset(offset), 1),
+                Err(SegmentError::OffsetNotInSegment(missing)) if missing == offset
+            ));
+        }
+    }
+
+    #[test]
+    fn segment_append_set() {
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let mut segment = Segment::in_memory(AbsOffset(0), config);
+        segment.append(Record::builder().value("single0").build()).unwrap();
+        let set: Vec<Record> = (0..10)
+            .map(|i| Record::builder().value(format!("member{}", i)).build())
+            .collect();
+        assert_eq!(
+            segment.append_set(&set).unwrap(),
+            (1..11).map(AbsOffset).collect::<Vec<_>>()
+        );
+        segment
+            .append(Record::builder().value("single11").build())
+            .unwrap();
+
+        // one frame holds the whole set, while each member has an index entry
+        assert_eq!(segment.record_lens().unwrap().len(), 3);
+        assert_eq!(segment.len(), 12);
+        for i in 0..10 {
+            let record = segment.read(AbsOffset(1 + i)).unwrap();
+            assert_eq!(record.offset, Some(1 + i));
+            assert_eq!(record.value, format!("member{}", i).as_bytes());
+        }
+        assert_eq!(
+            segment.read(AbsOffset(11)).unwrap().value,
+            "single11".as_bytes()
+        );
+
+        // a batch starting and ending part way through the set
+        let many = segment.read_many(AbsOffset(4), 3).unwrap();
+        let each: Vec<Record> = (4..7)
+            .map(|offset| segment.read(AbsOffset(offset)).unwrap())
+            .collect();
+        assert_eq!(many, each);
+        let records = segment.records().unwrap();
+        assert_eq!(records.len(), 12);
+        assert_eq!(
+            records[5],
+            (AbsOffset(5), segment.read(AbsOffset(5)).unwrap())
+        );
+
+        // rebuilding gives the same entries back
+        segment.rebuild_index().unwrap();
+        assert_eq!(segment.len(), 12);
+        assert_eq!(
+            segment.read(AbsOffset(7)).unwrap().value,
+            "member6".as_bytes()
+        );
+
+        // cutting the set in half keeps the members before the cut
+        segment.truncate_from(AbsOffset(5)).unwrap();
+        assert_eq!(segment.next_offset, 5);
+        assert_eq!(segment.len(), 5);
+        assert_eq!(
+            segment.read(AbsOffset(4)).unwrap().value,
+            "member3".as_bytes()
+        );
+        assert!(segment.read(AbsOffset(5)).is_err());
+        assert_eq!(
+            segment
+                .append(Record::builder().value("after").build())
+                .unwrap(),
+            AbsOffset(5)
+        );
+        assert_eq!(
+            segment.read(AbsOffset(5)).unwrap().value,
+            "after".as_bytes()
+        );
+    }
+
+    #[test]
+    fn segment_offset_exhausted() {
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let mut segment = Segment::in_memory(AbsOffset(u64::MAX - 2), config);
+        let record = || Record::builder().value("hello world").build();
+
+        assert_eq!(segment.append(record()).unwrap(), AbsOffset(u64::MAX - 2));
+        assert_eq!(segment.append(record()).unwrap(), AbsOffset(u64::MAX - 1));
+        // u64::MAX would leave no next offset, so nothing is written
+        assert!(matches!(
+            segment.append(record()),
+            Err(SegmentError::OffsetExhausted(u64::MAX))
+        ));
+        assert!(matches!(
+            segment.append_encoded(AbsOffset(u64::MAX), &record().encode_to_vec()),
+            Err(SegmentError::OffsetExhausted(u64::MAX))
+        ));
+        assert_eq!(segment.next_offset, u64::MAX);
+        assert_eq!(segment.len(), 2);
+        assert_eq!(segment.last_offset(), Some(AbsOffset(u64::MAX - 1)));
+    }
+
+    #[test]
+    fn segment_reindexes_unclosed_legacy_store() {
+        let dir = "segment-dir-segment_reindexes_unclosed_legacy_store";
+        std::fs::create_dir(dir).expect("Cannot create segment directory");
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+
+        // a store without a version holding one record, and the index it was left with after a
+        // crash: still at its preallocated size, its entry (0, 0) as zeroed as the rest of it
+        let payload = Record::builder()
+            .value("hello world")
+            .build()
+            .encode_to_vec();
+        let mut store = vec![];
+        store.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+        store.extend_from_slice(&payload);
+        std::fs::write(format!("{}/.store", dir), &store).unwrap();
+        std::fs::write(format!("{}/.index", dir), vec![0; 1024]).unwrap();
+
+        let mut segment = Segment::new(PathBuf::from(dir), AbsOffset(0), config).unwrap();
+        assert_eq!(segment.next_offset, 1);
+        assert_eq!(
+            segment.read(AbsOffset(0)).unwrap().value,
+            "hello world".as_bytes()
+        );
+        let next = Record::builder().value("next").build();
+        assert_eq!(segment.append(next).unwrap(), AbsOffset(1));
+        segment.remove();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+// counts allocations with a global allocator, which every test in the binary would go through,
+// so it's only built with `cargo test --features count-allocs`
+#[cfg(all(test, feature = "count-allocs"))]
+mod alloc_test {
+    use super::*;
+    use crate::log::log::ConfigBuilder;
+    use crate::proto::record::Record;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    // tracks the bytes each thread has allocated and not freed, and the most it's had at once,
+    // so a test can tell whether something was copied. Other tests running at the same time
+    // allocate on their own threads, so they don't count
+    struct CountingAlloc;
+
+    thread_local! {
+        static LIVE: Cell<isize> = const { Cell::new(0) };
+        static PEAK: Cell<isize> = const { Cell::new(0) };
+    }
+
+    fn track(delta: isize) {
+        let _ = LIVE.try_with(|live| {
+            live.set(live.get() + delta);
+            let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+        });
+    }
+
+    // the most this thread has had allocated at once while running f, on top of what it had
+    fn peak_allocated(f: impl FnOnce()) -> isize {
+        let before = LIVE.with(|live| live.get());
+        PEAK.with(|peak| peak.set(before));
+        f();
+        PEAK.with(|peak| peak.get()) - before
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                track(layout.size() as isize);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            track(-(layout.size() as isize));
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    #[test]
+    fn segment_append_large_value() {
+        let dir = "segment-dir-segment_append_large_value";
+        std::fs::create_dir(dir).expect("Cannot create segment directory");
+        let config = ConfigBuilder::new(1024, 16 * 1024 * 1024, 0)
+            .with_checksums(true)
+            .build();
+        let config = Arc::new(config);
+
+        // large enough to be written without copying it into the encoding
+        let value: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut segment = Segment::new(PathBuf::from(dir), AbsOffset(0), config.clone()).unwrap();
+        let large = Record::builder().key("large").value(value.clone()).build();
+        let peak = peak_allocated(|| assert_eq!(segment.append_ref(&large).unwrap(), AbsOffset(0)));
+        // nowhere near another copy of the value
+        assert!(
+            peak < value.len() as isize / 16,
+            "allocated {} bytes appending",
+            peak
+        );
+        assert_eq!(
+            segment
+                .append(Record::builder().value("small").build())
+                .unwrap(),
+            AbsOffset(1)
+        );
+
+        let record = segment.read(AbsOffset(0)).unwrap();
+        assert_eq!(record.offset, Some(0));
+        assert_eq!(record.key, Some("large".as_bytes().to_vec()));
+        assert!(record.value == value);
+        assert_eq!(
+            segment.read(AbsOffset(1)).unwrap().value,
+            "small".as_bytes()
+        );
+        segment.close().unwrap();
+
+        let segment = Segment::new(PathBuf::from(dir), AbsOffset(0), config).unwrap();
+        assert!(segment.read(AbsOffset(0)).unwrap().value == value);
+        assert_eq!(segment.next_offset, 2);
+        drop(segment);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
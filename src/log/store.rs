@@ -12,12 +12,24 @@ use std::{
 };
 use thiserror::Error;
 
-use crate::log::log::LEN_WIDTH;
+use crate::log::log::{CHECKSUM_WIDTH, LEN_WIDTH};
 use crate::proto::{self, record::Record};
 use std::io;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
+/// Version of the store format, written as the first byte of every store
+pub const STORE_VERSION: u8 = 1;
+/// Bytes before the first record: the version
+pub const VERSION_LEN: u64 = 1;
+/// Stores written before the version byte was added start straight away with the first
+/// record's length prefix, whose top byte is 0 for any record that fits in a store
+pub const LEGACY_VERSION: u8 = 0;
+
+use super::encryption::{self, NONCE_LENGTH, TAG_LENGTH};
 use super::log::Config;
+use super::offset::StorePos;
+use aes_gcm::Aes256Gcm;
 
 #[derive(Error, Debug)]
 pub enum StoreError {
@@ -25,64 +37,531 @@ pub enum StoreError {
     StoreFullError,
     #[error("Store entry {0} not found")]
     StoreEntryNotFound(u64),
+    #[error("Cannot encrypt record")]
+    EncryptionFailed,
+    #[error("Cannot decrypt store entry {0}")]
+    DecryptionFailed(u64),
+    #[error("Checksum mismatch for store entry {0}")]
+    ChecksumMismatch(u64),
+    #[error("Cannot truncate store to {0}, it's past the end or inside a record")]
+    InvalidTruncation(usize),
+    #[error("Unsupported store version {0}")]
+    UnsupportedVersion(u8),
 
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
+// bytes backing a store, either a file on disk or an in-memory buffer. Each append is written
+// out to the file before the index entry for it, so a crash can't leave an entry pointing past
+// the end of the file. Only a batching log's appends stay buffered, until it writes out the
+// whole batch and syncs it at once, see `Log::flush_batch`
+pub enum StoreBackend {
+    File(BufWriter<File>),
+    Memory(Vec<u8>),
+}
+
+/// Where an appended record's frame landed in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    // start of the frame, the position records are read from
+    pub position: StorePos,
+    pub len_prefix_at: StorePos,
+    // start of the payload as stored, after the length and checksum
+    pub payload_at: StorePos,
+    // bytes written for the whole frame
+    pub total: usize,
+}
+
+impl Written {
+    // where the next frame starts
+    pub fn end(&self) -> StorePos {
+        self.position + self.total as u64
+    }
+}
+
 pub struct Store {
-    pub file: File,
+    pub backend: StoreBackend,
     pub size: usize,
     pub path: PathBuf,
     pub config: Arc<Config>,
+    reads: AtomicU64, // number of reads made against the backend
+    cipher: Option<Aes256Gcm>, // encrypts payloads when encryption is configured
+    appends: u64,              // appends since the store was opened, to apply the sync policy
+    // position and length prefix of the record after the last one read, see `read_framed`
+    next_len: Mutex<Option<(StorePos, u64)>>,
+    start: u64, // where the first record starts, 0 for a legacy store without a version
 }
 
 impl Store {
-    pub fn new(path: PathBuf, config: Arc<Config>) -> Store {
-        let file = OpenOptions::new()
+    /// Opens the store at path, creating it if it doesn't exist.
+    /// Fails with `UnsupportedVersion` for stores written in a format this version can't read.
+    /// Legacy stores, from before the version byte, are read and appended to as they are
+    pub fn new(path: PathBuf, config: Arc<Config>) -> Result<Store, StoreError> {
+        let mut file = OpenOptions::new()
             .read(true)
             .create(true)
             .append(true)
-            .open(&path)
-            .unwrap();
-        let file_size = file.metadata().unwrap().len();
-        Self {
-            file,
+            .open(&path)?;
+        let mut file_size = file.metadata()?.len();
+        let mut start = VERSION_LEN;
+        if file_size == 0 {
+            file.write_all(&[STORE_VERSION])?;
+            file_size = VERSION_LEN;
+        } else {
+            let mut version = [0; VERSION_LEN as usize];
+            file.read_exact_at(&mut version, 0)?;
+            match version[0] {
+                STORE_VERSION => {}
+                // the index entries point at positions without a version in front of them,
+                // so the store's left as it is rather than rewritten
+                LEGACY_VERSION => start = 0,
+                version => return Err(StoreError::UnsupportedVersion(version)),
+            }
+        }
+        Ok(Self {
+            backend: StoreBackend::File(BufWriter::new(file)),
             size: file_size as usize,
             path,
+            cipher: config.get_encryption().map(|e| e.cipher()),
+            config,
+            reads: AtomicU64::new(0),
+            appends: 0,
+            next_len: Mutex::new(None),
+            start,
+        })
+    }
+
+    pub fn in_memory(config: Arc<Config>) -> Store {
+        Self {
+            backend: StoreBackend::Memory(vec![STORE_VERSION]),
+            size: VERSION_LEN as usize,
+            path: PathBuf::new(),
+            cipher: config.get_encryption().map(|e| e.cipher()),
             config,
+            reads: AtomicU64::new(0),
+            appends: 0,
+            next_len: Mutex::new(None),
+            start: VERSION_LEN,
         }
     }
 
     pub fn can_store_record(&self, record_len: usize) -> bool {
-        self.size + (record_len + LEN_WIDTH as usize) < self.config.get_max_store_bytes() as usize
+        self.size + (record_len + self.overhead() + self.header_len() as usize)
+            < self.config.get_max_store_bytes() as usize
     }
 
-    pub fn append(&mut self, value: Vec<u8>) -> Result<(usize, usize), StoreError> {
+    /// Bytes framing each payload: its length, followed by a checksum when checksums are enabled
+    pub fn header_len(&self) -> u64 {
+        if self.config.has_checksums() {
+            LEN_WIDTH as u64 + CHECKSUM_WIDTH as u64
+        } else {
+            LEN_WIDTH as u64
+        }
+    }
+
+    /// Bytes a payload of `len` bytes takes up in the store once framed and, if encryption is
+    /// configured, encrypted
+    pub fn framed_len(&self, len: usize) -> u64 {
+        self.header_len() + (len + self.overhead()) as u64
+    }
+
+    // bytes stored alongside each payload, besides its length
+    fn overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            NONCE_LENGTH + TAG_LENGTH
+        } else {
+            0
+        }
+    }
+
+    /// Appends value, returning the number of bytes written and the position it was written at.
+    /// See `append_framed` for the full layout of the frame
+    pub fn append(&mut self, value: Vec<u8>) -> Result<(usize, StorePos), StoreError> {
+        let written = self.append_framed(value)?;
+        Ok((written.total, written.position))
+    }
+
+    /// Appends value, returning where each part of its frame was written
+    pub fn append_framed(&mut self, value: Vec<u8>) -> Result<Written, StoreError> {
+        self.append_parts(&[value.as_slice()])
+    }
+
+    /// Appends `parts` as a single payload, the same as appending them joined together but
+    /// without copying them into one buffer first. An encrypted payload has to be encrypted
+    /// in one piece, so the parts are joined for that
+    pub fn append_parts(&mut self, parts: &[&[u8]]) -> Result<Written, StoreError> {
+        let encrypted = match &self.cipher {
+            Some(cipher) => {
+                let encrypted = match parts {
+                    [value] => encryption::encrypt(cipher, value),
+                    parts => encryption::encrypt(cipher, &parts.concat()),
+                };
+                Some(encrypted.ok_or(StoreError::EncryptionFailed)?)
+            }
+            None => None,
+        };
+        let encrypted_parts;
+        let parts = match &encrypted {
+            Some(value) => {
+                encrypted_parts = [value.as_slice()];
+                &encrypted_parts[..]
+            }
+            None => parts,
+        };
         let position = self.size;
-        let mut buffer = BufWriter::new(&mut self.file);
+
         // 8 bytes for the length of the encoded record
-        buffer.write_u64::<BigEndian>(value.len() as u64)?;
-        let written = buffer.write(&value)?;
-        let total_written = written + LEN_WIDTH as usize;
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let mut header = Vec::with_capacity(self.header_len() as usize);
+        header.write_u64::<BigEndian>(len as u64)?;
+        if self.config.has_checksums() {
+            // covers the length too, so a corrupt length is caught before it's trusted
+            let checksum = checksum(&header, parts);
+            header.write_u32::<BigEndian>(checksum)?;
+        }
+
+        let total_written = match &mut self.backend {
+            StoreBackend::File(writer) => {
+                let written = write_frame(writer, &header, parts)?;
+                if !self.config.is_batching() {
+                    writer.flush()?;
+                    self.appends += 1;
+                    if self.config.get_sync_policy().should_sync(self.appends) {
+                        writer.get_ref().sync_data()?;
+                    }
+                }
+                written
+            }
+            StoreBackend::Memory(buf) => write_frame(buf, &header, parts)?,
+        };
         self.size += total_written;
-        buffer.flush();
-        Ok((total_written, position))
+        Ok(Written {
+            position: StorePos(position as u64),
+            len_prefix_at: StorePos(position as u64),
+            payload_at: StorePos((position + header.len()) as u64),
+            total: total_written,
+        })
     }
 
-    pub fn read(&self, position: u64) -> Result<Vec<u8>, StoreError> {
-        let mut buf: Vec<u8> = vec![0; LEN_WIDTH as usize];
-        self.file.read_exact_at(&mut buf, position)?;
-        let len_of_record = BigEndian::read_u64(&buf[..]);
-        let mut record: Vec<u8> = vec![0; len_of_record as usize];
-        self.file
-            .read_exact_at(&mut record, position + LEN_WIDTH as u64)?; // add LEN_WIDTH, cos LEN_WIDTH holds the size of the record
+    /// Writes appends still in the store's buffer out to its file, without syncing it
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        if let StoreBackend::File(writer) = &mut self.backend {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the store, appends it's still buffering included, to a new file at `path`,
+    /// or into memory when there's no path
+    pub fn copy_to(&self, path: Option<PathBuf>) -> Result<Store, StoreError> {
+        let bytes = self.read_bytes(0, self.size)?;
+        match path {
+            Some(path) => {
+                std::fs::write(&path, bytes)?;
+                Store::new(path, self.config.clone())
+            }
+            None => {
+                let mut store = Store::in_memory(self.config.clone());
+                store.size = bytes.len();
+                store.start = self.start;
+                store.backend = StoreBackend::Memory(bytes);
+                Ok(store)
+            }
+        }
+    }
+
+    /// Writes out buffered appends and syncs the store's file to disk, whatever the sync policy
+    pub fn sync(&mut self) -> Result<(), StoreError> {
+        if let StoreBackend::File(writer) = &mut self.backend {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, position: StorePos) -> Result<Vec<u8>, StoreError> {
+        let (record, _) = self.read_framed(position)?;
         Ok(record)
     }
+
+    /// Reads the record at position along with the number of bytes it takes up in the store,
+    /// so the next record starts at `position` plus that size. The next record's length prefix
+    /// is read along with the record and kept, so reading records one after another takes one
+    /// read of the backend each rather than two
+    pub fn read_framed(&self, position: StorePos) -> Result<(Vec<u8>, u64), StoreError> {
+        let len_of_record = match self.cached_len(position) {
+            Some(len) => len,
+            None => self.record_len_at(position)?,
+        };
+        let frame_len = (self.header_len() + len_of_record) as usize;
+        let next = position + frame_len as u64;
+        let read_ahead = if next.0 + LEN_WIDTH as u64 <= self.size as u64 {
+            LEN_WIDTH as usize
+        } else {
+            0
+        };
+        let mut frame: Vec<u8> = vec![0; frame_len + read_ahead];
+        self.read_exact_at(&mut frame, position.0)?;
+        if read_ahead > 0 {
+            let len = BigEndian::read_u64(&frame[frame_len..]);
+            if let Ok(mut next_len) = self.next_len.lock() {
+                *next_len = Some((next, len));
+            }
+        }
+        self.unframe(&frame[..frame_len], position)
+    }
+
+    // the length prefix at position if it was read ahead by the last `read_framed`, checked
+    // like `record_len_at` checks one it reads
+    fn cached_len(&self, position: StorePos) -> Option<u64> {
+        let (cached_at, len) = (*self.next_len.lock().ok()?)?;
+        let remaining = (self.size as u64)
+            .saturating_sub(position.0)
+            .saturating_sub(self.header_len());
+        (cached_at == position && len <= remaining).then_some(len)
+    }
+
+    /// Reads only the length prefix of the record at position, that is the number of bytes stored
+    /// after the header. The next record starts at `position + header_len() + len`
+    pub fn record_len_at(&self, position: StorePos) -> Result<u64, StoreError> {
+        if position.0 + LEN_WIDTH as u64 > self.size as u64 {
+            return Err(StoreError::StoreEntryNotFound(position.0));
+        }
+        let mut buf: Vec<u8> = vec![0; LEN_WIDTH as usize];
+        self.read_exact_at(&mut buf, position.0)?;
+        let len = BigEndian::read_u64(&buf[..]);
+
+        // a corrupt length could point past the end of the store, don't try to read that much
+        let remaining = (self.size as u64 - position.0).saturating_sub(self.header_len());
+        if len > remaining {
+            return Err(self.corrupt_entry(position));
+        }
+        Ok(len)
+    }
+
+    /// Splits the frame at the start of `bytes` (read from `position`) into its payload,
+    /// checking its checksum and decrypting it. Also returns the size of the frame,
+    /// so the next frame in `bytes` starts there
+    pub fn unframe(&self, bytes: &[u8], position: StorePos) -> Result<(Vec<u8>, u64), StoreError> {
+        let header_len = self.header_len() as usize;
+        if bytes.len() < header_len {
+            return Err(StoreError::StoreEntryNotFound(position.0));
+        }
+        let len = BigEndian::read_u64(&bytes[..LEN_WIDTH as usize]);
+        if len > (bytes.len() - header_len) as u64 {
+            return Err(self.corrupt_entry(position));
+        }
+        let payload = &bytes[header_len..header_len + len as usize];
+
+        if self.config.has_checksums() {
+            let stored = BigEndian::read_u32(&bytes[LEN_WIDTH as usize..header_len]);
+            if stored != checksum(&bytes[..LEN_WIDTH as usize], &[payload]) {
+                return Err(StoreError::ChecksumMismatch(position.0));
+            }
+        }
+
+        let payload = self.decrypt_payload(payload.to_vec(), position)?;
+        Ok((payload, header_len as u64 + len))
+    }
+
+    /// Checks the frame at position against its checksum without decrypting its payload,
+    /// returning the frame's size like `read_framed`. Without checksums only its length is
+    /// checked, and its payload isn't read
+    pub fn check_frame(&self, position: StorePos) -> Result<u64, StoreError> {
+        let len = self.record_len_at(position)?;
+        let header_len = self.header_len();
+        if !self.config.has_checksums() {
+            return Ok(header_len + len);
+        }
+        let mut frame: Vec<u8> = vec![0; (header_len + len) as usize];
+        self.read_exact_at(&mut frame, position.0)?;
+        let stored = BigEndian::read_u32(&frame[LEN_WIDTH as usize..header_len as usize]);
+        if stored != checksum(&frame[..LEN_WIDTH as usize], &[&frame[header_len as usize..]]) {
+            return Err(StoreError::ChecksumMismatch(position.0));
+        }
+        Ok(header_len + len)
+    }
+
+    // a record whose length can't be right
+    fn corrupt_entry(&self, position: StorePos) -> StoreError {
+        if self.config.has_checksums() {
+            StoreError::ChecksumMismatch(position.0)
+        } else {
+            StoreError::StoreEntryNotFound(position.0)
+        }
+    }
+
+    /// Returns the payload as it was appended, decrypting it if encryption is configured.
+    /// `stored` is the payload as read from the store at `position`
+    pub fn decrypt_payload(
+        &self,
+        stored: Vec<u8>,
+        position: StorePos,
+    ) -> Result<Vec<u8>, StoreError> {
+        match &self.cipher {
+            Some(cipher) => {
+                encryption::decrypt(cipher, &stored).ok_or(StoreError::DecryptionFailed(position.0))
+            }
+            None => Ok(stored),
+        }
+    }
+
+    /// Reads `len` raw bytes starting at `position`, which may span several framed records
+    pub fn read_bytes(&self, position: u64, len: usize) -> Result<Vec<u8>, StoreError> {
+        let mut buf: Vec<u8> = vec![0; len];
+        self.read_exact_at(&mut buf, position)?;
+        Ok(buf)
+    }
+
+    /// Drops every byte from `size` onwards. The store can only shrink, and `size` has to be
+    /// where a record starts (or the end of the store) so no record is left half framed.
+    /// The version is always kept, so the smallest size is `records_start`. Shrinking the file
+    /// hands the blocks past the new end back to the filesystem, there's no need to punch holes
+    pub fn truncate_to(&mut self, size: usize) -> Result<(), StoreError> {
+        if size > self.size {
+            return Err(StoreError::InvalidTruncation(size));
+        }
+        let mut position = self.records_start();
+        while position.0 < size as u64 {
+            position += self.header_len() + self.record_len_at(position)?;
+        }
+        if position.0 != size as u64 {
+            return Err(StoreError::InvalidTruncation(size));
+        }
+
+        match &mut self.backend {
+            StoreBackend::File(writer) => {
+                writer.flush()?;
+                writer.get_ref().set_len(size as u64)?
+            }
+            StoreBackend::Memory(bytes) => bytes.truncate(size),
+        }
+        self.size = size;
+        // records appended from here on can start where a read-ahead length prefix was
+        if let Ok(mut next_len) = self.next_len.lock() {
+            *next_len = None;
+        }
+        Ok(())
+    }
+
+    /// Walks the store by its framing alone, without an index, yielding each record's position
+    /// and payload. A torn record at the end (e.g. from a crash mid-append) yields an error,
+    /// after which the iterator stops
+    pub fn iter(&self) -> StoreIter<'_> {
+        StoreIter {
+            store: self,
+            position: self.records_start(),
+            done: false,
+        }
+    }
+
+    /// Where the first record starts, after the version
+    pub fn records_start(&self) -> StorePos {
+        StorePos(self.start)
+    }
+
+    /// Bytes taken up by records, leaving out the version
+    pub fn records_len(&self) -> u64 {
+        self.size as u64 - self.start
+    }
+
+    pub fn read_count(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> io::Result<()> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        match &self.backend {
+            StoreBackend::File(writer) => {
+                // the newest bytes can still be in the buffer rather than the file
+                let buffered = writer.buffer();
+                let flushed = (self.size - buffered.len()) as u64;
+                let from_file = (flushed.saturating_sub(position) as usize).min(buf.len());
+                let (file_part, buffer_part) = buf.split_at_mut(from_file);
+                writer.get_ref().read_exact_at(file_part, position)?;
+                if buffer_part.is_empty() {
+                    return Ok(());
+                }
+                let start = (position + from_file as u64 - flushed) as usize;
+                let end = start + buffer_part.len();
+                if end > buffered.len() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                buffer_part.copy_from_slice(&buffered[start..end]);
+                Ok(())
+            }
+            StoreBackend::Memory(bytes) => {
+                let start = position as usize;
+                let end = start + buf.len();
+                if end > bytes.len() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                buf.copy_from_slice(&bytes[start..end]);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_in_memory(&self) -> bool {
+        matches!(self.backend, StoreBackend::Memory(_))
+    }
+}
+
+pub struct StoreIter<'a> {
+    store: &'a Store,
+    position: StorePos,
+    done: bool,
+}
+
+impl<'a> Iterator for StoreIter<'a> {
+    type Item = Result<(StorePos, Vec<u8>), StoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.position.0 >= self.store.size as u64 {
+            return None;
+        }
+        let position = self.position;
+        match self.store.read_framed(position) {
+            Ok((payload, framed_len)) => {
+                self.position += framed_len;
+                Some(Ok((position, payload)))
+            }
+            Err(e) => {
+                // there's no telling where the next record starts
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// writes a frame's header and payload, failing rather than leaving a short frame behind as
+// if it was written in full. Returns the size of the frame
+fn write_frame(writer: &mut impl Write, header: &[u8], payload: &[&[u8]]) -> io::Result<usize> {
+    writer.write_all(header)?;
+    for part in payload {
+        writer.write_all(part)?;
+    }
+    Ok(header.len() + payload.iter().map(|part| part.len()).sum::<usize>())
+}
+
+// crc32 of the length prefix followed by the payload, which can be in parts
+fn checksum(len: &[u8], payload: &[&[u8]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    for part in payload {
+        hasher.update(part);
+    }
+    hasher.finalize()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::log::log::ConfigBuilder;
+    use crate::log::log::{ConfigBuilder, INDEX_ENTRY_LENGTH};
+    use crate::log::offset::AbsOffset;
+    use crate::log::segment::Segment;
+    use crate::proto::record::Record;
+    use prost::Message;
 
     use super::*;
     #[test]
@@ -91,7 +570,7 @@ mod test {
         let mut path = PathBuf::new();
         path.push(&file_name);
         let config = ConfigBuilder::new(1024, 1024, 0).build();
-        let mut store = Store::new(path, Arc::new(config));
+        let mut store = Store::new(path, Arc::new(config)).unwrap();
 
         let record_1 = "hello_world1";
         let record_2 = "hello_world2";
@@ -100,18 +579,9 @@ mod test {
         let (_, position1) = store.append(record_1.as_bytes().to_vec()).unwrap();
         let (_, position2) = store.append(record_2.as_bytes().to_vec()).unwrap();
         let (_, position3) = store.append(record_3.as_bytes().to_vec()).unwrap();
-        assert_eq!(
-            &(store.read(position1 as u64).unwrap()),
-            record_1.as_bytes()
-        );
-        assert_eq!(
-            &(store.read(position2 as u64).unwrap()),
-            record_2.as_bytes()
-        );
-        assert_eq!(
-            &(store.read(position3 as u64).unwrap()),
-            record_3.as_bytes()
-        );
+        assert_eq!(&(store.read(position1).unwrap()), record_1.as_bytes());
+        assert_eq!(&(store.read(position2).unwrap()), record_2.as_bytes());
+        assert_eq!(&(store.read(position3).unwrap()), record_3.as_bytes());
 
         std::fs::remove_file(file_name).unwrap();
     }
@@ -122,7 +592,7 @@ mod test {
         let mut path = PathBuf::new();
         path.push(&file_name);
         let config = ConfigBuilder::new(1024, 20, 0).build();
-        let mut store = Store::new(path, Arc::new(config));
+        let mut store = Store::new(path, Arc::new(config)).unwrap();
         let record_1 = "hello_world1";
         let record_2 = "hello_world2";
 
@@ -135,4 +605,394 @@ mod test {
 
 
     }
+
+    #[test]
+    fn record_len_at() {
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut store = Store::in_memory(Arc::new(config));
+        let record_1 = "hello_world1";
+        let record_2 = "hello";
+
+        let (written_1, position_1) = store.append(record_1.as_bytes().to_vec()).unwrap();
+        let (_, position_2) = store.append(record_2.as_bytes().to_vec()).unwrap();
+
+        assert_eq!(
+            store.record_len_at(position_1).unwrap(),
+            record_1.len() as u64
+        );
+        assert_eq!(
+            store.record_len_at(position_2).unwrap(),
+            record_2.len() as u64
+        );
+        // stepping over the first record lands on the second
+        assert_eq!(position_1 + written_1 as u64, position_2);
+        assert_eq!(
+            position_1 + LEN_WIDTH as u64 + store.record_len_at(position_1).unwrap(),
+            position_2
+        );
+
+        assert!(matches!(
+            store.record_len_at(StorePos(store.size as u64)),
+            Err(StoreError::StoreEntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn truncate_to() {
+        let file_name = "tempfile_truncate_to";
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut store = Store::new(PathBuf::from(file_name), Arc::new(config.clone())).unwrap();
+
+        let (_, position_1) = store.append("hello_world1".as_bytes().to_vec()).unwrap();
+        let (_, position_2) = store.append("hello_world2".as_bytes().to_vec()).unwrap();
+        let (_, position_3) = store.append("hello_world3".as_bytes().to_vec()).unwrap();
+        let size = store.size;
+
+        // can't grow, or cut a record in half
+        assert!(matches!(
+            store.truncate_to(size + 1),
+            Err(StoreError::InvalidTruncation(_))
+        ));
+        assert!(matches!(
+            store.truncate_to(position_3.0 as usize + 1),
+            Err(StoreError::InvalidTruncation(_))
+        ));
+        assert_eq!(store.size, size);
+
+        store.truncate_to(position_3.0 as usize).unwrap();
+        assert_eq!(store.size as u64, position_3.0);
+        assert_eq!(store.read(position_1).unwrap(), "hello_world1".as_bytes());
+        assert_eq!(store.read(position_2).unwrap(), "hello_world2".as_bytes());
+        assert!(store.read(position_3).is_err());
+
+        // the file is shrunk too
+        let store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+        assert_eq!(store.size as u64, position_3.0);
+        assert!(store.read(position_3).is_err());
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn truncate_to_frees_blocks() {
+        use std::os::unix::fs::MetadataExt;
+        let file_name = "tempfile_truncate_to_frees_blocks";
+        let config = ConfigBuilder::new(1024, 4 * 1024 * 1024, 0).build();
+        let mut store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+        let (_, position_1) = store.append("hello_world1".as_bytes().to_vec()).unwrap();
+        let (_, position_2) = store.append(vec![7; 1024 * 1024]).unwrap();
+        store.flush().unwrap();
+        let blocks = || std::fs::metadata(file_name).unwrap().blocks();
+        let before = blocks();
+
+        store.truncate_to(position_2.0 as usize).unwrap();
+        // 512 byte blocks, the megabyte record took up 2048 of them
+        assert!(blocks() + 2000 <= before);
+        assert_eq!(std::fs::metadata(file_name).unwrap().len(), position_2.0);
+        assert_eq!(store.read(position_1).unwrap(), "hello_world1".as_bytes());
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn checksum_covers_length() {
+        let file_name = "tempfile_checksum_covers_length";
+        let config = ConfigBuilder::new(1024, 1024, 0).with_checksums(true).build();
+        let mut store = Store::new(PathBuf::from(file_name), Arc::new(config.clone())).unwrap();
+
+        let (written, position_1) = store.append("hello_world1".as_bytes().to_vec()).unwrap();
+        let (_, position_2) = store.append("hello_world2".as_bytes().to_vec()).unwrap();
+        store.append("hello_world3".as_bytes().to_vec()).unwrap();
+        assert_eq!(written, LEN_WIDTH as usize + CHECKSUM_WIDTH as usize + 12);
+        assert_eq!(store.read(position_2).unwrap(), "hello_world2".as_bytes());
+        store.flush().unwrap();
+
+        let mut bytes = std::fs::read(file_name).unwrap();
+        // flip a bit in the length prefix of two records, making the first one huge
+        // and the second one a byte longer, so it's only caught by the checksum
+        bytes[position_1.0 as usize] ^= 0x80;
+        bytes[position_2.0 as usize + LEN_WIDTH as usize - 1] ^= 0x01;
+        std::fs::write(file_name, bytes).unwrap();
+
+        let store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+        assert!(matches!(
+            store.read(position_1),
+            Err(StoreError::ChecksumMismatch(p)) if p == position_1.0
+        ));
+        assert!(matches!(
+            store.read(position_2),
+            Err(StoreError::ChecksumMismatch(p)) if p == position_2.0
+        ));
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn store_iter() {
+        let file_name = "tempfile_store_iter";
+        let config = ConfigBuilder::new(1024, 1024, 0).build();
+        let mut store = Store::new(PathBuf::from(file_name), Arc::new(config.clone())).unwrap();
+
+        let mut appended = vec![];
+        for i in 0..3 {
+            let value = format!("hello_world{}", i).into_bytes();
+            let (_, position) = store.append(value.clone()).unwrap();
+            appended.push((position, value));
+        }
+        let records: Vec<(StorePos, Vec<u8>)> = store.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(records, appended);
+
+        // a torn last record yields one error and ends the iteration
+        store.flush().unwrap();
+        let file = OpenOptions::new().append(true).open(file_name).unwrap();
+        file.set_len(store.size as u64 - 3).unwrap();
+        let store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+        let records: Vec<Result<(StorePos, Vec<u8>), StoreError>> = store.iter().collect();
+        assert_eq!(records.len(), 3);
+        assert!(records[1].is_ok());
+        assert!(records[2].is_err());
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn store_version() {
+        let file_name = "tempfile_store_version";
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let mut store = Store::new(PathBuf::from(file_name), config.clone()).unwrap();
+        let (_, position) = store.append("hello_world1".as_bytes().to_vec()).unwrap();
+        assert_eq!(position, StorePos(VERSION_LEN));
+        assert_eq!(store.records_len(), LEN_WIDTH as u64 + 12);
+        drop(store);
+
+        let bytes = std::fs::read(file_name).unwrap();
+        assert_eq!(bytes[0], STORE_VERSION);
+        let store = Store::new(PathBuf::from(file_name), config.clone()).unwrap();
+        assert_eq!(store.read(position).unwrap(), "hello_world1".as_bytes());
+        drop(store);
+
+        // a store written by a newer format isn't misread
+        let mut bytes = bytes;
+        bytes[0] = STORE_VERSION + 1;
+        std::fs::write(file_name, bytes).unwrap();
+        assert!(matches!(
+            Store::new(PathBuf::from(file_name), config),
+            Err(StoreError::UnsupportedVersion(v)) if v == STORE_VERSION + 1
+        ));
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn store_reads_legacy_format() {
+        let file_name = "tempfile_store_reads_legacy_format";
+        // as written before stores had a version: each record's length prefix, then the record
+        let mut bytes = vec![];
+        for value in ["hello_world1", "hello_world2"] {
+            bytes.write_u64::<BigEndian>(value.len() as u64).unwrap();
+            bytes.extend_from_slice(value.as_bytes());
+        }
+        std::fs::write(file_name, &bytes).unwrap();
+
+        let config = Arc::new(ConfigBuilder::new(1024, 1024, 0).build());
+        let mut store = Store::new(PathBuf::from(file_name), config.clone()).unwrap();
+        assert_eq!(store.records_start(), StorePos(0));
+        assert_eq!(store.read(StorePos(0)).unwrap(), "hello_world1".as_bytes());
+        assert_eq!(
+            store.read(StorePos(LEN_WIDTH as u64 + 12)).unwrap(),
+            "hello_world2".as_bytes()
+        );
+        let (_, position) = store.append("hello_world3".as_bytes().to_vec()).unwrap();
+        assert_eq!(position, StorePos(bytes.len() as u64));
+        drop(store);
+
+        // still without a version once reopened
+        let store = Store::new(PathBuf::from(file_name), config.clone()).unwrap();
+        let values: Vec<Vec<u8>> = store.iter().map(|r| r.unwrap().1).collect();
+        assert_eq!(values, ["hello_world1", "hello_world2", "hello_world3"].map(|v| v.as_bytes()));
+        assert_eq!(store.records_len(), store.size as u64);
+
+        std::fs::remove_file(file_name).unwrap();
+
+        // a legacy segment with one record, whose index entry is at position 0
+        let dir = "tempdir_store_reads_legacy_format";
+        std::fs::create_dir(dir).unwrap();
+        let payload = Record::builder()
+            .value("hello_world1")
+            .build()
+            .encode_to_vec();
+        let mut bytes = vec![];
+        bytes.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+        bytes.extend_from_slice(&payload);
+        std::fs::write(format!("{}/.store", dir), &bytes).unwrap();
+        std::fs::write(format!("{}/.index", dir), [0; INDEX_ENTRY_LENGTH as usize]).unwrap();
+
+        let mut segment = Segment::new(PathBuf::from(dir), AbsOffset(0), config.clone()).unwrap();
+        assert_eq!(segment.next_offset, 1);
+        assert_eq!(
+            segment.read(AbsOffset(0)).unwrap().value,
+            "hello_world1".as_bytes()
+        );
+        let record = Record::builder().value("hello_world2").build();
+        assert_eq!(segment.append(record).unwrap(), AbsOffset(1));
+        segment.close().unwrap();
+        drop(segment);
+
+        // the entry survives reopening, and rebuilding the index writes it again
+        let mut segment = Segment::new(PathBuf::from(dir), AbsOffset(0), config.clone()).unwrap();
+        assert_eq!(segment.next_offset, 2);
+        assert_eq!(segment.rebuild_index().unwrap(), 2);
+        segment.close().unwrap();
+        drop(segment);
+        let segment = Segment::new(PathBuf::from(dir), AbsOffset(0), config).unwrap();
+        assert_eq!(segment.next_offset, 2);
+        assert_eq!(
+            segment.read(AbsOffset(0)).unwrap().value,
+            "hello_world1".as_bytes()
+        );
+        assert_eq!(
+            segment.read(AbsOffset(1)).unwrap().value,
+            "hello_world2".as_bytes()
+        );
+        drop(segment);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn append_framed() {
+        for checksums in [false, true] {
+            let file_name = "tempfile_append_framed";
+            let config = ConfigBuilder::new(1024, 1024, 0).with_checksums(checksums).build();
+            let mut store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+
+            let first = store.append_framed("hello_world1".as_bytes().to_vec()).unwrap();
+            let second = store.append_framed("hello_world2".as_bytes().to_vec()).unwrap();
+            let header = LEN_WIDTH as u64 + if checksums { CHECKSUM_WIDTH as u64 } else { 0 };
+            assert_eq!(first.position, StorePos(VERSION_LEN));
+            assert_eq!(first.len_prefix_at, first.position);
+            assert_eq!(first.payload_at, first.position + header);
+            assert_eq!(first.total as u64, header + 12);
+            assert_eq!(second.position, first.end());
+            assert_eq!(second.end(), StorePos(store.size as u64));
+
+            let bytes = std::fs::read(file_name).unwrap();
+            let payload = second.payload_at.0 as usize;
+            assert_eq!(&bytes[payload..payload + 12], "hello_world2".as_bytes());
+
+            std::fs::remove_file(file_name).unwrap();
+        }
+    }
+
+    #[test]
+    fn store_buffered_appends() {
+        let file_name = "tempfile_store_buffered_appends";
+        let config = ConfigBuilder::new(1024, 1024 * 1024, 0)
+            .with_batching(100, std::time::Duration::from_secs(60))
+            .build();
+        let mut store = Store::new(PathBuf::from(file_name), Arc::new(config.clone())).unwrap();
+
+        // enough appends that the buffer fills and is written out part way through,
+        // reading each back straight away
+        let mut appended = vec![];
+        for i in 0..2000 {
+            let value = format!("hello_world{}", i).into_bytes();
+            let (_, position) = store.append(value.clone()).unwrap();
+            assert_eq!(store.read(position).unwrap(), value);
+            appended.push((position, value));
+        }
+        assert!(std::fs::metadata(file_name).unwrap().len() < store.size as u64);
+
+        // and again once some are in the file and the rest still buffered,
+        // including records split between the two
+        for (position, value) in &appended {
+            assert_eq!(&store.read(*position).unwrap(), value);
+        }
+        let records: Vec<(StorePos, Vec<u8>)> = store.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(records, appended);
+
+        store.flush().unwrap();
+        assert_eq!(std::fs::metadata(file_name).unwrap().len(), store.size as u64);
+        drop(store);
+        let store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+        for (position, value) in &appended {
+            assert_eq!(&store.read(*position).unwrap(), value);
+        }
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    // accepts at most `chunk` bytes per write, and nothing once `limit` bytes are written
+    struct ShortWriter {
+        written: Vec<u8>,
+        chunk: usize,
+        limit: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.chunk).min(self.limit - self.written.len());
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_frame_short_writes() {
+        let header = [0, 0, 0, 0, 0, 0, 0, 12];
+        let payload = "hello_world1".as_bytes();
+
+        // writes that make progress are carried on with until the frame is written
+        let mut writer = ShortWriter {
+            written: vec![],
+            chunk: 3,
+            limit: usize::MAX,
+        };
+        assert_eq!(write_frame(&mut writer, &header, &[payload]).unwrap(), 20);
+        assert_eq!(&writer.written[8..], payload);
+
+        // one that stops part way through the payload fails the append
+        let mut writer = ShortWriter {
+            written: vec![],
+            chunk: 3,
+            limit: 14,
+        };
+        let err = write_frame(&mut writer, &header, &[payload]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn read_framed_reads_next_len_ahead() {
+        let file_name = "tempfile_read_framed_reads_next_len_ahead";
+        let config = ConfigBuilder::new(1024, 1024, 0).with_checksums(true).build();
+        let mut store = Store::new(PathBuf::from(file_name), Arc::new(config)).unwrap();
+        let positions: Vec<StorePos> = (0..5)
+            .map(|i| {
+                let (_, position) = store.append(format!("hello_world{}", i).into_bytes()).unwrap();
+                position
+            })
+            .collect();
+
+        // the first read also reads the length prefix, the rest had theirs read ahead
+        let before = store.read_count();
+        for (i, position) in positions.iter().enumerate() {
+            assert_eq!(store.read(*position).unwrap(), format!("hello_world{}", i).as_bytes());
+        }
+        assert_eq!(store.read_count() - before, 6);
+        let before = store.read_count();
+        assert_eq!(store.read(positions[1]).unwrap(), "hello_world1".as_bytes());
+        assert_eq!(store.read_count() - before, 2);
+
+        // a record appended after truncating doesn't get the length read ahead of the old one
+        assert_eq!(store.read(positions[1]).unwrap(), "hello_world1".as_bytes());
+        store.truncate_to(positions[2].0 as usize).unwrap();
+        store.append("replaced".as_bytes().to_vec()).unwrap();
+        assert_eq!(store.read(positions[2]).unwrap(), "replaced".as_bytes());
+
+        std::fs::remove_file(file_name).unwrap();
+    }
 }
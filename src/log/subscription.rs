@@ -0,0 +1,55 @@
+use thiserror::Error;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+use crate::proto::record::Record;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SubscriptionError {
+    // the subscriber fell behind and the oldest records were dropped from its buffer,
+    // they can still be read back from the log
+    #[error("Subscriber lagged behind by {0} records")]
+    Lagged(u64),
+
+    #[error("Log has been closed")]
+    Closed,
+
+    #[error("No new records")]
+    Empty,
+}
+
+/// Receives records as they are appended to a log.
+///
+/// Each subscription has a bounded buffer (see `ConfigBuilder::with_subscription_buffer`).
+/// Appends never wait on slow subscribers, instead once a subscriber's buffer overflows the
+/// oldest records are dropped and the next receive returns `SubscriptionError::Lagged(n)`
+/// with the number of records missed. Receiving after that continues from the oldest record
+/// still buffered, so the missed ones can be re-read from the log with `Log::iter_from`.
+pub struct Subscription {
+    receiver: broadcast::Receiver<Record>,
+}
+
+impl Subscription {
+    pub fn new(receiver: broadcast::Receiver<Record>) -> Self {
+        Self { receiver }
+    }
+
+    /// Waits for the next appended record
+    pub async fn recv(&mut self) -> Result<Record, SubscriptionError> {
+        self.receiver.recv().await.map_err(|e| match e {
+            RecvError::Lagged(n) => SubscriptionError::Lagged(n),
+            RecvError::Closed => SubscriptionError::Closed,
+        })
+    }
+
+    /// Returns the next appended record if there is one, without waiting
+    pub fn try_recv(&mut self) -> Result<Record, SubscriptionError> {
+        self.receiver.try_recv().map_err(|e| match e {
+            TryRecvError::Lagged(n) => SubscriptionError::Lagged(n),
+            TryRecvError::Closed => SubscriptionError::Closed,
+            TryRecvError::Empty => SubscriptionError::Empty,
+        })
+    }
+}
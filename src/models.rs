@@ -21,3 +21,15 @@ pub struct ConsumeRequest {
 pub struct ConsumeResponse {
     pub record: Record,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamRequest {
+    pub from: u64,
+}
+
+// a single record sent down the stream, the value is sent as (lossy) utf-8
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamedRecord {
+    pub offset: u64,
+    pub value: String,
+}
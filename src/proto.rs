@@ -1,3 +1,150 @@
 pub mod record {
     include!(concat!(env!("OUT_DIR"), "/rustlog.models.rs"));
 }
+
+use prost::encoding::{encode_key, encode_varint, WireType};
+use prost::Message;
+use record::{Header, Record};
+
+use crate::log::offset::AbsOffset;
+
+// field number of `Record.value` in proto/v1/record.proto
+const VALUE_TAG: u32 = 1;
+
+impl Record {
+    pub fn builder() -> RecordBuilder {
+        RecordBuilder::default()
+    }
+
+    /// A hash of the record's key and value, leaving out everything the log assigns (offset,
+    /// timestamp) along with headers and expiry, so records with the same content hash the
+    /// same. It's FNV-1a, which stays the same across builds and can be stored, but equal
+    /// hashes don't guarantee equal content
+    pub fn content_hash(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+        let mut hash = OFFSET_BASIS;
+        let mut update = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(PRIME);
+            }
+        };
+        // lengths keep key "ab" with value "c" apart from key "a" with value "bc"
+        match &self.key {
+            Some(key) => {
+                update(&[1]);
+                update(&(key.len() as u64).to_be_bytes());
+                update(key);
+            }
+            None => update(&[0]),
+        }
+        update(&(self.value.len() as u64).to_be_bytes());
+        update(&self.value);
+        hash
+    }
+
+    /// Encodes everything but the value, followed by the value's field key and length, so
+    /// writing the value itself right after gives a record that decodes the same as
+    /// `encode_to_vec`. A large value then doesn't have to be copied into the encoding
+    pub fn encode_without_value(&self) -> Vec<u8> {
+        // every field but the value, named so a new field can't be left out
+        let Record {
+            value,
+            offset,
+            key,
+            headers,
+            timestamp,
+            expire_at_millis,
+            set,
+        } = self;
+        let rest = Record {
+            value: vec![],
+            offset: *offset,
+            key: key.clone(),
+            headers: headers.clone(),
+            timestamp: *timestamp,
+            expire_at_millis: *expire_at_millis,
+            set: set.clone(),
+        };
+        // fields can come in any order, so the value goes last
+        let mut encoded = rest.encode_to_vec();
+        encode_key(VALUE_TAG, WireType::LengthDelimited, &mut encoded);
+        encode_varint(value.len() as u64, &mut encoded);
+        encoded
+    }
+}
+
+/// Builds a [`Record`] to be appended.
+/// The offset is left unset so the log assigns it on append, unless set with `with_offset`
+#[derive(Default)]
+pub struct RecordBuilder {
+    record: Record,
+}
+
+impl RecordBuilder {
+    pub fn value(mut self, value: impl Into<Vec<u8>>) -> Self {
+        self.record.value = value.into();
+        self
+    }
+
+    pub fn key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.record.key = Some(key.into());
+        self
+    }
+
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.record.headers.push(Header {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.record.offset = Some(offset);
+        self
+    }
+
+    /// Expires the record at the given time, in milliseconds since the unix epoch as given by
+    /// the log's clock. Expired records stay in the log but can't be read
+    pub fn expire_at_millis(mut self, expire_at_millis: u64) -> Self {
+        self.record.expire_at_millis = Some(expire_at_millis);
+        self
+    }
+
+    pub fn build(self) -> Record {
+        self.record
+    }
+}
+
+/// A record read back from a log, which always has an offset.
+/// Derefs to the [`Record`], whose generated `offset()` would quietly return 0 if it was unset
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppendedRecord {
+    offset: AbsOffset,
+    record: Record,
+}
+
+impl AppendedRecord {
+    pub fn new(offset: AbsOffset, mut record: Record) -> Self {
+        record.offset = Some(offset.0);
+        Self { offset, record }
+    }
+
+    pub fn offset(&self) -> AbsOffset {
+        self.offset
+    }
+
+    pub fn into_record(self) -> Record {
+        self.record
+    }
+}
+
+impl std::ops::Deref for AppendedRecord {
+    type Target = Record;
+
+    fn deref(&self) -> &Record {
+        &self.record
+    }
+}
@@ -1,13 +1,18 @@
+use std::collections::VecDeque;
 use std::time::Duration;
 
 // use crate::models::{ConsumeRequest, ConsumeResponse, ProduceRequest, ProduceResponse};
-// use crate::log::Log;
+use crate::log::log::{Log, LogError};
+use crate::log::offset::AbsOffset;
+use crate::log::subscription::{Subscription, SubscriptionError};
+use crate::models::{StreamRequest, StreamedRecord};
+use crate::proto::record::Record;
 use actix_web::{
     get, post,
     web::{self, Bytes},
     App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-// use std::sync::Mutex;
+use std::sync::Mutex;
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -49,3 +54,231 @@ pub async fn test(bytes: Bytes) -> impl Responder {
 //         None => HttpResponse::NotFound().finish(),
 //     }
 // }
+
+// records read from the log each time its lock is taken while replaying
+const REPLAY_CHUNK: usize = 256;
+
+struct StreamState {
+    log: web::Data<Mutex<Log>>,
+    subscription: Subscription,
+    pending: VecDeque<Record>, // records read from the log, sent before anything from the subscription
+    next_offset: u64,
+    replaying: bool,         // reading from the log rather than the subscription
+    error: Option<LogError>, // a failed read from the log, sent once pending is, ending the stream
+    done: bool,
+}
+
+/// Streams records as newline delimited json, starting with the records from `from` up to the
+/// current head of the log and then every record appended after that.
+/// The log is replayed a chunk at a time, and a record that can't be read ends the stream with
+/// an error. When the client disconnects the stream, and with it the subscription, is dropped
+#[get("/stream")]
+pub async fn stream(query: web::Query<StreamRequest>, log: web::Data<Mutex<Log>>) -> impl Responder {
+    // records appended from here on come through the subscription, and also the replay if it
+    // hasn't finished, those are sent once
+    let subscription = log.lock().unwrap_or_else(|e| e.into_inner()).subscribe();
+    let state = StreamState {
+        log: log.clone(),
+        subscription,
+        pending: VecDeque::new(),
+        next_offset: query.from,
+        replaying: true,
+        error: None,
+        done: false,
+    };
+
+    let body = futures_util::stream::unfold(state, next_chunk);
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body)
+}
+
+async fn next_chunk(mut state: StreamState) -> Option<(Result<Bytes, actix_web::Error>, StreamState)> {
+    loop {
+        if state.done {
+            return None;
+        }
+        if let Some(record) = state.pending.pop_front() {
+            let offset = record.offset.unwrap_or(state.next_offset);
+            state.next_offset = offset + 1;
+            let streamed = StreamedRecord {
+                offset,
+                value: String::from_utf8_lossy(&record.value).into_owned(),
+            };
+            let mut chunk = serde_json::to_vec(&streamed).expect("cannot serialize record");
+            chunk.push(b'\n');
+            return Some((Ok(Bytes::from(chunk)), state));
+        }
+        if let Some(e) = state.error.take() {
+            state.done = true;
+            let e = actix_web::error::ErrorInternalServerError(e.to_string());
+            return Some((Err(e), state));
+        }
+        if state.replaying {
+            replay_chunk(&mut state);
+            continue;
+        }
+
+        match state.subscription.recv().await {
+            Ok(record) => {
+                // skip records already sent from the log
+                if record.offset.unwrap_or(0) >= state.next_offset {
+                    state.pending.push_back(record);
+                }
+            }
+            // the subscription dropped records, catch up from the log
+            Err(SubscriptionError::Lagged(_)) => state.replaying = true,
+            Err(_) => return None,
+        }
+    }
+}
+
+// reads the next records to send from the log, up to the first one that can't be read. Stops
+// replaying once it's read up to the head of the log
+fn replay_chunk(state: &mut StreamState) {
+    let log = state.log.lock().unwrap_or_else(|e| e.into_inner());
+    let mut read = 0;
+    for item in log
+        .iter_from(AbsOffset(state.next_offset))
+        .take(REPLAY_CHUNK)
+    {
+        match item {
+            Ok(record) => state.pending.push_back(record),
+            Err(e) => {
+                state.error = Some(e);
+                return;
+            }
+        }
+        read += 1;
+    }
+    state.replaying = read == REPLAY_CHUNK;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::log::Config;
+    use actix_web::body::MessageBody;
+    use std::pin::Pin;
+
+    async fn next_record(body: &mut (impl MessageBody + Unpin)) -> StreamedRecord {
+        let chunk = futures_util::future::poll_fn(|cx| Pin::new(&mut *body).poll_next(cx))
+            .await
+            .expect("stream ended")
+            .map_err(|_| "cannot read chunk")
+            .unwrap();
+        serde_json::from_slice(&chunk).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn stream_test() {
+        let log = Log::in_memory(Config::default()).expect("cannot create log");
+        let log = web::Data::new(Mutex::new(log));
+        log.lock()
+            .unwrap()
+            .append(Record::builder().value("before").build())
+            .unwrap();
+
+        let app = actix_web::test::init_service(App::new().app_data(log.clone()).service(stream)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/stream?from=0")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let mut body = resp.into_body();
+
+        // replayed from the log
+        let record = next_record(&mut body).await;
+        assert_eq!(record.offset, 0);
+        assert_eq!(record.value, "before");
+
+        // produced after the stream was opened
+        log.lock()
+            .unwrap()
+            .append(Record::builder().value("after").build())
+            .unwrap();
+        let record = next_record(&mut body).await;
+        assert_eq!(record.offset, 1);
+        assert_eq!(record.value, "after");
+    }
+
+    #[actix_web::test]
+    async fn stream_test_replays_in_chunks() {
+        let log = Log::in_memory(Config::default()).expect("cannot create log");
+        let log = web::Data::new(Mutex::new(log));
+        let records = REPLAY_CHUNK as u64 * 2 + 5;
+        for i in 0..records {
+            log.lock()
+                .unwrap()
+                .append(Record::builder().value(format!("hello world{}", i)).build())
+                .unwrap();
+        }
+
+        let app =
+            actix_web::test::init_service(App::new().app_data(log.clone()).service(stream)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/stream?from=3")
+            .to_request();
+        let mut body = actix_web::test::call_service(&app, req).await.into_body();
+        for i in 3..records {
+            let record = next_record(&mut body).await;
+            assert_eq!(record.offset, i);
+            assert_eq!(record.value, format!("hello world{}", i));
+        }
+
+        // appended once the replay's caught up, and sent once
+        log.lock()
+            .unwrap()
+            .append(Record::builder().value("after").build())
+            .unwrap();
+        let record = next_record(&mut body).await;
+        assert_eq!(record.offset, records);
+        assert_eq!(record.value, "after");
+    }
+
+    #[actix_web::test]
+    async fn stream_test_ends_on_error() {
+        use crate::log::log::{ConfigBuilder, SkipOrFail, LEN_WIDTH};
+        use std::path::PathBuf;
+
+        let log_dir = PathBuf::from("log_dir_stream_ends_on_error");
+        let mut log = Log::new(log_dir.clone(), None).expect("cannot create log");
+        for i in 0..5 {
+            log.append_str(&format!("hello world{}", i)).unwrap();
+        }
+        let (_, position) = log.read_with_position(AbsOffset(2)).unwrap();
+        let (_, store, _) = log.segment_paths().remove(0);
+        drop(log);
+
+        // overwrite the middle record's payload with bytes that don't decode
+        let mut bytes = std::fs::read(&store).unwrap();
+        let start = position.0 as usize + LEN_WIDTH as usize;
+        bytes[start..start + 23].fill(0xff);
+        std::fs::write(&store, bytes).unwrap();
+
+        // even when reads skip past it, the records after it aren't sent without it
+        let config = ConfigBuilder::new(1024, 1024, 0)
+            .with_scan_on_error(SkipOrFail::Skip)
+            .build();
+        let log = Log::new(log_dir.clone(), Some(config)).expect("cannot create log");
+        let log = web::Data::new(Mutex::new(log));
+        let app =
+            actix_web::test::init_service(App::new().app_data(log.clone()).service(stream)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/stream?from=0")
+            .to_request();
+        let mut body = actix_web::test::call_service(&app, req).await.into_body();
+        for i in 0..2 {
+            assert_eq!(next_record(&mut body).await.offset, i);
+        }
+        let chunk = futures_util::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await;
+        assert!(matches!(chunk, Some(Err(_))));
+        let chunk = futures_util::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await;
+        assert!(chunk.is_none());
+
+        drop(body);
+        drop(app);
+        drop(log);
+        std::fs::remove_dir_all(log_dir).expect("cannot remove dir");
+    }
+}